@@ -6,25 +6,121 @@ export function ageDecryptWithPassphrase(passphrase: string, data: Uint8Array, s
 /** Encryption API */
 export function ageEncryptWithPassphrase(passphrase: string, data: Uint8Array, signal?: AbortSignal | undefined | null): Promise<Buffer>
 
+/** Aggregate progress of a batch operation */
+export interface BatchProgress {
+  operationId: string
+  filesDone: number
+  filesTotal: number
+  bytesDone: number
+  /** bytes of started transfers, grows as transfers start */
+  bytesTotal: number
+  /** bytes per second */
+  throughput: number
+  /** estimated seconds remaining, none until throughput is known */
+  eta?: number
+}
+
 export function cancelAllRequests(): Promise<void>
 
+/** Cancel in-flight requests of a graph, requests of other graphs are not affected */
+export function cancelGraphRequests(graphUuid: string): Promise<void>
+
 /** Helper */
 export function canonicalizePath(filePath: string): Promise<string>
 
+/** A file path colliding with another path of different case */
+export interface CaseCollision {
+  path: string
+  /** the path kept, in the same batch or an existing local file */
+  conflictWith: string
+}
+
+/** Limits of version and backup files, nothing is pruned by default */
+export interface CleanupOptions {
+  /** remove files older than this */
+  maxAgeDays?: number
+  /** keep the newest files of each page */
+  maxVersionsPerPage?: number
+  /** total size budget in bytes, of version and backup files */
+  maxTotalSize?: number
+  /** report files without removing them */
+  dryRun?: boolean
+}
+
+/** Files removed by a cleanup */
+export interface CleanupReport {
+  /** relative to graph base path */
+  removed: Array<string>
+  reclaimedBytes: number
+  /** base copies of deleted pages, among removed files */
+  orphanedBaseFiles: number
+  failed: Array<FileError>
+}
+
+/**
+ * Prune version and backup files of a graph by age, count per page and total size,
+ * and remove base copies of deleted pages
+ */
+export function cleanupVersionFiles(graphUuid: string, basePath: string | undefined | null, options: CleanupOptions): Promise<CleanupReport>
+
+/** Conflicted file in a download batch */
+export interface ConflictedFile {
+  path: string
+  /** "keep-remote", "keep-local", "keep-both" or "case-collision" */
+  resolution: string
+  /**
+   * path of the conflict copy of local file, for "keep-both";
+   * the colliding path, for "case-collision"
+   */
+  conflictPath?: string
+}
+
 export function decryptFnames(graphUuid: string, fnames: Array<string>): Array<string>
 
 /**
  * (delete-local-file [this graph-uuid base-path filepath access-token])
  * NOTE: token is not used
+ * signal: AbortSignal to cancel the operation
+ * dry_run: report planned deletes without deleting
  */
-export function deleteLocalFiles(graphUuid: string, basePath: string, filePaths: Array<string>): Promise<void>
+export function deleteLocalFiles(graphUuid: string, basePath: string | undefined | null, filePaths: Array<string>, signal?: AbortSignal | undefined | null, dryRun?: boolean | undefined | null): Promise<FilesResult>
 
-/** (delete-remote-file [this graph-uuid base-path filepath local-txid access-token]))#[napi] */
-export function deleteRemoteFiles(graphUuid: string, basePath: string, filePaths: Array<string>, txid: number, token: string): Promise<number>
+/**
+ * (delete-remote-file [this graph-uuid base-path filepath local-txid access-token]))#[napi]
+ * signal: AbortSignal to cancel the operation
+ * dry_run: return planned deletes in `planned`, without deleting
+ */
+export function deleteRemoteFiles(graphUuid: string, basePath: string | undefined | null, filePaths: Array<string>, txid: number, token: string | undefined | null, signal?: AbortSignal | undefined | null, dryRun?: boolean | undefined | null): Promise<UpdateRemoteFilesResult>
 
 export function encryptFnames(graphUuid: string, fnames: Array<string>): Array<string>
 
-export function fetchRemoteFiles(graphUuid: string, basePath: string, filePaths: Array<string>, token: string): Promise<Array<string>>
+/**
+ * preserve_mtime: set modified times of downloaded files from remote
+ * signal: AbortSignal to cancel the operation
+ * dry_run: report planned writes without downloading
+ */
+export function fetchRemoteFiles(graphUuid: string, basePath: string | undefined | null, filePaths: Array<string>, token: string | undefined | null, preserveMtime?: boolean | undefined | null, signal?: AbortSignal | undefined | null, dryRun?: boolean | undefined | null): Promise<FilesResult>
+
+/** Local file change, pushed by the watcher */
+export interface FileChangeEvent {
+  graphUuid: string
+  /** "add", "change", "unlink" or "rename" */
+  type: string
+  /** relative to graph base path, a folder path for "unlink" if the whole folder is gone */
+  path: string
+  /** old path, for "rename" */
+  from?: string
+  /** none for "unlink" */
+  meta?: FileMeta
+}
+
+/** Error of a file in a batch */
+export interface FileError {
+  path: string
+  /** see `Error::kind`, e.g. "not-found", "permission-denied" or "sync" */
+  kind: string
+  message: string
+}
 
 export interface FileMeta {
   size: number
@@ -39,56 +135,271 @@ export interface FileMeta {
   encryptedFname: string
 }
 
-/** (get-local-all-files-meta [this graph-uuid base-path] "get all local files' metadata") */
-export function getLocalAllFilesMeta(graphUuid: string, basePath: string): Promise<Record<string, FileMeta>>
+/** Size limits of uploaded files, no limit by default */
+export interface FileSizeLimits {
+  /** max file size in bytes */
+  maxSize?: number
+  /** max file sizes by lowercase extension, e.g. "mp4", overriding `max_size` */
+  extensionLimits?: Record<string, number>
+  /** defer oversized files to unmetered connections, instead of skipping them */
+  defer?: boolean
+}
+
+/** Files' metadata, keyed by NFC normalized file name */
+export interface FilesMetaResult {
+  succeeded: Record<string, FileMeta>
+  failed: Array<FileError>
+}
+
+export interface FilesResult {
+  succeeded: Array<string>
+  failed: Array<FileError>
+  /** changes of a dry run, instead of the files being changed */
+  planned?: Array<PlannedAction>
+}
+
+/** Large files of a graph deferred to an unmetered connection */
+export function getDeferredUploads(graphUuid: string): Array<string>
+
+/**
+ * (get-local-all-files-meta [this graph-uuid base-path] "get all local files' metadata")
+ * Unchanged files are served from the local file index, unless `force_rescan` is set.
+ * Keyed by NFC normalized file name.
+ */
+export function getLocalAllFilesMeta(graphUuid: string, basePath: string | undefined | null, forceRescan?: boolean | undefined | null): Promise<FilesMetaResult>
 
 /**
  * get local files' metadata: file-size, md5
  * (get-local-files-meta [this graph-uuid base-path filepaths] "get local files' metadata")
  */
-export function getLocalFilesMeta(graphUuid: string, basePath: string, filePaths: Array<string>): Promise<Record<string, FileMeta>>
+export function getLocalFilesMeta(graphUuid: string, basePath: string | undefined | null, filePaths: Array<string>): Promise<FilesMetaResult>
+
+/** Local symlinks and special files(sockets, FIFOs and devices) not synced */
+export function getLocalSkippedFiles(graphUuid: string, basePath: string | undefined | null): Promise<Array<SkippedFile>>
+
+/** Local files whose paths differ only in Unicode normalization form, only one of them is synced */
+export function getLocalUnicodeCollisions(graphUuid: string, basePath: string | undefined | null): Promise<Array<UnicodeCollision>>
+
+/** A graph set by `set_env` */
+export interface GraphInfo {
+  graphUuid: string
+  /** "dev" or "prod" */
+  env: string
+  basePath?: string
+  hasToken: boolean
+}
 
 /** Set rsapi Logger */
 export function initLogger(jsLoggingFn: (...args: any[]) => any): void
 
+/** Detect whether the filesystem of base path is case sensitive */
+export function isFsCaseSensitive(basePath: string): Promise<boolean>
+
 /** Age encryption key generation */
 export function keygen(): Promise<Record<string, string>>
 
+export function listGraphs(): Array<GraphInfo>
+
+/** Merge report of a page file */
+export interface MergeReport {
+  path: string
+  /** "merged", "unchanged" or "conflict" */
+  status: string
+  conflicts: number
+}
+
+export interface MergeResult {
+  succeeded: Array<MergeReport>
+  failed: Array<FileError>
+}
+
+/**
+ * Merge incoming page files(fetched by `fetch_remote_files`) into local files.
+ * Conflicting files are left untouched unless `write_conflict_markers` is set.
+ */
+export function mergeRemoteFiles(graphUuid: string, basePath: string | undefined | null, filePaths: Array<string>, writeConflictMarkers?: boolean | undefined | null): Promise<MergeResult>
+
 /** Metadata for batch remote update */
 export interface Metadata {
+  /** case sensitivity of the graph folder, used for case collisions instead of probing */
   fsCaseSensitive: boolean
   version: string
   revision: string
   platform: string
 }
 
+/** A change a sync operation would make */
+export interface PlannedAction {
+  /** "write-local", "delete-local", "upload" or "delete-remote" */
+  type: string
+  path: string
+  encryptedPath?: string
+  /** local file to be written, deleted or uploaded */
+  localPath?: string
+  /** remote url to download from */
+  remoteUrl?: string
+  /** local file size, for uploads and deletes */
+  size?: number
+}
+
 /** Download/Upload Progress Info */
 export interface Progress {
   graphUuid: string
   file: string
+  /** "download" or "upload" of a file, "batch-download" or "batch-upload" of a batch */
   type: string
   progress: number
   total: number
   percent: number
+  /** aggregate progress, for batch types */
+  batch?: BatchProgress
 }
 
+/**
+ * Compute sync plan: upload, download, delete-local, delete-remote, rename-local,
+ * rename-remote and conflict actions.
+ * Compare with remote full file list when txid is 0, otherwise remote changes since txid.
+ */
+export function planSync(graphUuid: string, basePath: string | undefined | null, txid: number, token: string | undefined | null): Promise<any>
+
+/** Remove a graph set by `set_env`, its requests are cancelled and watcher stopped */
+export function removeGraph(graphUuid: string): void
+
 /** (rename-local-file [this graph-uuid base-path from to access-token]) */
-export function renameLocalFile(graphUuid: string, basePath: string, from: string, to: string): Promise<void>
+export function renameLocalFile(graphUuid: string, basePath: string | undefined | null, from: string, to: string): Promise<void>
+
+/** Back up local files overwritten or deleted by sync to `logseq/bak`, enabled by default */
+export function setBackupEnabled(graphUuid: string, enabled: boolean): void
 
-/** Set dev environment along with encryption key */
-export function setEnv(graphUuid: string, env: string, secretKey: string, publicKey: string): Promise<void>
+/**
+ * Store files of a graph of at least `min_size` bytes in chunks, only changed chunks are
+ * uploaded. Disabled by default, or when `min_size` is not set.
+ * Versions without chunked storage write manifests of chunked files over local files.
+ */
+export function setChunkedStorage(graphUuid: string, minSize?: number | undefined | null): void
+
+/**
+ * Compress page files of a graph before encryption, disabled by default.
+ * Versions without compression can't read compressed pages.
+ */
+export function setCompressionEnabled(graphUuid: string, enabled: boolean): void
+
+/**
+ * Set dev environment along with encryption key
+ * ignore_rules: gitignore-style rules, applied after `logseq/.syncignore`
+ * base_path, token: used by operations on the graph when not passed
+ */
+export function setEnv(graphUuid: string, env: string, secretKey: string, publicKey: string, ignoreRules?: Array<string> | undefined | null, basePath?: string | undefined | null, token?: string | undefined | null): Promise<void>
+
+/** Size limits of files uploaded by a graph */
+export function setFileSizeLimits(graphUuid: string, limits: FileSizeLimits): void
+
+/**
+ * Large files are deferred on metered connections, when enabled by `set_file_size_limits`.
+ * Connections are unmetered by default on desktop
+ */
+export function setMeteredConnection(metered: boolean): void
 
 export function setProgressCallback(callback: (...args: any[]) => any): void
 
 export function setProxy(proxy?: string | undefined | null): Promise<void>
 
+/**
+ * Set how symlinks of a graph are synced
+ * policy: "follow"(default), "skip" or "link"
+ */
+export function setSymlinkPolicy(graphUuid: string, policy: string): void
+
+export function setWatchCallback(callback: (...args: any[]) => any): void
+
+/** Local file not synced */
+export interface SkippedFile {
+  path: string
+  /**
+   * "symlink", "symlink-loop", "socket", "fifo" or "device" when scanning,
+   * "too-large", "deferred" or "unchanged" when uploading
+   */
+  reason: string
+}
+
+/** Subscribe to sync events of all graphs, return id of the subscription */
+export function subscribeEvents(callback: (...args: any[]) => any): number
+
+/** Sync event of a graph */
+export interface SyncEvent {
+  graphUuid: string
+  /**
+   * "operation-started", "operation-finished", "file-uploaded", "file-downloaded",
+   * "file-skipped", "conflict", "credential-refreshed", "retry-scheduled" or "error"
+   */
+  type: string
+  /** operation name, e.g. "update-remote-files" */
+  operation?: string
+  operationId?: string
+  path?: string
+  /** skip reason, conflict resolution, error message, or outcome of a finished operation */
+  detail?: string
+  /** milliseconds since epoch */
+  timestamp: number
+}
+
+/** Local files whose paths differ only in Unicode normalization form */
+export interface UnicodeCollision {
+  /** on-disk path of the skipped file */
+  path: string
+  /** on-disk path of the file kept */
+  conflictWith: string
+}
+
+/** Return false if the subscription is not found */
+export function unsubscribeEvents(id: number): boolean
+
+export function unwatchGraph(graphUuid: string): void
+
 /**
  * remote -> local
  * (update-local-file [this graph-uuid base-path filepath access-token] "remote -> local")
+ * conflict_policy: "keep-remote"(default), "keep-local", "keep-both" or "newest-wins"
+ * preserve_mtime: set modified times of downloaded files from remote
+ * signal: AbortSignal to cancel the operation
+ * dry_run: report planned writes and conflicts without changing local files
  */
-export function updateLocalFiles(graphUuid: string, basePath: string, filePaths: Array<string>, token: string): Promise<void>
+export function updateLocalFiles(graphUuid: string, basePath: string | undefined | null, filePaths: Array<string>, token: string | undefined | null, conflictPolicy?: string | undefined | null, deviceName?: string | undefined | null, preserveMtime?: boolean | undefined | null, signal?: AbortSignal | undefined | null, dryRun?: boolean | undefined | null): Promise<UpdateLocalFilesResult>
+
+export interface UpdateLocalFilesResult {
+  /** updated files, including local files kept by the conflict policy */
+  succeeded: Array<string>
+  failed: Array<FileError>
+  conflicts: Array<ConflictedFile>
+  /** changes of a dry run, instead of the files being changed */
+  planned?: Array<PlannedAction>
+}
 
-export function updateLocalVersionFiles(graphUuid: string, basePath: string, filePaths: Array<string>, token: string): Promise<void>
+/** signal: AbortSignal to cancel the operation */
+export function updateLocalVersionFiles(graphUuid: string, basePath: string | undefined | null, filePaths: Array<string>, token: string | undefined | null, signal?: AbortSignal | undefined | null): Promise<FilesResult>
 
-export function updateRemoteFiles(graphUuid: string, basePath: string, filePaths: Array<string>, txid: number, token: string, metadata?: Metadata | undefined | null): Promise<number>
+/**
+ * signal: AbortSignal to cancel the operation,
+ * force: upload files even if unchanged remotely
+ * dry_run: report planned uploads without uploading
+ */
+export function updateRemoteFiles(graphUuid: string, basePath: string | undefined | null, filePaths: Array<string>, txid: number, token: string | undefined | null, metadata?: Metadata | undefined | null, signal?: AbortSignal | undefined | null, force?: boolean | undefined | null, dryRun?: boolean | undefined | null): Promise<UpdateRemoteFilesResult>
 
+export interface UpdateRemoteFilesResult {
+  txid: number
+  succeeded: Array<string>
+  failed: Array<FileError>
+  /** files over size limits, skipped or deferred, files unchanged remotely, and ignored files */
+  skipped: Array<SkippedFile>
+  /** changes of a dry run, instead of the files being changed */
+  planned?: Array<PlannedAction>
+}
+
+/** Update the auth token of a graph, used by operations started afterwards */
+export function updateToken(graphUuid: string, token: string): void
+
+/**
+ * Watch local file changes of a graph, events are pushed to the watch callback
+ * debounce_ms: quiet interval before a changed file is reported, default 500ms
+ */
+export function watchGraph(graphUuid: string, basePath: string | undefined | null, debounceMs?: number | undefined | null): void
@@ -331,20 +331,42 @@ if (!nativeBinding) {
 module.exports.ageDecryptWithPassphrase = nativeBinding.ageDecryptWithPassphrase
 module.exports.ageEncryptWithPassphrase = nativeBinding.ageEncryptWithPassphrase
 module.exports.cancelAllRequests = nativeBinding.cancelAllRequests
+module.exports.cancelGraphRequests = nativeBinding.cancelGraphRequests
 module.exports.canonicalizePath = nativeBinding.canonicalizePath
+module.exports.cleanupVersionFiles = nativeBinding.cleanupVersionFiles
 module.exports.decryptFnames = nativeBinding.decryptFnames
 module.exports.deleteLocalFiles = nativeBinding.deleteLocalFiles
 module.exports.deleteRemoteFiles = nativeBinding.deleteRemoteFiles
 module.exports.encryptFnames = nativeBinding.encryptFnames
 module.exports.fetchRemoteFiles = nativeBinding.fetchRemoteFiles
+module.exports.getDeferredUploads = nativeBinding.getDeferredUploads
 module.exports.getLocalAllFilesMeta = nativeBinding.getLocalAllFilesMeta
 module.exports.getLocalFilesMeta = nativeBinding.getLocalFilesMeta
+module.exports.getLocalSkippedFiles = nativeBinding.getLocalSkippedFiles
+module.exports.getLocalUnicodeCollisions = nativeBinding.getLocalUnicodeCollisions
 module.exports.initLogger = nativeBinding.initLogger
+module.exports.isFsCaseSensitive = nativeBinding.isFsCaseSensitive
 module.exports.keygen = nativeBinding.keygen
+module.exports.listGraphs = nativeBinding.listGraphs
+module.exports.mergeRemoteFiles = nativeBinding.mergeRemoteFiles
+module.exports.planSync = nativeBinding.planSync
+module.exports.removeGraph = nativeBinding.removeGraph
 module.exports.renameLocalFile = nativeBinding.renameLocalFile
+module.exports.setBackupEnabled = nativeBinding.setBackupEnabled
+module.exports.setChunkedStorage = nativeBinding.setChunkedStorage
+module.exports.setCompressionEnabled = nativeBinding.setCompressionEnabled
 module.exports.setEnv = nativeBinding.setEnv
+module.exports.setFileSizeLimits = nativeBinding.setFileSizeLimits
+module.exports.setMeteredConnection = nativeBinding.setMeteredConnection
 module.exports.setProgressCallback = nativeBinding.setProgressCallback
 module.exports.setProxy = nativeBinding.setProxy
+module.exports.setSymlinkPolicy = nativeBinding.setSymlinkPolicy
+module.exports.setWatchCallback = nativeBinding.setWatchCallback
+module.exports.subscribeEvents = nativeBinding.subscribeEvents
+module.exports.unsubscribeEvents = nativeBinding.unsubscribeEvents
+module.exports.unwatchGraph = nativeBinding.unwatchGraph
 module.exports.updateLocalFiles = nativeBinding.updateLocalFiles
 module.exports.updateLocalVersionFiles = nativeBinding.updateLocalVersionFiles
 module.exports.updateRemoteFiles = nativeBinding.updateRemoteFiles
+module.exports.updateToken = nativeBinding.updateToken
+module.exports.watchGraph = nativeBinding.watchGraph
@@ -0,0 +1,109 @@
+//! Results of batch operations: files done, and an error for each failed file.
+
+use std::collections::HashMap;
+
+use napi_derive::napi;
+use rsapi_impl::batch::{BatchResult, FileError};
+use rsapi_impl::conflict::ConflictedFile;
+use rsapi_impl::dry_run::PlannedAction;
+use rsapi_impl::graph::{LocalUpdate, RemoteUpdate};
+use rsapi_impl::merge::MergeReport;
+use rsapi_impl::special_file::SkippedFile;
+use rsapi_impl::FileMeta;
+
+#[napi(object)]
+pub struct FilesResult {
+    pub succeeded: Vec<String>,
+    pub failed: Vec<FileError>,
+    /// changes of a dry run, instead of the files being changed
+    pub planned: Option<Vec<PlannedAction>>,
+}
+
+impl From<BatchResult<String>> for FilesResult {
+    fn from(batch: BatchResult<String>) -> Self {
+        FilesResult {
+            succeeded: batch.succeeded,
+            failed: batch.failed,
+            planned: None,
+        }
+    }
+}
+
+/// Files' metadata, keyed by NFC normalized file name
+#[napi(object)]
+pub struct FilesMetaResult {
+    pub succeeded: HashMap<String, FileMeta>,
+    pub failed: Vec<FileError>,
+}
+
+impl FilesMetaResult {
+    pub fn new<F: Fn(&FileMeta) -> String>(batch: BatchResult<FileMeta>, key: F) -> Self {
+        FilesMetaResult {
+            succeeded: batch
+                .succeeded
+                .into_iter()
+                .map(|meta| (key(&meta), meta))
+                .collect(),
+            failed: batch.failed,
+        }
+    }
+}
+
+#[napi(object)]
+pub struct UpdateLocalFilesResult {
+    /// updated files, including local files kept by the conflict policy
+    pub succeeded: Vec<String>,
+    pub failed: Vec<FileError>,
+    pub conflicts: Vec<ConflictedFile>,
+    /// changes of a dry run, instead of the files being changed
+    pub planned: Option<Vec<PlannedAction>>,
+}
+
+impl From<LocalUpdate> for UpdateLocalFilesResult {
+    fn from(update: LocalUpdate) -> Self {
+        UpdateLocalFilesResult {
+            succeeded: update.files.succeeded,
+            failed: update.files.failed,
+            conflicts: update.conflicts,
+            planned: None,
+        }
+    }
+}
+
+#[napi(object)]
+pub struct UpdateRemoteFilesResult {
+    pub txid: i64,
+    pub succeeded: Vec<String>,
+    pub failed: Vec<FileError>,
+    /// files over size limits, skipped or deferred, files unchanged remotely, and ignored files
+    pub skipped: Vec<SkippedFile>,
+    /// changes of a dry run, instead of the files being changed
+    pub planned: Option<Vec<PlannedAction>>,
+}
+
+impl From<RemoteUpdate> for UpdateRemoteFilesResult {
+    fn from(update: RemoteUpdate) -> Self {
+        UpdateRemoteFilesResult {
+            txid: update.txid,
+            succeeded: update.files.succeeded,
+            failed: update.files.failed,
+            skipped: update.skipped,
+            planned: None,
+        }
+    }
+}
+
+#[napi(object)]
+pub struct MergeResult {
+    pub succeeded: Vec<MergeReport>,
+    pub failed: Vec<FileError>,
+}
+
+impl From<BatchResult<MergeReport>> for MergeResult {
+    fn from(batch: BatchResult<MergeReport>) -> Self {
+        MergeResult {
+            succeeded: batch.succeeded,
+            failed: batch.failed,
+        }
+    }
+}
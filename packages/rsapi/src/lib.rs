@@ -2,30 +2,49 @@
 #![feature(result_flattening)]
 #![feature(async_closure)]
 
-use std::time::Instant;
-use std::{collections::HashMap, path::PathBuf};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, OnceLock};
+use std::time::{Duration, Instant};
 
 use napi::bindgen_prelude::*;
 use napi::threadsafe_function::{
     ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
 };
-use napi::Result;
+use napi::{Env, JsFunction, JsObject, JsUnknown, Result};
 use napi_derive::napi;
 
 use rsapi_impl as implementation;
-pub use rsapi_impl::{graph::Metadata, FileMeta, Progress};
+use rsapi_impl::cancel::CancelHandle;
+use rsapi_impl::conflict::ConflictOptions;
+use rsapi_impl::dry_run::DryRun;
+use rsapi_impl::graph::{DownloadOptions, UploadOptions};
+pub use rsapi_impl::{
+    batch::FileError, conflict::ConflictedFile, dry_run::PlannedAction, graph::GraphInfo,
+    graph::Metadata, housekeeping::CleanupOptions, housekeeping::CleanupReport,
+    large_file::FileSizeLimits, merge::MergeReport, special_file::SkippedFile,
+    unicode_path::UnicodeCollision, BatchProgress, FileChangeEvent, FileMeta, Progress, SyncEvent,
+};
+use rsapi_impl::{housekeeping, watcher, RsapiContext};
 
 use crate::age_task::{DecryptTask, EncryptInput, EncryptTask};
+use crate::batch::{
+    FilesMetaResult, FilesResult, MergeResult, UpdateLocalFilesResult, UpdateRemoteFilesResult,
+};
 
 pub mod age_task;
+pub mod batch;
 
 type ProgressCallbackFunction = ThreadsafeFunction<Progress, ErrorStrategy::CalleeHandled>;
-static mut PROGRESS_CALLBACK: Option<ProgressCallbackFunction> = None;
+type WatchCallbackFunction = ThreadsafeFunction<FileChangeEvent, ErrorStrategy::CalleeHandled>;
+type EventCallbackFunction = ThreadsafeFunction<SyncEvent, ErrorStrategy::CalleeHandled>;
+
+static CONTEXT: LazyLock<RsapiContext> = LazyLock::new(RsapiContext::new);
 
 static LOGGER: NodeJsLogger = NodeJsLogger;
-static mut LOGGING_CALLBACK: Option<
+static LOGGING_CALLBACK: OnceLock<
     ThreadsafeFunction<(String, String), ErrorStrategy::CalleeHandled>,
-> = None;
+> = OnceLock::new();
 
 pub struct NodeJsLogger;
 
@@ -37,7 +56,7 @@ impl log::Log for NodeJsLogger {
 
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
-            if let Some(callback) = unsafe { LOGGING_CALLBACK.as_ref() } {
+            if let Some(callback) = LOGGING_CALLBACK.get() {
                 callback.call(
                     Ok((record.level().to_string(), format!("{}", record.args()))),
                     ThreadsafeFunctionCallMode::NonBlocking,
@@ -54,7 +73,7 @@ impl log::Log for NodeJsLogger {
 /// Set rsapi Logger
 #[napi]
 pub fn init_logger(js_logging_fn: JsFunction) -> Result<()> {
-    if unsafe { LOGGING_CALLBACK.is_some() } {
+    if LOGGING_CALLBACK.get().is_some() {
         return Ok(());
     }
 
@@ -67,9 +86,7 @@ pub fn init_logger(js_logging_fn: JsFunction) -> Result<()> {
             },
         )?;
 
-    unsafe {
-        LOGGING_CALLBACK = Some(logging_fn);
-    }
+    let _ = LOGGING_CALLBACK.set(logging_fn);
     let _ = log::set_logger(&LOGGER);
     log::set_max_level(log::LevelFilter::Debug);
 
@@ -88,20 +105,104 @@ pub async fn keygen() -> Result<HashMap<String, String>> {
 }
 
 /// Set dev environment along with encryption key
+/// ignore_rules: gitignore-style rules, applied after `logseq/.syncignore`
+/// base_path, token: used by operations on the graph when not passed
 #[napi]
 pub async fn set_env(
     graph_uuid: String,
     env: String,
     secret_key: String,
     public_key: String,
+    ignore_rules: Option<Vec<String>>,
+    base_path: Option<String>,
+    token: Option<String>,
 ) -> Result<()> {
-    implementation::set_env(&graph_uuid, &env, &secret_key, &public_key)?;
+    CONTEXT.set_env(
+        &graph_uuid,
+        &env,
+        &secret_key,
+        &public_key,
+        &ignore_rules.unwrap_or_default(),
+        base_path.as_deref().map(Path::new),
+        token.as_deref(),
+    )?;
+    Ok(())
+}
+
+/// Remove a graph set by `set_env`, its requests are cancelled and watcher stopped
+#[napi]
+pub fn remove_graph(graph_uuid: String) -> Result<()> {
+    CONTEXT.remove_graph(&graph_uuid)?;
+    Ok(())
+}
+
+#[napi]
+pub fn list_graphs() -> Vec<GraphInfo> {
+    CONTEXT.list_graphs()
+}
+
+/// Update the auth token of a graph, used by operations started afterwards
+#[napi]
+pub fn update_token(graph_uuid: String, token: String) -> Result<()> {
+    CONTEXT.update_token(&graph_uuid, &token)?;
+    Ok(())
+}
+
+/// Set how symlinks of a graph are synced
+/// policy: "follow"(default), "skip" or "link"
+#[napi]
+pub fn set_symlink_policy(graph_uuid: String, policy: String) -> Result<()> {
+    CONTEXT.set_symlink_policy(&graph_uuid, policy.parse()?)?;
+    Ok(())
+}
+
+/// Size limits of files uploaded by a graph
+#[napi]
+pub fn set_file_size_limits(graph_uuid: String, limits: FileSizeLimits) -> Result<()> {
+    CONTEXT.set_file_size_limits(&graph_uuid, limits)?;
+    Ok(())
+}
+
+/// Back up local files overwritten or deleted by sync to `logseq/bak`, enabled by default
+#[napi]
+pub fn set_backup_enabled(graph_uuid: String, enabled: bool) -> Result<()> {
+    CONTEXT.set_backup_enabled(&graph_uuid, enabled)?;
+    Ok(())
+}
+
+/// Store files of a graph of at least `min_size` bytes in chunks, only changed chunks are
+/// uploaded. Disabled by default, or when `min_size` is not set.
+/// Versions without chunked storage write manifests of chunked files over local files.
+#[napi]
+pub fn set_chunked_storage(graph_uuid: String, min_size: Option<i64>) -> Result<()> {
+    CONTEXT.set_chunked_storage(&graph_uuid, min_size.map(|size| size.max(0) as _))?;
+    Ok(())
+}
+
+/// Compress page files of a graph before encryption, disabled by default.
+/// Versions without compression can't read compressed pages.
+#[napi]
+pub fn set_compression_enabled(graph_uuid: String, enabled: bool) -> Result<()> {
+    CONTEXT.set_compression_enabled(&graph_uuid, enabled)?;
     Ok(())
 }
 
+/// Large files are deferred on metered connections, when enabled by `set_file_size_limits`.
+/// Connections are unmetered by default on desktop
+#[napi]
+pub fn set_metered_connection(metered: bool) {
+    CONTEXT.set_metered_connection(metered);
+}
+
+/// Large files of a graph deferred to an unmetered connection
+#[napi]
+pub fn get_deferred_uploads(graph_uuid: String) -> Vec<String> {
+    CONTEXT.deferred_uploads(&graph_uuid)
+}
+
 #[napi]
 pub async fn set_proxy(proxy: Option<String>) -> Result<()> {
-    implementation::set_proxy(proxy.as_ref().map(|x| &**x))?;
+    CONTEXT.set_proxy(proxy.as_ref().map(|x| &**x))?;
     Ok(())
 }
 
@@ -114,63 +215,177 @@ pub fn set_progress_callback(callback: JsFunction) -> Result<()> {
             Ok(vec![ctx.env.to_js_value(&ctx.value)?.into_unknown()])
         })?;
 
-    unsafe {
-        PROGRESS_CALLBACK = Some(progress_fn);
-    }
+    CONTEXT.set_progress_callback(move |info: Progress| {
+        progress_fn.call(Ok(info), ThreadsafeFunctionCallMode::NonBlocking);
+    });
 
-    fn progress_callback(info: Progress) {
-        if let Some(callback) = unsafe { PROGRESS_CALLBACK.as_ref() } {
-            callback.call(Ok(info), ThreadsafeFunctionCallMode::NonBlocking);
-        } else {
-            log::warn!("progress callback not set!");
-        }
-    }
+    Ok(())
+}
+
+#[napi]
+pub fn set_watch_callback(callback: JsFunction) -> Result<()> {
+    let watch_fn: WatchCallbackFunction = callback
+        .create_threadsafe_function(1000, |ctx: ThreadSafeCallContext<FileChangeEvent>| {
+            Ok(vec![ctx.value])
+        })?;
+
+    CONTEXT.set_watch_callback(move |event: FileChangeEvent| {
+        watch_fn.call(Ok(event), ThreadsafeFunctionCallMode::NonBlocking);
+    });
+
+    Ok(())
+}
+
+/// Subscribe to sync events of all graphs, return id of the subscription
+#[napi]
+pub fn subscribe_events(callback: JsFunction) -> Result<u32> {
+    let event_fn: EventCallbackFunction = callback
+        .create_threadsafe_function(1000, |ctx: ThreadSafeCallContext<SyncEvent>| {
+            Ok(vec![ctx.value])
+        })?;
 
-    implementation::set_progress_callback(progress_callback);
+    Ok(CONTEXT.subscribe_events(move |event: SyncEvent| {
+        event_fn.call(Ok(event), ThreadsafeFunctionCallMode::NonBlocking);
+    }))
+}
+
+/// Return false if the subscription is not found
+#[napi]
+pub fn unsubscribe_events(id: u32) -> bool {
+    CONTEXT.unsubscribe_events(id)
+}
 
+/// Watch local file changes of a graph, events are pushed to the watch callback
+/// debounce_ms: quiet interval before a changed file is reported, default 500ms
+#[napi]
+pub fn watch_graph(
+    graph_uuid: String,
+    base_path: Option<String>,
+    debounce_ms: Option<u32>,
+) -> Result<()> {
+    let debounce = debounce_ms
+        .map(|ms| Duration::from_millis(ms as _))
+        .unwrap_or(watcher::DEFAULT_DEBOUNCE);
+    CONTEXT.watch(&graph_uuid, base_path, debounce)?;
+    Ok(())
+}
+
+#[napi]
+pub fn unwatch_graph(graph_uuid: String) -> Result<()> {
+    CONTEXT.unwatch(&graph_uuid)?;
     Ok(())
 }
 
 #[napi]
 pub async fn cancel_all_requests() -> Result<()> {
-    implementation::cancel_all_requests()?;
+    CONTEXT.cancel_all_requests()?;
     Ok(())
 }
 
+/// Cancel in-flight requests of a graph, requests of other graphs are not affected
+#[napi]
+pub async fn cancel_graph_requests(graph_uuid: String) -> Result<()> {
+    CONTEXT.cancel_graph_requests(&graph_uuid)?;
+    Ok(())
+}
+
+/// Cancel the operation when the AbortSignal is aborted.
+/// Return a function removing the abort listener, see `remove_listener_on_settle`
+fn cancel_on_abort(
+    env: &Env,
+    signal: Option<JsObject>,
+    cancel: &CancelHandle,
+) -> Result<Option<JsUnknown>> {
+    let signal = match signal {
+        Some(signal) => signal,
+        None => return Ok(None),
+    };
+    let token = cancel.token();
+    if signal.get_named_property::<bool>("aborted")? {
+        token.cancel();
+        return Ok(None);
+    }
+    let on_abort = env
+        .create_function_from_closure("onabort", move |ctx| {
+            token.cancel();
+            ctx.env.get_undefined()
+        })?
+        .into_unknown();
+    let abort = env.create_string("abort")?.into_unknown();
+    let mut options = env.create_object()?;
+    options.set_named_property("once", env.get_boolean(true)?)?;
+
+    let add_event_listener: JsFunction = signal.get_named_property("addEventListener")?;
+    add_event_listener.call(Some(&signal), &[&abort, &on_abort, &options.into_unknown()])?;
+    // signal.removeEventListener.bind(signal, "abort", onabort)
+    let remove_event_listener = signal
+        .get_named_property::<JsFunction>("removeEventListener")?
+        .coerce_to_object()?;
+    let bind: JsFunction = remove_event_listener.get_named_property("bind")?;
+    let remove_listener = bind.call(
+        Some(&remove_event_listener),
+        &[&signal.into_unknown(), &abort, &on_abort],
+    )?;
+    Ok(Some(remove_listener))
+}
+
+/// Remove the abort listener when the promise settles, so that a long-lived signal
+/// does not keep finished operations alive
+fn remove_listener_on_settle(
+    promise: JsObject,
+    remove_listener: Option<JsUnknown>,
+) -> Result<JsObject> {
+    match remove_listener {
+        Some(remove_listener) => {
+            let finally: JsFunction = promise.get_named_property("finally")?;
+            finally
+                .call(Some(&promise), &[remove_listener])?
+                .coerce_to_object()
+        }
+        None => Ok(promise),
+    }
+}
+
 /// get local files' metadata: file-size, md5
 /// (get-local-files-meta [this graph-uuid base-path filepaths] "get local files' metadata")
 #[napi]
 pub async fn get_local_files_meta(
     graph_uuid: String,
-    base_path: String,
+    base_path: Option<String>,
     file_paths: Vec<String>,
-) -> Result<HashMap<String, FileMeta>> {
+) -> Result<FilesMetaResult> {
     log::trace!("get local files metadata {:?}", file_paths);
-    let graph = implementation::get_graph(&graph_uuid)?;
-    Ok(graph.get_files_meta(base_path, file_paths).await?)
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
+    let base_path = graph.base_path_or(base_path)?;
+    let batch = graph.get_files_meta(base_path, file_paths).await?;
+    Ok(FilesMetaResult::new(batch, |meta| {
+        rsapi_impl::unicode_path::nfc(&meta.incoming_fname).into_owned()
+    }))
 }
 
 /// (get-local-all-files-meta [this graph-uuid base-path] "get all local files' metadata")
+/// Unchanged files are served from the local file index, unless `force_rescan` is set.
+/// Keyed by NFC normalized file name.
 #[napi]
 pub async fn get_local_all_files_meta(
     graph_uuid: String,
-    base_path: String,
-) -> Result<HashMap<String, FileMeta>> {
+    base_path: Option<String>,
+    force_rescan: Option<bool>,
+) -> Result<FilesMetaResult> {
     let start_time = Instant::now();
 
-    let graph = implementation::get_graph(&graph_uuid)?;
-    let files_meta: HashMap<String, FileMeta> = graph
-        .get_all_files_meta(&base_path)
-        .await?
-        .into_iter()
-        .map(|meta| (meta.fname.clone(), meta))
-        .collect();
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
+    let base_path = graph.base_path_or(base_path)?;
+    let batch = graph
+        .get_all_files_meta(&base_path, force_rescan.unwrap_or_default())
+        .await?;
+    let files_meta = FilesMetaResult::new(batch, |meta| meta.normalized_fname.clone());
 
     let elapsed = start_time.elapsed();
     log::info!(
         "get file meta of {:?}: {} files in {}ms",
         base_path,
-        files_meta.len(),
+        files_meta.succeeded.len(),
         elapsed.as_millis()
     );
 
@@ -181,13 +396,14 @@ pub async fn get_local_all_files_meta(
 #[napi]
 pub async fn rename_local_file(
     graph_uuid: String,
-    base_path: String,
+    base_path: Option<String>,
     from: String,
     to: String,
 ) -> Result<()> {
     log::info!("rename local file: {:?} => {:?}", from, to);
 
-    let graph = implementation::get_graph(&graph_uuid)?;
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
+    let base_path = graph.base_path_or(base_path)?;
     graph.rename_local_file(&base_path, &from, &to).await?;
 
     Ok(())
@@ -195,137 +411,300 @@ pub async fn rename_local_file(
 
 /// (delete-local-file [this graph-uuid base-path filepath access-token])
 /// NOTE: token is not used
-#[napi]
-pub async fn delete_local_files(
+/// signal: AbortSignal to cancel the operation
+/// dry_run: report planned deletes without deleting
+#[napi(ts_return_type = "Promise<FilesResult>")]
+pub fn delete_local_files(
+    env: Env,
     graph_uuid: String,
-    base_path: String,
+    base_path: Option<String>,
     file_paths: Vec<String>,
-) -> Result<()> {
+    #[napi(ts_arg_type = "AbortSignal")] signal: Option<JsObject>,
+    dry_run: Option<bool>,
+) -> Result<JsObject> {
     log::info!("delete local files: {:?}", file_paths);
 
-    let graph = implementation::get_graph(&graph_uuid)?;
-    graph.delete_local_files(base_path, file_paths).await?;
-
-    Ok(())
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
+    let base_path = graph.base_path_or(base_path)?;
+    let cancel = graph.cancel_handle(None);
+    let dry_run = DryRun::new(dry_run.unwrap_or_default());
+    let remove_listener = cancel_on_abort(&env, signal, &cancel)?;
+    let promise = env.spawn_future(async move {
+        let deleted = graph
+            .delete_local_files(base_path, file_paths, &dry_run, &cancel)
+            .await?;
+
+        Ok(FilesResult {
+            planned: dry_run.planned(),
+            ..deleted.into()
+        })
+    })?;
+    remove_listener_on_settle(promise, remove_listener)
 }
 
-#[napi]
-pub async fn fetch_remote_files(
+/// preserve_mtime: set modified times of downloaded files from remote
+/// signal: AbortSignal to cancel the operation
+/// dry_run: report planned writes without downloading
+#[allow(clippy::too_many_arguments)]
+#[napi(ts_return_type = "Promise<FilesResult>")]
+pub fn fetch_remote_files(
+    env: Env,
     graph_uuid: String,
-    base_path: String,
+    base_path: Option<String>,
     file_paths: Vec<String>,
-    token: String,
-) -> Result<Vec<String>> {
+    token: Option<String>,
+    preserve_mtime: Option<bool>,
+    #[napi(ts_arg_type = "AbortSignal")] signal: Option<JsObject>,
+    dry_run: Option<bool>,
+) -> Result<JsObject> {
     log::info!("fetch remote files: {:?}", file_paths);
 
-    let graph = implementation::get_graph(&graph_uuid)?;
-    match graph
-        .fetch_remote_files(base_path, file_paths, &token)
-        .await
-    {
-        Ok(val) => Ok(val),
-        Err(e) => {
-            log::error!("fetch remote files error: {:?}", e);
-            return Err(e.into());
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
+    let base_path = graph.base_path_or(base_path)?;
+    let token = graph.token_or(token.as_deref())?;
+    let cancel = graph.cancel_handle(None);
+    let dry_run = DryRun::new(dry_run.unwrap_or_default());
+    let remove_listener = cancel_on_abort(&env, signal, &cancel)?;
+    let promise = env.spawn_future(async move {
+        match graph
+            .fetch_remote_files(
+                base_path,
+                file_paths,
+                &token,
+                preserve_mtime.unwrap_or_default(),
+                &dry_run,
+                &cancel,
+            )
+            .await
+        {
+            Ok(fetched) => Ok(FilesResult {
+                planned: dry_run.planned(),
+                ..fetched.into()
+            }),
+            Err(e) => {
+                log::error!("fetch remote files error: {:?}", e);
+                Err(e.into())
+            }
         }
-    }
+    })?;
+    remove_listener_on_settle(promise, remove_listener)
 }
 
 /// remote -> local
 /// (update-local-file [this graph-uuid base-path filepath access-token] "remote -> local")
-#[napi]
-pub async fn update_local_files(
+/// conflict_policy: "keep-remote"(default), "keep-local", "keep-both" or "newest-wins"
+/// preserve_mtime: set modified times of downloaded files from remote
+/// signal: AbortSignal to cancel the operation
+/// dry_run: report planned writes and conflicts without changing local files
+#[allow(clippy::too_many_arguments)]
+#[napi(ts_return_type = "Promise<UpdateLocalFilesResult>")]
+pub fn update_local_files(
+    env: Env,
     graph_uuid: String,
-    base_path: String,
+    base_path: Option<String>,
     file_paths: Vec<String>,
-    token: String,
-) -> Result<()> {
+    token: Option<String>,
+    conflict_policy: Option<String>,
+    device_name: Option<String>,
+    preserve_mtime: Option<bool>,
+    #[napi(ts_arg_type = "AbortSignal")] signal: Option<JsObject>,
+    dry_run: Option<bool>,
+) -> Result<JsObject> {
     log::info!("update local files: {:?}", file_paths);
 
-    let graph = implementation::get_graph(&graph_uuid)?;
-    if let Err(e) = graph
-        .update_local_files(base_path, file_paths, &token)
-        .await
-    {
-        log::error!("update local files error: {:?}", e);
-        return Err(e.into());
-    }
-
-    Ok(())
+    let options = DownloadOptions {
+        conflict: ConflictOptions {
+            policy: conflict_policy
+                .as_deref()
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            device_name: device_name.unwrap_or_default(),
+        },
+        preserve_mtime: preserve_mtime.unwrap_or_default(),
+        dry_run: DryRun::new(dry_run.unwrap_or_default()),
+    };
+
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
+    let base_path = graph.base_path_or(base_path)?;
+    let token = graph.token_or(token.as_deref())?;
+    let cancel = graph.cancel_handle(None);
+    let remove_listener = cancel_on_abort(&env, signal, &cancel)?;
+    let promise = env.spawn_future(async move {
+        match graph
+            .update_local_files(base_path, file_paths, &token, &options, &cancel)
+            .await
+        {
+            Ok(update) => Ok(UpdateLocalFilesResult {
+                planned: options.dry_run.planned(),
+                ..update.into()
+            }),
+            Err(e) => {
+                log::error!("update local files error: {:?}", e);
+                Err(e.into())
+            }
+        }
+    })?;
+    remove_listener_on_settle(promise, remove_listener)
 }
 
 // Version files are saved in S3 with uuid as file names.
-#[napi]
-pub async fn update_local_version_files(
+/// signal: AbortSignal to cancel the operation
+#[napi(ts_return_type = "Promise<FilesResult>")]
+pub fn update_local_version_files(
+    env: Env,
     graph_uuid: String,
-    base_path: String,
+    base_path: Option<String>,
     file_paths: Vec<String>,
-    token: String,
-) -> Result<()> {
+    token: Option<String>,
+    #[napi(ts_arg_type = "AbortSignal")] signal: Option<JsObject>,
+) -> Result<JsObject> {
     log::debug!("download version files: {:?}", file_paths);
 
-    let graph = implementation::get_graph(&graph_uuid)?;
-    graph
-        .update_local_version_files(base_path, file_paths, &token)
-        .await?;
-
-    Ok(())
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
+    let base_path = graph.base_path_or(base_path)?;
+    let token = graph.token_or(token.as_deref())?;
+    let cancel = graph.cancel_handle(None);
+    let remove_listener = cancel_on_abort(&env, signal, &cancel)?;
+    let promise = env.spawn_future(async move {
+        let downloaded = graph
+            .update_local_version_files(base_path, file_paths, &token, &cancel)
+            .await?;
+
+        Ok(FilesResult::from(downloaded))
+    })?;
+    remove_listener_on_settle(promise, remove_listener)
 }
 
-#[napi]
-pub async fn update_remote_files(
+/// signal: AbortSignal to cancel the operation,
+/// force: upload files even if unchanged remotely
+/// dry_run: report planned uploads without uploading
+#[allow(clippy::too_many_arguments)]
+#[napi(ts_return_type = "Promise<UpdateRemoteFilesResult>")]
+pub fn update_remote_files(
+    env: Env,
     graph_uuid: String,
-    base_path: String,
+    base_path: Option<String>,
     file_paths: Vec<String>,
     txid: i64,
-    token: String,
-    _metadata: Option<Metadata>,
-) -> Result<i64> {
+    token: Option<String>,
+    metadata: Option<Metadata>,
+    #[napi(ts_arg_type = "AbortSignal")] signal: Option<JsObject>,
+    force: Option<bool>,
+    dry_run: Option<bool>,
+) -> Result<JsObject> {
     log::info!("update remote files[txid={}]: {:?}", txid, file_paths);
 
-    let graph = implementation::get_graph(&graph_uuid)?;
-    let mut retries = 0;
-    loop {
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
+    let base_path = graph.base_path_or(base_path)?;
+    let token = graph.token_or(token.as_deref())?;
+    let cancel = graph.cancel_handle(None);
+    let remove_listener = cancel_on_abort(&env, signal, &cancel)?;
+    let options = UploadOptions {
+        metadata,
+        force: force.unwrap_or_default(),
+        dry_run: DryRun::new(dry_run.unwrap_or_default()),
+    };
+    let promise = env.spawn_future(async move {
         match graph
-            .update_remote_files(&base_path, &file_paths, txid, &token, None)
+            .update_remote_files(base_path, file_paths, txid, &token, &options, &cancel)
             .await
         {
-            Ok(txid) => {
-                log::debug!("update remote files success, txid={}", txid);
-                return Ok(txid);
+            Ok(update) => {
+                log::debug!("update remote files success, txid={}", update.txid);
+                Ok(UpdateRemoteFilesResult {
+                    planned: options.dry_run.planned(),
+                    ..update.into()
+                })
             }
             Err(e) => {
-                if e.to_string().contains("ExpiredToken") {
-                    log::warn!("token expired, retry");
-                }
-                if retries >= 2 {
-                    log::error!("update remote files: {}", e);
-                    return Err(e.into());
-                }
-                log::warn!("update remote files(retry={}): {}", retries, e);
-                retries += 1;
+                log::error!("update remote files: {}", e);
+                Err(e.into())
             }
         }
-    }
+    })?;
+    remove_listener_on_settle(promise, remove_listener)
 }
 
 /// (delete-remote-file [this graph-uuid base-path filepath local-txid access-token]))#[napi]
-#[napi]
-pub async fn delete_remote_files(
+/// signal: AbortSignal to cancel the operation
+/// dry_run: return planned deletes in `planned`, without deleting
+#[allow(clippy::too_many_arguments)]
+#[napi(ts_return_type = "Promise<UpdateRemoteFilesResult>")]
+pub fn delete_remote_files(
+    env: Env,
     graph_uuid: String,
-    base_path: String,
+    base_path: Option<String>,
     file_paths: Vec<String>,
     txid: i64,
-    token: String,
-) -> Result<i64> {
+    token: Option<String>,
+    #[napi(ts_arg_type = "AbortSignal")] signal: Option<JsObject>,
+    dry_run: Option<bool>,
+) -> Result<JsObject> {
     log::info!("delete remote files[txid={}]: {:?}", txid, file_paths);
 
-    let graph = implementation::get_graph(&graph_uuid)?;
-    let txid = graph
-        .delete_remote_files(base_path, file_paths, txid, &token)
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
+    let base_path = graph.base_path_or(base_path)?;
+    let token = graph.token_or(token.as_deref())?;
+    let cancel = graph.cancel_handle(None);
+    let dry_run = DryRun::new(dry_run.unwrap_or_default());
+    let remove_listener = cancel_on_abort(&env, signal, &cancel)?;
+    let promise = env.spawn_future(async move {
+        let update = graph
+            .delete_remote_files(base_path, file_paths, txid, &token, &dry_run, &cancel)
+            .await?;
+
+        Ok(UpdateRemoteFilesResult {
+            planned: dry_run.planned(),
+            ..update.into()
+        })
+    })?;
+    remove_listener_on_settle(promise, remove_listener)
+}
+
+/// Merge incoming page files(fetched by `fetch_remote_files`) into local files.
+/// Conflicting files are left untouched unless `write_conflict_markers` is set.
+#[napi]
+pub async fn merge_remote_files(
+    graph_uuid: String,
+    base_path: Option<String>,
+    file_paths: Vec<String>,
+    write_conflict_markers: Option<bool>,
+) -> Result<MergeResult> {
+    log::info!("merge remote files: {:?}", file_paths);
+
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
+    let base_path = graph.base_path_or(base_path)?;
+    let reports = graph
+        .merge_remote_files(
+            &base_path,
+            &file_paths,
+            write_conflict_markers.unwrap_or_default(),
+        )
         .await?;
+    Ok(reports.into())
+}
 
-    Ok(txid)
+/// Compute sync plan: upload, download, delete-local, delete-remote, rename-local,
+/// rename-remote and conflict actions.
+/// Compare with remote full file list when txid is 0, otherwise remote changes since txid.
+#[napi]
+pub async fn plan_sync(
+    graph_uuid: String,
+    base_path: Option<String>,
+    txid: i64,
+    token: Option<String>,
+) -> Result<serde_json::Value> {
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
+    let base_path = graph.base_path_or(base_path)?;
+    let token = graph.token_or(token.as_deref())?;
+    let plan = graph.plan_sync(&base_path, txid, &token).await?;
+    log::info!(
+        "sync plan[txid={}]: {} actions",
+        plan.txid,
+        plan.actions.len()
+    );
+    Ok(serde_json::to_value(plan)?)
 }
 
 /// Encryption API
@@ -354,7 +733,7 @@ pub fn age_decrypt_with_passphrase(
 pub fn encrypt_fnames(graph_uuid: String, fnames: Vec<String>) -> Result<Vec<String>> {
     use rayon::prelude::*;
 
-    let graph = implementation::get_graph(&graph_uuid)?;
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
     fnames
         .par_iter()
         .map(|p| Ok(graph.encrypt_filename(p)?))
@@ -365,13 +744,54 @@ pub fn encrypt_fnames(graph_uuid: String, fnames: Vec<String>) -> Result<Vec<Str
 pub fn decrypt_fnames(graph_uuid: String, fnames: Vec<String>) -> Result<Vec<String>> {
     use rayon::prelude::*;
 
-    let graph = implementation::get_graph(&graph_uuid)?;
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
     fnames
         .par_iter()
         .map(|p| Ok(graph.decrypt_filename(p)?))
         .collect()
 }
 
+/// Local files whose paths differ only in Unicode normalization form, only one of them is synced
+#[napi]
+pub async fn get_local_unicode_collisions(
+    graph_uuid: String,
+    base_path: Option<String>,
+) -> Result<Vec<UnicodeCollision>> {
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
+    let base_path = graph.base_path_or(base_path)?;
+    Ok(graph.find_unicode_collisions(base_path).await?)
+}
+
+/// Local symlinks and special files(sockets, FIFOs and devices) not synced
+#[napi]
+pub async fn get_local_skipped_files(
+    graph_uuid: String,
+    base_path: Option<String>,
+) -> Result<Vec<SkippedFile>> {
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
+    let base_path = graph.base_path_or(base_path)?;
+    Ok(graph.find_skipped_files(base_path).await?)
+}
+
+/// Prune version and backup files of a graph by age, count per page and total size,
+/// and remove base copies of deleted pages
+#[napi]
+pub async fn cleanup_version_files(
+    graph_uuid: String,
+    base_path: Option<String>,
+    options: CleanupOptions,
+) -> Result<CleanupReport> {
+    let graph = CONTEXT.get_graph(&graph_uuid)?;
+    let base_path = graph.base_path_or(base_path)?;
+    Ok(housekeeping::cleanup(base_path, &options).await?)
+}
+
+/// Detect whether the filesystem of base path is case sensitive
+#[napi]
+pub async fn is_fs_case_sensitive(base_path: String) -> Result<bool> {
+    Ok(CONTEXT.is_case_sensitive(base_path).await?)
+}
+
 /// Helper
 #[napi]
 pub async fn canonicalize_path(file_path: String) -> Result<String> {
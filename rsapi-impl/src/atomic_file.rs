@@ -0,0 +1,155 @@
+//! Crash-safe file writes.
+//!
+//! Content is written to a temp file in the same folder, synced to disk, then renamed over
+//! the target, so the target always holds either the old or the new content.
+//! Temp files left by a crash are removed while scanning graph files, see `is_stale_temp_file`.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::error::{Error, Result};
+
+/// Extension of temp files, temp files are also hidden files
+pub const TEMP_FILE_EXT: &str = "rsapi-tmp";
+
+/// Younger temp files may belong to a write in progress
+const STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+
+static TEMP_FILE_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Removes the temp file when a write fails or its future is dropped(cancelled)
+struct TempFileGuard(Option<PathBuf>);
+
+impl Drop for TempFileGuard {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// `pages/a.md` => `pages/.a.md.<pid>-<seq>.rsapi-tmp`
+fn temp_path_of(path: &Path) -> Result<PathBuf> {
+    let fname = path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .ok_or(Error::InvalidArg)?;
+    let seq = TEMP_FILE_SEQ.fetch_add(1, Ordering::Relaxed);
+    Ok(path.with_file_name(format!(
+        ".{}.{}-{}.{}",
+        fname,
+        std::process::id(),
+        seq,
+        TEMP_FILE_EXT
+    )))
+}
+
+/// pid of the process writing the temp file
+fn temp_file_owner(fname: &str) -> Option<u32> {
+    let stem = fname.strip_suffix(TEMP_FILE_EXT)?.strip_suffix('.')?;
+    let (_, owner) = stem.rsplit_once('.')?;
+    owner.split_once('-')?.0.parse().ok()
+}
+
+/// Write content to path atomically, parent folders are created
+pub async fn write_file<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, content: C) -> Result<()> {
+    let path = path.as_ref();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let temp_path = temp_path_of(path)?;
+    let mut guard = TempFileGuard(Some(temp_path.clone()));
+
+    let mut file = fs::File::create(&temp_path).await?;
+    file.write_all(content.as_ref()).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    // keep permissions of the replaced file
+    if let Ok(metadata) = fs::metadata(path).await {
+        let _ = fs::set_permissions(&temp_path, metadata.permissions()).await;
+    }
+    fs::rename(&temp_path, path).await?;
+    guard.0 = None;
+
+    // persist the rename
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        if let Ok(dir) = fs::File::open(dir).await {
+            let _ = dir.sync_all().await;
+        }
+    }
+    Ok(())
+}
+
+/// Is path a temp file left by a crashed write: written by another process, an hour ago or earlier
+pub fn is_stale_temp_file(path: &Path) -> bool {
+    let owned_by_other = path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .and_then(temp_file_owner)
+        .is_some_and(|owner| owner != std::process::id());
+    owned_by_other
+        && std::fs::symlink_metadata(path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.elapsed().ok())
+            .is_some_and(|age| age >= STALE_TEMP_FILE_AGE)
+}
+
+/// Remove stale temp files, returns number of removed files
+pub async fn remove_temp_files(paths: Vec<PathBuf>) -> usize {
+    let mut removed = 0;
+    for path in paths {
+        match fs::remove_file(&path).await {
+            Ok(()) => {
+                log::info!("remove stale temp file: {:?}", path);
+                removed += 1;
+            }
+            Err(e) => log::warn!("cannot remove stale temp file {:?}: {}", path, e),
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_file_and_cleanup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pages/a.md");
+
+        write_file(&path, "- old").await.unwrap();
+        write_file(&path, "- new").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "- new");
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("pages")).unwrap().count(),
+            1
+        );
+
+        let stale = dir.path().join("pages/.a.md.0-0.rsapi-tmp");
+        std::fs::write(&stale, "- partial").unwrap();
+        assert_eq!(temp_file_owner(".a.md.0-0.rsapi-tmp"), Some(0));
+        assert_eq!(temp_file_owner(".a.md"), None);
+        // may be written right now
+        assert!(!is_stale_temp_file(&stale));
+        std::fs::File::options()
+            .write(true)
+            .open(&stale)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - 2 * STALE_TEMP_FILE_AGE)
+            .unwrap();
+        assert!(is_stale_temp_file(&stale));
+        assert!(!is_stale_temp_file(&path));
+
+        assert_eq!(remove_temp_files(vec![stale.clone()]).await, 1);
+        assert!(!stale.exists());
+        assert!(path.exists());
+    }
+}
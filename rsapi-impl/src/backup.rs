@@ -0,0 +1,115 @@
+//! Backups of local files overwritten or deleted by sync.
+//!
+//! Backups follow the layout of Logseq's own backups, e.g. `pages/a.md` is backed up to
+//! `logseq/bak/pages/a/2024-01-02T03_04_05.678Z.Desktop.md`. They are pruned by
+//! `housekeeping::cleanup`.
+
+use std::path::Path;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use tokio::fs;
+
+use crate::error::Result;
+
+const BAK: &str = "logseq/bak";
+
+fn platform() -> &'static str {
+    match std::env::consts::OS {
+        "android" => "Android",
+        "ios" => "iOS",
+        _ => "Desktop",
+    }
+}
+
+/// Backup path of a graph file, relative to graph base path
+pub fn backup_path(file_path: &str, time: DateTime<Utc>) -> String {
+    let (dir, fname) = match file_path.rsplit_once('/') {
+        Some((dir, fname)) => (Some(dir), fname),
+        None => (None, file_path),
+    };
+    let (stem, ext) = match fname.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, Some(ext)),
+        _ => (fname, None),
+    };
+    let time = time
+        .to_rfc3339_opts(SecondsFormat::Millis, true)
+        .replace(':', "_");
+
+    let mut path = BAK.to_string();
+    for part in dir.into_iter().chain([stem]) {
+        path.push('/');
+        path.push_str(part);
+    }
+    path.push('/');
+    path.push_str(&time);
+    path.push('.');
+    path.push_str(platform());
+    if let Some(ext) = ext {
+        path.push('.');
+        path.push_str(ext);
+    }
+    path
+}
+
+/// Copy a local file to backup before it is overwritten.
+/// Return backup path, none if there is no regular file to back up.
+pub(crate) async fn copy_to_backup(
+    base_path: &Path,
+    file_path: &str,
+    local_path: &Path,
+) -> Result<Option<String>> {
+    backup(base_path, file_path, local_path, false).await
+}
+
+/// Move a local file to backup, instead of deleting it.
+/// Return backup path, none if there is no regular file to back up.
+pub(crate) async fn move_to_backup(
+    base_path: &Path,
+    file_path: &str,
+    local_path: &Path,
+) -> Result<Option<String>> {
+    backup(base_path, file_path, local_path, true).await
+}
+
+async fn backup(
+    base_path: &Path,
+    file_path: &str,
+    local_path: &Path,
+    remove: bool,
+) -> Result<Option<String>> {
+    match fs::symlink_metadata(local_path).await {
+        Ok(metadata) if metadata.is_file() => {}
+        _ => return Ok(None),
+    }
+    let backup_path = backup_path(file_path, Utc::now());
+    let target = base_path.join(&backup_path);
+    if let Some(dir) = target.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    if remove {
+        fs::rename(local_path, &target).await?;
+    } else {
+        fs::copy(local_path, &target).await?;
+    }
+    log::debug!("backup {:?} to {:?}", file_path, backup_path);
+    Ok(Some(backup_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_backup_path() {
+        let time = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        assert_eq!(
+            backup_path("pages/a.b.md", time),
+            "logseq/bak/pages/a.b/2024-01-02T03_04_05.000Z.Desktop.md"
+        );
+        assert_eq!(
+            backup_path(".gitignore", time),
+            "logseq/bak/.gitignore/2024-01-02T03_04_05.000Z.Desktop"
+        );
+    }
+}
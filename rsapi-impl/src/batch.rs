@@ -0,0 +1,116 @@
+//! Per-file results of batch operations.
+//!
+//! A failed file doesn't fail the whole batch: batch operations return the files done
+//! along with an error for each file that failed. Errors affecting the whole batch,
+//! e.g. a failed API request or a cancel, are still returned as `Err`.
+
+#[cfg(feature = "napi")]
+use napi_derive::napi;
+use serde::Serialize;
+
+use crate::error::Error;
+
+/// Error of a file in a batch
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FileError {
+    pub path: String,
+    /// see `Error::kind`, e.g. "not-found", "permission-denied" or "sync"
+    pub kind: String,
+    pub message: String,
+}
+
+impl FileError {
+    pub fn new(path: &str, error: &Error) -> Self {
+        FileError {
+            path: path.to_string(),
+            kind: error.kind().to_string(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Files done by a batch operation, and errors of failed files
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BatchResult<T> {
+    pub succeeded: Vec<T>,
+    pub failed: Vec<FileError>,
+}
+
+impl<T> Default for BatchResult<T> {
+    fn default() -> Self {
+        BatchResult {
+            succeeded: vec![],
+            failed: vec![],
+        }
+    }
+}
+
+impl<T> BatchResult<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, path: &str, result: Result<T, Error>) {
+        match result {
+            Ok(item) => self.succeeded.push(item),
+            Err(e) => self.fail(path, &e),
+        }
+    }
+
+    pub fn fail(&mut self, path: &str, error: &Error) {
+        log::warn!("{:?} failed: {}", path, error);
+        self.failed.push(FileError::new(path, error));
+    }
+
+    pub fn is_all_succeeded(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    pub fn map<U, F: FnMut(T) -> U>(self, f: F) -> BatchResult<U> {
+        BatchResult {
+            succeeded: self.succeeded.into_iter().map(f).collect(),
+            failed: self.failed,
+        }
+    }
+}
+
+impl<T, S: AsRef<str>> FromIterator<(S, Result<T, Error>)> for BatchResult<T> {
+    fn from_iter<I: IntoIterator<Item = (S, Result<T, Error>)>>(iter: I) -> Self {
+        let mut batch = BatchResult::new();
+        for (path, result) in iter {
+            batch.push(path.as_ref(), result);
+        }
+        batch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_batch_result() {
+        let not_found = std::io::Error::from(std::io::ErrorKind::NotFound);
+        let batch: BatchResult<i32> = vec![
+            ("a", Ok(1)),
+            ("b", Err(Error::from(not_found))),
+            ("c", Err(Error::UnsafePath("../c".into()))),
+            ("d", Ok(2)),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(batch.succeeded, vec![1, 2]);
+        assert!(!batch.is_all_succeeded());
+        assert_eq!(
+            batch
+                .failed
+                .iter()
+                .map(|e| (&*e.path, &*e.kind))
+                .collect::<Vec<_>>(),
+            vec![("b", "not-found"), ("c", "unsafe-path")]
+        );
+        assert_eq!(batch.map(|n| n * 10).succeeded, vec![10, 20]);
+    }
+}
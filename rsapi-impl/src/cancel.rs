@@ -0,0 +1,188 @@
+//! Cancellation of sync operations.
+//!
+//! Tokens form a tree: all requests => graph => operation. Cancelling a graph aborts
+//! in-flight operations of that graph only, and an operation can be cancelled by its id.
+//! Later operations get fresh tokens, so a cancel never outlives the requests it targets.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio_util::sync::CancellationToken;
+
+// operation id => (seq, token)
+type Operations = Arc<Mutex<HashMap<String, (u64, CancellationToken)>>>;
+
+/// Cancellation tokens of a context
+#[derive(Default)]
+pub struct Cancellation {
+    root: Mutex<CancellationToken>,
+    // graph uuid => token
+    graphs: Mutex<HashMap<String, CancellationToken>>,
+    operations: Operations,
+    seq: AtomicU64,
+}
+
+impl Cancellation {
+    fn graph_token(&self, graph_uuid: &str) -> CancellationToken {
+        let root = self.root.lock().unwrap().clone();
+        let mut tokens = self.graphs.lock().unwrap();
+        let token = tokens
+            .entry(graph_uuid.to_string())
+            .or_insert_with(|| root.child_token());
+        if token.is_cancelled() {
+            *token = root.child_token();
+        }
+        token.clone()
+    }
+
+    /// Register an operation of the graph, an id is generated if not given
+    pub fn new_handle(&self, graph_uuid: &str, id: Option<String>) -> CancelHandle {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let id = id.unwrap_or_else(|| format!("op-{}", seq));
+        let token = self.graph_token(graph_uuid).child_token();
+        if self
+            .operations
+            .lock()
+            .unwrap()
+            .insert(id.clone(), (seq, token.clone()))
+            .is_some()
+        {
+            log::warn!("duplicated operation id: {}", id);
+        }
+        CancelHandle {
+            id,
+            seq,
+            token,
+            operations: self.operations.clone(),
+        }
+    }
+
+    /// Cancel all in-flight operations of all graphs
+    pub fn cancel_all(&self) {
+        let root = std::mem::take(&mut *self.root.lock().unwrap());
+        self.graphs.lock().unwrap().clear();
+        root.cancel();
+    }
+
+    /// Cancel in-flight operations of a graph
+    pub fn cancel_graph(&self, graph_uuid: &str) {
+        if let Some(token) = self.graphs.lock().unwrap().remove(graph_uuid) {
+            token.cancel();
+        }
+    }
+
+    /// Cancel an operation by id, return false if not found(finished or unknown)
+    pub fn cancel_operation(&self, id: &str) -> bool {
+        match self.operations.lock().unwrap().get(id) {
+            Some((_, token)) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Cancellation handle of a running operation, unregistered when dropped
+pub struct CancelHandle {
+    id: String,
+    seq: u64,
+    token: CancellationToken,
+    operations: Operations,
+}
+
+impl CancelHandle {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The underlying token, e.g. to cancel from an abort signal
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Resolves when the operation is cancelled
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+}
+
+impl Drop for CancelHandle {
+    fn drop(&mut self) {
+        let mut operations = self.operations.lock().unwrap();
+        // the id may be reused by a later operation
+        if operations
+            .get(&self.id)
+            .is_some_and(|(seq, _)| *seq == self.seq)
+        {
+            operations.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_tree() {
+        let cancellation = Cancellation::default();
+        let a1 = cancellation.new_handle("a", Some("a1".into()));
+        let a2 = cancellation.new_handle("a", None);
+        let b = cancellation.new_handle("b", None);
+
+        assert!(cancellation.cancel_operation("a1"));
+        assert!(a1.is_cancelled());
+        assert!(!a2.is_cancelled());
+
+        cancellation.cancel_graph("a");
+        assert!(a2.is_cancelled());
+        assert!(!b.is_cancelled());
+        assert!(!cancellation.new_handle("a", None).is_cancelled());
+
+        drop(a1);
+        assert!(!cancellation.cancel_operation("a1"));
+
+        cancellation.cancel_all();
+        assert!(b.is_cancelled());
+        assert!(!cancellation.new_handle("b", None).is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_delete_remote_files() {
+        use serde_json::json;
+        use sync::mock::{json_body, MockServer};
+
+        use crate::dry_run::DryRun;
+        use crate::error::Error;
+
+        let (secret_key, public_key) = lsq_encryption::keygen();
+        let ctx = crate::RsapiContext::new();
+        ctx.set_env("graph", "dev", &secret_key, &public_key, &[], None, None)
+            .unwrap();
+        let mut graph = (*ctx.get_graph("graph").unwrap()).clone();
+        let server = MockServer::start(|_| json_body(json!({"TXId": 4}))).await;
+        graph.endpoint = server.endpoint();
+
+        let cancel = graph.cancel_handle(Some("delete".into()));
+        assert!(ctx.cancel_operation("delete"));
+        let dir = tempfile::tempdir().unwrap();
+        let ret = graph
+            .delete_remote_files(
+                dir.path(),
+                ["pages/a.md"],
+                3,
+                "token",
+                &DryRun::default(),
+                &cancel,
+            )
+            .await;
+        assert!(matches!(ret, Err(Error::Cancelled)));
+        assert!(server.requests_of("delete_files").is_empty());
+    }
+}
@@ -0,0 +1,575 @@
+//! Chunked storage of large files, for delta sync.
+//!
+//! Content is split by content-defined chunking, so an edit only changes the chunks around it.
+//! Chunks are encrypted and stored individually, keyed by a keyed hash of their content, and
+//! the file itself is stored as a manifest referencing its chunks. Only chunks missing
+//! remotely are uploaded. Downloads reassemble the chunks and verify them against the manifest.
+//!
+//! Versions without chunked storage download the manifest as the file content, writing
+//! `logseq-chunked:{json}` over local files, so chunked storage is opt-in per graph, and
+//! only used when the server reports the capability. Content not chunked that starts with
+//! the manifest header is stored escaped, see `escape`.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::prelude::*;
+use hmac::{Hmac, Mac};
+use lsq_encryption::md5_hexdigest;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sync::SyncClient;
+
+use crate::error::{Error, Result};
+use crate::graph::Graph;
+
+// header of manifest content, followed by the manifest JSON
+const MANIFEST_HEADER: &[u8] = b"logseq-chunked:";
+// prefix of stored content not chunked, that would otherwise be taken as a manifest
+const ESCAPE_HEADER: &[u8] = b"logseq-escaped:";
+// server capability of storing chunks
+const CAPABILITY: &str = "chunked-storage";
+// label deriving the key of chunk ids from the filename key
+const CHUNK_KEY_LABEL: &[u8] = b"logseq-chunk-id";
+
+const MIN_CHUNK_SIZE: usize = 256 * 1024;
+const AVG_CHUNK_SIZE: usize = 1024 * 1024;
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+// normalized chunking: harder to cut below the average size, easier above
+const MASK_S: u64 = mask(22);
+const MASK_L: u64 = mask(18);
+
+const CONCURRENCY: usize = 4;
+
+// high bits of the gear hash depend on a window of the last 64 bytes
+const fn mask(bits: u32) -> u64 {
+    ((1 << bits) - 1) << (64 - bits)
+}
+
+// random values of bytes, by splitmix64
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x6c6f_6773_6571;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Length of the next chunk of data
+fn next_chunk_len(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let normal = end.min(AVG_CHUNK_SIZE);
+    let mut hash = 0u64;
+    for (i, b) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+        let mask = if i < normal { MASK_S } else { MASK_L };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// Split content into chunks, by content-defined cut points
+pub fn split(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = vec![];
+    while !data.is_empty() {
+        let (chunk, rest) = data.split_at(next_chunk_len(data));
+        chunks.push(chunk);
+        data = rest;
+    }
+    chunks
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Key of chunk ids, derived from the graph's filename key instead of reusing it
+fn chunk_key(graph: &Graph) -> [u8; 32] {
+    hmac_sha256(&graph.fname_encryption_key, CHUNK_KEY_LABEL)
+}
+
+/// Chunk id, keyed by the graph's chunk key so that ids reveal nothing of the content
+fn chunk_id(key: &[u8], chunk: &[u8]) -> String {
+    hex::encode(hmac_sha256(key, chunk))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChunkRef {
+    pub id: String,
+    pub size: u64,
+}
+
+/// Stored content of a chunked file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub size: u64,
+    /// md5 of the whole content
+    pub md5: String,
+    pub chunks: Vec<ChunkRef>,
+}
+
+impl Manifest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let json = serde_json::to_vec(self).expect("manifest is serializable");
+        [MANIFEST_HEADER, &json].concat()
+    }
+
+    /// Manifest of stored content, none for content not chunked
+    pub fn parse(content: &[u8]) -> Option<Result<Manifest>> {
+        let json = content.strip_prefix(MANIFEST_HEADER)?;
+        Some(serde_json::from_slice(json).map_err(|e| Error::Corrupted(e.to_string())))
+    }
+}
+
+/// Stored form of content not chunked: content starting with the manifest header, or the
+/// escape header itself, is prefixed with the escape header
+pub fn escape(content: &[u8]) -> Cow<'_, [u8]> {
+    if content.starts_with(MANIFEST_HEADER) || content.starts_with(ESCAPE_HEADER) {
+        [ESCAPE_HEADER, content].concat().into()
+    } else {
+        content.into()
+    }
+}
+
+/// Content of stored content not chunked, reverse of `escape`
+pub fn unescape(stored: Vec<u8>) -> Vec<u8> {
+    match stored.strip_prefix(ESCAPE_HEADER) {
+        Some(content) => content.to_vec(),
+        None => stored,
+    }
+}
+
+/// Whether the server stores chunks of the graph
+pub async fn is_supported(client: &SyncClient, graph_uuid: &str) -> Result<bool> {
+    let graph = client.get_graph_by_uuid(graph_uuid).await?;
+    Ok(graph.capabilities.iter().any(|c| c == CAPABILITY))
+}
+
+/// Remote storage of chunks, of a graph.
+/// Chunks of a file are stored and located in one request each.
+pub trait ChunkStore: Sync {
+    /// Chunks of ids not stored yet
+    fn missing_chunks(
+        &self,
+        ids: &[String],
+    ) -> impl Future<Output = Result<HashSet<String>>> + Send;
+
+    /// Upload an encrypted chunk, return its upload key for `store_chunks`
+    fn upload_chunk(&self, content: Vec<u8>) -> impl Future<Output = Result<String>> + Send;
+
+    /// Store uploaded chunks, chunk id => upload key
+    fn store_chunks(
+        &self,
+        chunks: &HashMap<String, String>,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Download urls of chunks, chunk id => url
+    fn chunk_urls(
+        &self,
+        ids: &[&str],
+    ) -> impl Future<Output = Result<HashMap<String, String>>> + Send;
+
+    /// Encrypted content of a chunk, by its download url
+    fn download_chunk(&self, url: &str) -> impl Future<Output = Result<Vec<u8>>> + Send;
+}
+
+impl ChunkStore for SyncClient {
+    async fn missing_chunks(&self, ids: &[String]) -> Result<HashSet<String>> {
+        Ok(self.get_missing_chunks(ids).await?.into_iter().collect())
+    }
+
+    async fn upload_chunk(&self, content: Vec<u8>) -> Result<String> {
+        Ok(self.upload_tempfile(Cow::Owned(content), |_, _| {}).await?)
+    }
+
+    async fn store_chunks(&self, chunks: &HashMap<String, String>) -> Result<()> {
+        Ok(self.put_chunks(chunks).await?)
+    }
+
+    async fn chunk_urls(&self, ids: &[&str]) -> Result<HashMap<String, String>> {
+        Ok(self.get_chunks(ids).await?)
+    }
+
+    async fn download_chunk(&self, url: &str) -> Result<Vec<u8>> {
+        Ok(self.download_file(url, |_, _| {}).await?)
+    }
+}
+
+/// Store content as encrypted chunks, return its manifest.
+/// progress: bytes of chunks uploaded, bytes of chunks to upload
+pub async fn upload<S: ChunkStore>(
+    store: &S,
+    graph: &Graph,
+    content: &[u8],
+    progress: &(impl Fn(usize, usize) + Sync),
+) -> Result<Manifest> {
+    let key = chunk_key(graph);
+    let chunks = split(content)
+        .into_iter()
+        .map(|chunk| (chunk_id(&key, chunk), chunk))
+        .collect::<Vec<_>>();
+    let manifest = Manifest {
+        size: content.len() as _,
+        md5: md5_hexdigest(content),
+        chunks: chunks
+            .iter()
+            .map(|(id, chunk)| ChunkRef {
+                id: id.clone(),
+                size: chunk.len() as _,
+            })
+            .collect(),
+    };
+
+    let ids = chunks.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>();
+    let missing = store.missing_chunks(&ids).await?;
+    // a chunk may repeat in the content
+    let pending = chunks
+        .into_iter()
+        .filter(|(id, _)| missing.contains(id))
+        .collect::<HashMap<_, _>>();
+    let total = pending.values().map(|chunk| chunk.len()).sum();
+    log::debug!(
+        "upload {} of {} chunks, {} bytes",
+        pending.len(),
+        manifest.chunks.len(),
+        total
+    );
+
+    let done = &AtomicUsize::new(0);
+    let mut uploads = vec![];
+    for (id, chunk) in pending {
+        uploads.push(async move {
+            let encrypted = graph.encrypt_content(chunk)?.into_owned();
+            let upload_key = store.upload_chunk(encrypted).await?;
+            let done = done.fetch_add(chunk.len(), Ordering::Relaxed) + chunk.len();
+            progress(done, total);
+            Ok::<_, Error>((id, upload_key))
+        });
+    }
+    let uploaded = stream::iter(uploads)
+        .buffer_unordered(CONCURRENCY)
+        .try_collect::<HashMap<_, _>>()
+        .await?;
+    if !uploaded.is_empty() {
+        store.store_chunks(&uploaded).await?;
+    }
+    Ok(manifest)
+}
+
+/// Download and reassemble content of a manifest, verifying chunks and the whole content
+pub async fn download<S: ChunkStore>(
+    store: &S,
+    graph: &Graph,
+    manifest: &Manifest,
+) -> Result<Vec<u8>> {
+    let key = &chunk_key(graph);
+    let ids = manifest
+        .chunks
+        .iter()
+        .map(|c| c.id.as_str())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let urls = store.chunk_urls(&ids).await?;
+    let mut downloads = vec![];
+    for id in ids {
+        let url = urls
+            .get(id)
+            .ok_or_else(|| Error::Corrupted(format!("chunk {} not found", id)))?;
+        downloads.push(async move {
+            let encrypted = store.download_chunk(url).await?;
+            let chunk = graph.decrypt_content(&encrypted)?.into_owned();
+            if chunk_id(key, &chunk) != id {
+                return Err(Error::Corrupted(format!("chunk {} mismatch", id)));
+            }
+            Ok((id, chunk))
+        });
+    }
+    let chunks = stream::iter(downloads)
+        .buffer_unordered(CONCURRENCY)
+        .try_collect::<HashMap<_, _>>()
+        .await?;
+
+    let mut content = Vec::with_capacity(manifest.size as _);
+    for c in &manifest.chunks {
+        let chunk = &chunks[c.id.as_str()];
+        if chunk.len() as u64 != c.size {
+            return Err(Error::Corrupted(format!("chunk {} size mismatch", c.id)));
+        }
+        content.extend_from_slice(chunk);
+    }
+    if content.len() as u64 != manifest.size || md5_hexdigest(&content) != manifest.md5 {
+        return Err(Error::Corrupted("chunked content mismatch".to_string()));
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::json;
+    use sync::mock::{json_body, MockServer};
+
+    use crate::graph::DownloadOptions;
+    use crate::RsapiContext;
+
+    type Blobs = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// Chunk store of the sync API, uploading chunks to memory instead of the bucket
+    struct ServerStore {
+        client: SyncClient,
+        // upload key => content, shared with the mock server
+        uploads: Blobs,
+        puts: AtomicUsize,
+    }
+
+    impl ChunkStore for ServerStore {
+        async fn missing_chunks(&self, ids: &[String]) -> Result<HashSet<String>> {
+            self.client.missing_chunks(ids).await
+        }
+
+        async fn upload_chunk(&self, content: Vec<u8>) -> Result<String> {
+            let n = self.puts.fetch_add(1, Ordering::Relaxed);
+            let upload_key = format!("tmp/{}", n);
+            self.uploads
+                .lock()
+                .unwrap()
+                .insert(upload_key.clone(), content);
+            Ok(upload_key)
+        }
+
+        async fn store_chunks(&self, chunks: &HashMap<String, String>) -> Result<()> {
+            self.client.store_chunks(chunks).await
+        }
+
+        async fn chunk_urls(&self, ids: &[&str]) -> Result<HashMap<String, String>> {
+            self.client.chunk_urls(ids).await
+        }
+
+        async fn download_chunk(&self, url: &str) -> Result<Vec<u8>> {
+            self.client.download_chunk(url).await
+        }
+    }
+
+    /// Mock server storing chunks, and serving `files` by encrypted path
+    async fn chunk_server(uploads: Blobs, chunks: Blobs, files: Blobs) -> MockServer {
+        MockServer::start(move |req| {
+            let path = &*req.path;
+            if let Some(id) = path.strip_prefix("chunks/") {
+                return chunks.lock().unwrap().get(id).cloned().unwrap_or_default();
+            }
+            if let Some(name) = path.strip_prefix("files/") {
+                return files.lock().unwrap().get(name).cloned().unwrap_or_default();
+            }
+            match path {
+                "get_graph" => json_body(json!({
+                    "GraphUUID": "graph",
+                    "TXId": 1,
+                    "Capabilities": ["chunked-storage"],
+                })),
+                "get_missing_chunks" => {
+                    let chunks = chunks.lock().unwrap();
+                    let missing = req.json()["Chunks"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .filter(|id| !chunks.contains_key(id.as_str().unwrap()))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    json_body(json!({ "MissingChunks": missing }))
+                }
+                "put_chunks" => {
+                    let mut uploads = uploads.lock().unwrap();
+                    for (id, upload_key) in req.json()["Chunks"].as_object().unwrap() {
+                        let content = uploads.remove(upload_key.as_str().unwrap()).unwrap();
+                        chunks.lock().unwrap().insert(id.clone(), content);
+                    }
+                    json_body(json!({}))
+                }
+                "get_chunks" => {
+                    let urls = req.json()["Chunks"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|id| {
+                            let id = id.as_str().unwrap();
+                            (id.to_string(), req.url(&format!("chunks/{}", id)))
+                        })
+                        .collect::<HashMap<_, _>>();
+                    json_body(json!({ "PresignedChunkUrls": urls }))
+                }
+                "get_files" => {
+                    let urls = req.json()["Files"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|name| {
+                            let name = name.as_str().unwrap();
+                            (name.to_string(), req.url(&format!("files/{}", name)))
+                        })
+                        .collect::<HashMap<_, _>>();
+                    json_body(json!({ "PresignedFileUrls": urls }))
+                }
+                _ => json_body(json!({"message": "not found"})),
+            }
+        })
+        .await
+    }
+
+    fn random_bytes(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_chunked_delta_upload() {
+        let (secret_key, public_key) = lsq_encryption::keygen();
+        let ctx = RsapiContext::new();
+        ctx.set_env("graph", "dev", &secret_key, &public_key, &[], None, None)
+            .unwrap();
+        let mut graph = (*ctx.get_graph("graph").unwrap()).clone();
+        let (uploads, chunks) = (Blobs::default(), Blobs::default());
+        let server = chunk_server(uploads.clone(), chunks.clone(), Blobs::default()).await;
+        graph.endpoint = server.endpoint();
+        let mut client = SyncClient::new(&graph.shared.sync, graph.endpoint, "token");
+        client.set_graph("graph", 1);
+        let store = ServerStore {
+            client,
+            uploads,
+            puts: AtomicUsize::new(0),
+        };
+        assert!(is_supported(&store.client, "graph").await.unwrap());
+        // servers without the capability get files as-is
+        let legacy =
+            MockServer::start(|_| json_body(json!({"GraphUUID": "graph", "TXId": 1}))).await;
+        let legacy_client = SyncClient::new(&graph.shared.sync, legacy.endpoint(), "token");
+        assert!(!is_supported(&legacy_client, "graph").await.unwrap());
+
+        // chunk ids are not keyed by the filename key itself
+        assert_ne!(chunk_key(&graph), graph.fname_encryption_key);
+
+        let content = random_bytes(6 * 1024 * 1024, 42);
+        let manifest = upload(&store, &graph, &content, &|_, _| {}).await.unwrap();
+        let uploaded = store.puts.load(Ordering::Relaxed);
+        assert!(uploaded > 3);
+        assert_eq!(uploaded, manifest.chunks.len());
+        // chunks of a file are stored at once
+        assert_eq!(server.requests_of("put_chunks").len(), 1);
+        assert!(manifest
+            .chunks
+            .iter()
+            .all(|c| c.size as usize <= MAX_CHUNK_SIZE));
+
+        // an insertion only changes the chunks around it
+        let mut edited = content.clone();
+        edited.splice(3_000_000..3_000_000, b"inserted".iter().copied());
+        let edited_manifest = upload(&store, &graph, &edited, &|_, _| {}).await.unwrap();
+        assert!(store.puts.load(Ordering::Relaxed) - uploaded <= 2);
+
+        let parsed = Manifest::parse(&edited_manifest.to_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(download(&store, &graph, &parsed).await.unwrap(), edited);
+        // chunks of a file are located at once
+        assert_eq!(server.requests_of("get_chunks").len(), 1);
+        assert!(Manifest::parse(b"- page content").is_none());
+
+        // chunks are verified
+        let id = &manifest.chunks[0].id;
+        let other = graph.encrypt_content(b"other").unwrap().into_owned();
+        chunks.lock().unwrap().insert(id.clone(), other);
+        assert!(matches!(
+            download(&store, &graph, &manifest).await,
+            Err(Error::Corrupted(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_download_chunked_and_escaped_files() {
+        let (secret_key, public_key) = lsq_encryption::keygen();
+        let ctx = RsapiContext::new();
+        ctx.set_env("graph", "dev", &secret_key, &public_key, &[], None, None)
+            .unwrap();
+        let mut graph = (*ctx.get_graph("graph").unwrap()).clone();
+        let (uploads, chunks, files) = (Blobs::default(), Blobs::default(), Blobs::default());
+        let server = chunk_server(uploads.clone(), chunks, files.clone()).await;
+        graph.endpoint = server.endpoint();
+        let mut client = SyncClient::new(&graph.shared.sync, graph.endpoint, "token");
+        client.set_graph("graph", 1);
+        let store = ServerStore {
+            client,
+            uploads,
+            puts: AtomicUsize::new(0),
+        };
+
+        let large = random_bytes(2 * 1024 * 1024, 7);
+        let manifest = upload(&store, &graph, &large, &|_, _| {}).await.unwrap();
+        // plain content colliding with the headers
+        let collided = b"logseq-chunked:{\"size\":0}".to_vec();
+        let escaped = [ESCAPE_HEADER, b"- a"].concat();
+        for (path, stored) in [
+            ("assets/large.bin", manifest.to_bytes()),
+            ("assets/collided.txt", escape(&collided).into_owned()),
+            ("assets/escaped.txt", escape(&escaped).into_owned()),
+        ] {
+            files.lock().unwrap().insert(
+                graph.encrypt_filename(path).unwrap(),
+                graph.encrypt_content(&stored).unwrap().into_owned(),
+            );
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let update = graph
+            .update_local_files(
+                dir.path(),
+                [
+                    "assets/large.bin",
+                    "assets/collided.txt",
+                    "assets/escaped.txt",
+                ],
+                "token",
+                &DownloadOptions::default(),
+                &graph.cancel_handle(None),
+            )
+            .await
+            .unwrap();
+        assert!(update.files.failed.is_empty(), "{:?}", update.files.failed);
+        assert_eq!(
+            std::fs::read(dir.path().join("assets/large.bin")).unwrap(),
+            large
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("assets/collided.txt")).unwrap(),
+            collided
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("assets/escaped.txt")).unwrap(),
+            escaped
+        );
+        assert_eq!(escape(b"- a"), Cow::Borrowed(b"- a"));
+    }
+}
@@ -0,0 +1,100 @@
+//! Compression of page content before encryption.
+//!
+//! Encrypted content is random to transport compression, so text pages are zstd compressed
+//! before they are encrypted. Compressed content starts with a versioned header inside the
+//! plaintext; content without it, e.g. uploaded by older versions, is stored as-is.
+//! Plaintext that happens to start with the header is always compressed, see `escape`.
+
+use std::borrow::Cow;
+
+use crate::error::{Error, Result};
+
+// header of compressed content, followed by the zstd frame
+const HEADER: &[u8] = b"logseq-zstd:1\n";
+
+const LEVEL: i32 = 3;
+
+// smaller content gains nothing from compression
+const MIN_SIZE: usize = 256;
+
+/// Compress content, as-is if compression doesn't reduce its size.
+/// Content starting with the header is always compressed.
+pub fn compress(data: &[u8]) -> Cow<'_, [u8]> {
+    let collides = data.starts_with(HEADER);
+    if data.len() < MIN_SIZE && !collides {
+        return data.into();
+    }
+    match zstd::bulk::compress(data, LEVEL) {
+        Ok(compressed) if collides || HEADER.len() + compressed.len() < data.len() => {
+            [HEADER, &compressed].concat().into()
+        }
+        Ok(_) => data.into(),
+        Err(e) => {
+            log::warn!("cannot compress content: {}", e);
+            data.into()
+        }
+    }
+}
+
+/// Content as-is, unless it starts with the header and would be taken as compressed content
+pub fn escape(data: &[u8]) -> Cow<'_, [u8]> {
+    if data.starts_with(HEADER) {
+        compress(data)
+    } else {
+        data.into()
+    }
+}
+
+/// Decompress content with the compression header, other content is returned as-is
+pub fn decompress(data: Vec<u8>) -> Result<Vec<u8>> {
+    let Some(compressed) = data.strip_prefix(HEADER) else {
+        return Ok(data);
+    };
+    zstd::stream::decode_all(compressed).map_err(|e| Error::Corrupted(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression() {
+        let page = "- block\n  collapsed:: true\n".repeat(100);
+        let compressed = compress(page.as_bytes());
+        assert!(compressed.starts_with(HEADER));
+        assert!(compressed.len() < page.len() / 10);
+        assert_eq!(
+            decompress(compressed.into_owned()).unwrap(),
+            page.as_bytes()
+        );
+
+        // legacy content and content not worth compressing
+        assert_eq!(decompress(b"- block".to_vec()).unwrap(), b"- block");
+        assert!(matches!(compress(b"- block"), Cow::Borrowed(_)));
+        assert!(matches!(
+            decompress([HEADER, b"not zstd"].concat()),
+            Err(Error::Corrupted(_))
+        ));
+        assert!(matches!(escape(b"- block"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_plaintext_starting_with_header() {
+        let small = [HEADER, b"- block"].concat();
+        let large = [HEADER, "- block\n".repeat(100).as_bytes()].concat();
+        for plain in [&small, &large, &HEADER.to_vec()] {
+            let escaped = escape(plain);
+            assert_ne!(&*escaped, &plain[..]);
+            assert_eq!(&decompress(escaped.into_owned()).unwrap(), plain);
+            assert_eq!(&decompress(compress(plain).into_owned()).unwrap(), plain);
+        }
+
+        let (secret_key, public_key) = lsq_encryption::keygen();
+        let ctx = crate::RsapiContext::new();
+        ctx.set_env("graph", "dev", &secret_key, &public_key, &[], None, None)
+            .unwrap();
+        let graph = ctx.get_graph("graph").unwrap();
+        let encrypted = graph.encrypt_content(&small).unwrap().into_owned();
+        assert_eq!(&*graph.decrypt_content(&encrypted).unwrap(), &small[..]);
+    }
+}
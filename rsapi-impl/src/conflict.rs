@@ -0,0 +1,198 @@
+//! Conflict resolution of downloaded files.
+//!
+//! A download conflicts when the local file was modified since last sync,
+//! i.e. local content differs from both the remote content and the base version.
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Local, TimeZone};
+#[cfg(feature = "napi")]
+use napi_derive::napi;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// overwrite local file, the legacy behaviour
+    #[default]
+    KeepRemote,
+    KeepLocal,
+    /// move local file to a conflict copy, then write remote
+    KeepBoth,
+    /// compare local mtime with remote last modified time
+    NewestWins,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-remote" => Ok(ConflictPolicy::KeepRemote),
+            "keep-local" => Ok(ConflictPolicy::KeepLocal),
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            "newest-wins" => Ok(ConflictPolicy::NewestWins),
+            _ => Err(Error::InvalidArg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConflictOptions {
+    pub policy: ConflictPolicy,
+    /// device name used in conflict copy names
+    pub device_name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    KeepRemote,
+    KeepLocal,
+    KeepBoth,
+    /// not written, colliding with a path of different case
+    CaseCollision,
+}
+
+impl Resolution {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Resolution::KeepRemote => "keep-remote",
+            Resolution::KeepLocal => "keep-local",
+            Resolution::KeepBoth => "keep-both",
+            Resolution::CaseCollision => "case-collision",
+        }
+    }
+}
+
+/// Conflicted file in a download batch
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConflictedFile {
+    pub path: String,
+    /// "keep-remote", "keep-local", "keep-both" or "case-collision"
+    pub resolution: String,
+    /// path of the conflict copy of local file, for "keep-both";
+    /// the colliding path, for "case-collision"
+    pub conflict_path: Option<String>,
+}
+
+/// Is local file modified since last sync, and different from remote
+pub fn is_conflict(local_md5: &str, remote_md5: &str, base_md5: Option<&str>) -> bool {
+    local_md5 != remote_md5 && base_md5 != Some(local_md5)
+}
+
+impl ConflictPolicy {
+    /// Times are in milliseconds, newest-wins falls back to keep-remote if remote time is unknown
+    pub fn resolve(&self, local_mtime: i64, remote_mtime: Option<i64>) -> Resolution {
+        match self {
+            ConflictPolicy::KeepRemote => Resolution::KeepRemote,
+            ConflictPolicy::KeepLocal => Resolution::KeepLocal,
+            ConflictPolicy::KeepBoth => Resolution::KeepBoth,
+            ConflictPolicy::NewestWins => match remote_mtime {
+                Some(remote_mtime) if local_mtime > remote_mtime => Resolution::KeepLocal,
+                _ => Resolution::KeepRemote,
+            },
+        }
+    }
+}
+
+/// `pages/foo.md` => `pages/foo (conflict from <device> <date>).md`
+pub fn conflict_copy_path<Tz: TimeZone>(file_path: &str, device: &str, date: DateTime<Tz>) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let (dir, fname) = match file_path.rfind('/') {
+        Some(pos) => file_path.split_at(pos + 1),
+        None => ("", file_path),
+    };
+    let (stem, ext) = match fname.rfind('.') {
+        Some(pos) if pos > 0 => fname.split_at(pos),
+        _ => (fname, ""),
+    };
+    let device = sanitize_device_name(device);
+    let device = if device.is_empty() {
+        "unknown device"
+    } else {
+        &device
+    };
+    format!(
+        "{}{} (conflict from {} {}){}",
+        dir,
+        stem,
+        device,
+        date.format("%Y-%m-%d %H-%M-%S"),
+        ext
+    )
+}
+
+/// Device name as part of a file name: no path separators, `..`, or characters reserved on Windows
+fn sanitize_device_name(device: &str) -> String {
+    device
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim_matches(|c: char| c == '.' || c.is_whitespace())
+        .replace("..", "_")
+}
+
+pub fn local_conflict_copy_path(file_path: &str, device: &str) -> String {
+    conflict_copy_path(file_path, device, Local::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn test_conflict_copy_path() {
+        let date = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        assert_eq!(
+            conflict_copy_path("assets/image.png", "Pixel", date),
+            "assets/image (conflict from Pixel 2024-01-02 03-04-05).png"
+        );
+        assert_eq!(
+            conflict_copy_path("logseq/config.edn", "", date),
+            "logseq/config (conflict from unknown device 2024-01-02 03-04-05).edn"
+        );
+        assert_eq!(
+            conflict_copy_path(".gitignore", "Mac", date),
+            ".gitignore (conflict from Mac 2024-01-02 03-04-05)"
+        );
+        assert_eq!(
+            conflict_copy_path("pages/a.md", "../../etc/x", date),
+            "pages/a (conflict from ___etc_x 2024-01-02 03-04-05).md"
+        );
+        assert_eq!(
+            conflict_copy_path("pages/a.md", "Bob's PC: \"work\"?", date),
+            "pages/a (conflict from Bob's PC_ _work__ 2024-01-02 03-04-05).md"
+        );
+        assert_eq!(
+            conflict_copy_path("pages/a.md", " .. ", date),
+            "pages/a (conflict from unknown device 2024-01-02 03-04-05).md"
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert!(!is_conflict("a", "a", None));
+        assert!(!is_conflict("a", "b", Some("a")));
+        assert!(is_conflict("a", "b", Some("c")));
+        assert!(is_conflict("a", "b", None));
+
+        let policy = ConflictPolicy::NewestWins;
+        assert_eq!(policy.resolve(2, Some(1)), Resolution::KeepLocal);
+        assert_eq!(policy.resolve(1, Some(2)), Resolution::KeepRemote);
+        assert_eq!(policy.resolve(1, None), Resolution::KeepRemote);
+        assert_eq!(
+            "keep-both".parse::<ConflictPolicy>().unwrap(),
+            ConflictPolicy::KeepBoth
+        );
+        assert!("unknown".parse::<ConflictPolicy>().is_err());
+    }
+}
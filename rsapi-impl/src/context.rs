@@ -0,0 +1,466 @@
+//! Context of rsapi: graphs, sync environment, callbacks and cancellation.
+//!
+//! Bindings hold one context for the process. Contexts are independent of each other,
+//! so tests may create as many as they need.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use notify::RecommendedWatcher;
+use sync::{Endpoint, SyncContext};
+
+use crate::cancel::Cancellation;
+use crate::error::{Error, Result};
+use crate::events::{EventBus, SyncEvent};
+use crate::fs_case::CaseSensitivity;
+use crate::graph::{Graph, GraphCatalog, GraphInfo};
+use crate::ignore_rules::IgnoreRules;
+use crate::large_file::{DeferredUploads, FileSizeLimits};
+use crate::remote_index::RemoteIndex;
+use crate::special_file::SymlinkPolicy;
+use crate::watcher::{self, FileChangeEvent};
+use crate::Progress;
+
+pub(crate) type ProgressCallback = Arc<dyn Fn(Progress) + Send + Sync>;
+type WatchCallback = Arc<dyn Fn(FileChangeEvent) + Send + Sync>;
+
+/// State shared by a context and its graphs
+#[derive(Default)]
+pub(crate) struct Shared {
+    pub(crate) sync: Arc<SyncContext>,
+    pub(crate) cancellation: Cancellation,
+    pub(crate) deferred_uploads: DeferredUploads,
+    pub(crate) remote_index: RemoteIndex,
+    pub(crate) events: EventBus,
+    pub(crate) case_sensitivity: CaseSensitivity,
+    unmetered: Unmetered,
+    progress_callback: RwLock<Option<ProgressCallback>>,
+    watch_callback: RwLock<Option<WatchCallback>>,
+}
+
+impl fmt::Debug for Shared {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Shared").finish_non_exhaustive()
+    }
+}
+
+/// Connection state set by the host app. Until told otherwise, mobile apps are assumed
+/// to be on a metered connection, and desktop apps on an unmetered one.
+struct Unmetered(AtomicBool);
+
+impl Default for Unmetered {
+    fn default() -> Self {
+        let mobile = cfg!(any(target_os = "android", target_os = "ios"));
+        Unmetered(AtomicBool::new(!mobile))
+    }
+}
+
+impl Shared {
+    pub(crate) fn is_unmetered(&self) -> bool {
+        self.unmetered.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_progress_callback(&self, callback: ProgressCallback) {
+        *self.progress_callback.write().unwrap() = Some(callback);
+    }
+
+    pub(crate) fn notify_progress(&self, progress: Progress) {
+        let callback = self.progress_callback.read().unwrap().clone();
+        if let Some(callback) = callback {
+            callback(progress);
+        }
+    }
+
+    pub(crate) fn notify_file_change(&self, event: FileChangeEvent) {
+        let callback = self.watch_callback.read().unwrap().clone();
+        if let Some(callback) = callback {
+            callback(event);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RsapiContext {
+    shared: Arc<Shared>,
+    graphs: RwLock<GraphCatalog>,
+    // running watchers, by graph uuid. Dropping a watcher stops its event loop.
+    watchers: Mutex<HashMap<String, RecommendedWatcher>>,
+}
+
+impl RsapiContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set sync environment along with encryption keys of a graph.
+    /// Other graphs keep their own environments. Settings of a graph already set, e.g.
+    /// its symlink policy, are kept, and its running operations are not cancelled.
+    /// base_path, token: defaults of operations on the graph
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_env(
+        &self,
+        graph_uuid: &str,
+        env: &str,
+        secret_key: &str,
+        public_key: &str,
+        ignore_rules: &[String],
+        base_path: Option<&Path>,
+        token: Option<&str>,
+    ) -> Result<()> {
+        log::info!("set sync env {:?} for {}", env, graph_uuid);
+        let endpoint = Endpoint::from_env(env).ok_or(Error::InvalidArg)?;
+        let base_path = base_path.map(dunce::canonicalize).transpose()?;
+
+        let g = Graph {
+            uuid: graph_uuid.into(),
+            endpoint,
+            base_path,
+            token: token.map(str::to_string),
+            age_public_key: public_key.into(),
+            age_secret_key: secret_key.into(),
+            fname_encryption_key: lsq_encryption::to_raw_x25519_key(secret_key)?,
+            ignore_rules: ignore_rules.to_vec(),
+            symlink_policy: Default::default(),
+            size_limits: Default::default(),
+            backup_enabled: true,
+            chunk_threshold: None,
+            compression_enabled: false,
+            shared: self.shared.clone(),
+        };
+        // validate rules
+        IgnoreRules::new(g.ignore_rules.iter().map(|r| &**r))?;
+
+        let mut graphs = self.graphs.write().unwrap();
+        if let Some(old_token) = graphs.set_graph_env(g) {
+            if !graphs.uses_token(&old_token) {
+                self.shared.sync.forget_token(&old_token);
+            }
+        }
+        Ok(())
+    }
+
+    // Set proxy for all sync requests, convert error to impl::Error
+    pub fn set_proxy(&self, proxy: Option<&str>) -> Result<()> {
+        if proxy.is_some() {
+            log::info!("setting proxy: {:?}", proxy);
+        }
+        self.shared.sync.set_proxy(proxy)?;
+        Ok(())
+    }
+
+    pub fn get_graph(&self, graph_uuid: &str) -> Result<Arc<Graph>> {
+        self.graphs.read().unwrap().get_graph(graph_uuid)
+    }
+
+    /// Remove a graph, cancelling its requests and stopping its watcher
+    pub fn remove_graph(&self, graph_uuid: &str) -> Result<()> {
+        self.graphs
+            .write()
+            .unwrap()
+            .remove_graph(graph_uuid)
+            .ok_or(Error::GraphNotSet)?;
+        log::info!("remove graph {}", graph_uuid);
+        self.shared.cancellation.cancel_graph(graph_uuid);
+        self.shared.deferred_uploads.clear(graph_uuid);
+        self.shared.remote_index.clear(graph_uuid);
+        self.unwatch(graph_uuid)
+    }
+
+    pub fn list_graphs(&self) -> Vec<GraphInfo> {
+        self.graphs.read().unwrap().list_graphs()
+    }
+
+    /// Update the default auth token of a graph, e.g. after refreshing it
+    pub fn update_token(&self, graph_uuid: &str, token: &str) -> Result<()> {
+        let mut graphs = self.graphs.write().unwrap();
+        let old_token = graphs.update_token(graph_uuid, token)?;
+        // temp credentials of the old token are no longer needed, unless other graphs use it
+        if let Some(old_token) = old_token {
+            if !graphs.uses_token(&old_token) {
+                self.shared.sync.forget_token(&old_token);
+            }
+        }
+        Ok(())
+    }
+
+    /// Set how symlinks of a graph are scanned and written, see `SymlinkPolicy`
+    pub fn set_symlink_policy(&self, graph_uuid: &str, policy: SymlinkPolicy) -> Result<()> {
+        log::info!("set symlink policy {:?} for {}", policy, graph_uuid);
+        self.graphs
+            .write()
+            .unwrap()
+            .set_symlink_policy(graph_uuid, policy)
+    }
+
+    pub fn set_file_size_limits(&self, graph_uuid: &str, limits: FileSizeLimits) -> Result<()> {
+        log::info!("set file size limits {:?} for {}", limits, graph_uuid);
+        self.graphs
+            .write()
+            .unwrap()
+            .set_file_size_limits(graph_uuid, limits)
+    }
+
+    /// Whether local files overwritten or deleted by sync of a graph are backed up,
+    /// enabled by default
+    pub fn set_backup_enabled(&self, graph_uuid: &str, enabled: bool) -> Result<()> {
+        log::info!("set backup enabled {} for {}", enabled, graph_uuid);
+        self.graphs
+            .write()
+            .unwrap()
+            .set_backup_enabled(graph_uuid, enabled)
+    }
+
+    /// Store files of a graph of at least `min_size` bytes in chunks, so that edits of large
+    /// files only upload changed chunks. Disabled by default, or when `min_size` is none,
+    /// as versions without chunked storage write the manifests of chunked files over local
+    /// files.
+    pub fn set_chunked_storage(&self, graph_uuid: &str, min_size: Option<u64>) -> Result<()> {
+        log::info!("set chunked storage {:?} for {}", min_size, graph_uuid);
+        self.graphs
+            .write()
+            .unwrap()
+            .set_chunk_threshold(graph_uuid, min_size)
+    }
+
+    /// Compress page files of a graph before encryption. Disabled by default, as versions
+    /// without compression can't read compressed pages.
+    pub fn set_compression_enabled(&self, graph_uuid: &str, enabled: bool) -> Result<()> {
+        log::info!("set compression enabled {} for {}", enabled, graph_uuid);
+        self.graphs
+            .write()
+            .unwrap()
+            .set_compression_enabled(graph_uuid, enabled)
+    }
+
+    /// Set by the host app on network changes. Deferred large files are uploaded by syncs
+    /// on unmetered connections. Connections are metered by default on mobile only.
+    pub fn set_metered_connection(&self, metered: bool) {
+        log::info!("metered connection: {}", metered);
+        self.shared.unmetered.0.store(!metered, Ordering::Relaxed);
+    }
+
+    /// Detect whether the filesystem of base path is case sensitive, cached per base path
+    pub async fn is_case_sensitive<P: AsRef<Path>>(&self, base_path: P) -> Result<bool> {
+        self.shared.case_sensitivity.detect(base_path).await
+    }
+
+    /// Large files of a graph deferred to an unmetered connection
+    pub fn deferred_uploads(&self, graph_uuid: &str) -> Vec<String> {
+        self.shared.deferred_uploads.list(graph_uuid)
+    }
+
+    pub fn set_progress_callback<F>(&self, cb: F)
+    where
+        F: Fn(Progress) + Send + Sync + 'static,
+    {
+        self.shared.set_progress_callback(Arc::new(cb));
+    }
+
+    /// Callback of file changes, called from watcher threads
+    pub fn set_watch_callback<F>(&self, cb: F)
+    where
+        F: Fn(FileChangeEvent) + Send + Sync + 'static,
+    {
+        *self.shared.watch_callback.write().unwrap() = Some(Arc::new(cb));
+    }
+
+    /// Subscribe to sync events of all graphs, return id of the subscription
+    pub fn subscribe_events<F>(&self, cb: F) -> u32
+    where
+        F: Fn(SyncEvent) + Send + Sync + 'static,
+    {
+        self.shared.events.subscribe(Arc::new(cb))
+    }
+
+    /// Return false if the subscription is not found
+    pub fn unsubscribe_events(&self, id: u32) -> bool {
+        self.shared.events.unsubscribe(id)
+    }
+
+    pub fn cancel_all_requests(&self) -> Result<()> {
+        self.shared.cancellation.cancel_all();
+        log::debug!("cancelling all request");
+        Ok(())
+    }
+
+    /// Cancel in-flight requests of a graph
+    pub fn cancel_graph_requests(&self, graph_uuid: &str) -> Result<()> {
+        self.shared.cancellation.cancel_graph(graph_uuid);
+        log::debug!("cancelling requests of {}", graph_uuid);
+        Ok(())
+    }
+
+    /// Cancel an operation started with the id, return false if it is not running
+    pub fn cancel_operation(&self, operation_id: &str) -> bool {
+        log::debug!("cancelling operation {}", operation_id);
+        self.shared.cancellation.cancel_operation(operation_id)
+    }
+
+    /// Start watching base path of a graph, replacing the running watcher of the graph
+    pub fn watch<P: AsRef<Path>>(
+        &self,
+        graph_uuid: &str,
+        base_path: Option<P>,
+        debounce: Duration,
+    ) -> Result<()> {
+        let graph = self.get_graph(graph_uuid)?;
+        let base_path = graph.base_path_or(base_path)?;
+        let watcher = watcher::watch(graph, base_path, debounce)?;
+        self.watchers
+            .lock()
+            .unwrap()
+            .insert(graph_uuid.to_string(), watcher);
+        Ok(())
+    }
+
+    /// Stop watching a graph, pending changes are still reported
+    pub fn unwatch(&self, graph_uuid: &str) -> Result<()> {
+        let watcher = self.watchers.lock().unwrap().remove(graph_uuid);
+        if watcher.is_some() {
+            log::info!("stop watching graph {}", graph_uuid);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_independent_contexts() {
+        let (secret_key, public_key) = lsq_encryption::keygen();
+        let (other_secret_key, other_public_key) = lsq_encryption::keygen();
+
+        let ctx = RsapiContext::new();
+        let other = RsapiContext::new();
+        ctx.set_env("graph", "dev", &secret_key, &public_key, &[], None, None)
+            .unwrap();
+        other
+            .set_env(
+                "graph",
+                "prod",
+                &other_secret_key,
+                &other_public_key,
+                &[],
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(matches!(
+            RsapiContext::new().get_graph("graph"),
+            Err(Error::GraphNotSet)
+        ));
+
+        let graph = ctx.get_graph("graph").unwrap();
+        let other_graph = other.get_graph("graph").unwrap();
+        assert_ne!(graph.key_id(), other_graph.key_id());
+
+        let handle = graph.cancel_handle(None);
+        let other_handle = other_graph.cancel_handle(None);
+        ctx.cancel_all_requests().unwrap();
+        assert!(handle.is_cancelled());
+        assert!(!other_handle.is_cancelled());
+    }
+
+    #[test]
+    fn test_set_env_keeps_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let (secret_key, public_key) = lsq_encryption::keygen();
+
+        let ctx = RsapiContext::new();
+        ctx.set_env(
+            "graph",
+            "dev",
+            &secret_key,
+            &public_key,
+            &[],
+            None,
+            Some("t1"),
+        )
+        .unwrap();
+        ctx.set_symlink_policy("graph", SymlinkPolicy::Follow)
+            .unwrap();
+        ctx.set_backup_enabled("graph", false).unwrap();
+        let handle = ctx.get_graph("graph").unwrap().cancel_handle(None);
+
+        ctx.set_env(
+            "graph",
+            "prod",
+            &secret_key,
+            &public_key,
+            &[],
+            Some(dir.path()),
+            Some("t2"),
+        )
+        .unwrap();
+        let graph = ctx.get_graph("graph").unwrap();
+        assert_eq!(graph.endpoint, Endpoint::PROD);
+        assert_eq!(graph.token_or(None).unwrap(), "t2");
+        assert!(graph.base_path_or(None::<&Path>).is_ok());
+        assert_eq!(graph.symlink_policy, SymlinkPolicy::Follow);
+        assert!(!graph.backup_enabled);
+        assert!(!handle.is_cancelled());
+    }
+
+    #[test]
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn test_metered_connection() {
+        let ctx = RsapiContext::new();
+        // desktop hosts may never report the connection
+        assert!(ctx.shared.is_unmetered());
+        ctx.set_metered_connection(true);
+        assert!(!ctx.shared.is_unmetered());
+        assert!(RsapiContext::new().shared.is_unmetered());
+    }
+
+    #[test]
+    fn test_graphs_of_different_envs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (secret_key, public_key) = lsq_encryption::keygen();
+
+        let ctx = RsapiContext::new();
+        ctx.set_env("a", "dev", &secret_key, &public_key, &[], None, Some("t1"))
+            .unwrap();
+        ctx.set_env(
+            "b",
+            "prod",
+            &secret_key,
+            &public_key,
+            &[],
+            Some(dir.path()),
+            Some("t1"),
+        )
+        .unwrap();
+
+        let a = ctx.get_graph("a").unwrap();
+        let b = ctx.get_graph("b").unwrap();
+        assert_eq!(a.endpoint, Endpoint::DEV);
+        assert_eq!(b.endpoint, Endpoint::PROD);
+        assert!(matches!(
+            a.base_path_or(None::<&Path>),
+            Err(Error::BasePathNotSet)
+        ));
+        assert_eq!(
+            b.base_path_or(None::<&Path>).unwrap(),
+            dunce::canonicalize(dir.path()).unwrap()
+        );
+
+        ctx.update_token("a", "t2").unwrap();
+        assert_eq!(ctx.get_graph("a").unwrap().token_or(None).unwrap(), "t2");
+        // running operations keep the old graph
+        assert_eq!(a.token_or(None).unwrap(), "t1");
+        assert_eq!(b.token_or(Some("t3")).unwrap(), "t3");
+
+        ctx.remove_graph("a").unwrap();
+        let graphs = ctx.list_graphs();
+        assert_eq!(graphs.len(), 1);
+        assert_eq!(graphs[0].graph_uuid, "b");
+        assert_eq!(graphs[0].env, "prod");
+        assert!(matches!(ctx.remove_graph("a"), Err(Error::GraphNotSet)));
+    }
+}
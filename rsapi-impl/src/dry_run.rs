@@ -0,0 +1,335 @@
+//! Dry-run of sync operations.
+//!
+//! Sync operations take a `DryRun` flag. A dry run goes through path checks, ignore rules,
+//! filename encryption, remote url resolution and conflict detection as usual, and returns
+//! the usual result, but records the intended changes instead of writing local files or
+//! the server.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "napi")]
+use napi_derive::napi;
+use serde::Serialize;
+
+/// A change a sync operation would make
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PlannedAction {
+    /// "write-local", "delete-local", "upload" or "delete-remote"
+    pub r#type: &'static str,
+    pub path: String,
+    pub encrypted_path: Option<String>,
+    /// local file to be written, deleted or uploaded
+    pub local_path: Option<String>,
+    /// remote url to download from
+    pub remote_url: Option<String>,
+    /// local file size, for uploads and deletes
+    pub size: Option<i64>,
+}
+
+impl PlannedAction {
+    fn new(r#type: &'static str, path: &str) -> Self {
+        Self {
+            r#type,
+            path: path.to_string(),
+            encrypted_path: None,
+            local_path: None,
+            remote_url: None,
+            size: None,
+        }
+    }
+
+    pub fn write_local(path: &str, local_path: &Path, remote_url: &str) -> Self {
+        Self {
+            local_path: Some(local_path.display().to_string()),
+            remote_url: Some(remote_url.to_string()),
+            ..Self::new("write-local", path)
+        }
+    }
+
+    pub fn delete_local(path: &str, local_path: &Path, size: u64) -> Self {
+        Self {
+            local_path: Some(local_path.display().to_string()),
+            size: Some(size as _),
+            ..Self::new("delete-local", path)
+        }
+    }
+
+    pub fn upload(path: &str, encrypted_path: &str, local_path: &Path, size: u64) -> Self {
+        Self {
+            encrypted_path: Some(encrypted_path.to_string()),
+            local_path: Some(local_path.display().to_string()),
+            size: Some(size as _),
+            ..Self::new("upload", path)
+        }
+    }
+
+    pub fn delete_remote(path: &str, encrypted_path: &str) -> Self {
+        Self {
+            encrypted_path: Some(encrypted_path.to_string()),
+            ..Self::new("delete-remote", path)
+        }
+    }
+}
+
+/// Dry-run flag of sync operations, collecting planned actions when enabled.
+/// The default value is disabled.
+#[derive(Clone, Default)]
+pub struct DryRun(Option<Arc<Mutex<Vec<PlannedAction>>>>);
+
+impl DryRun {
+    pub fn new(enabled: bool) -> Self {
+        if enabled {
+            Self::enabled()
+        } else {
+            Self::default()
+        }
+    }
+
+    pub fn enabled() -> Self {
+        Self(Some(Default::default()))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub(crate) fn record(&self, action: PlannedAction) {
+        if let Some(actions) = &self.0 {
+            log::debug!("dry run: {} {:?}", action.r#type, action.path);
+            actions.lock().unwrap().push(action);
+        }
+    }
+
+    /// Planned actions, ordered by type and path
+    pub fn take_actions(&self) -> Vec<PlannedAction> {
+        let mut actions = match &self.0 {
+            Some(actions) => std::mem::take(&mut *actions.lock().unwrap()),
+            None => vec![],
+        };
+        actions.sort_by(|a, b| (a.r#type, &a.path).cmp(&(b.r#type, &b.path)));
+        actions
+    }
+
+    /// Planned actions of a dry run, none if disabled
+    pub fn planned(&self) -> Option<Vec<PlannedAction>> {
+        self.is_enabled().then(|| self.take_actions())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use sync::mock::{json_body, MockRequest, MockServer};
+
+    use super::*;
+    use crate::conflict::{ConflictOptions, ConflictPolicy};
+    use crate::graph::{DownloadOptions, Graph, UploadOptions};
+    use crate::special_file::SkippedFile;
+
+    fn test_graph() -> Graph {
+        let (secret_key, public_key) = lsq_encryption::keygen();
+        let ctx = crate::RsapiContext::new();
+        ctx.set_env("graph", "dev", &secret_key, &public_key, &[], None, None)
+            .unwrap();
+        let graph = ctx.get_graph("graph").unwrap();
+        (*graph).clone()
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_delete_local_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("pages")).unwrap();
+        std::fs::write(dir.path().join("pages/a.md"), "- a").unwrap();
+
+        let graph = test_graph();
+        let dry_run = DryRun::enabled();
+        let deleted = graph
+            .delete_local_files(
+                dir.path(),
+                ["pages/a.md"],
+                &dry_run,
+                &graph.cancel_handle(None),
+            )
+            .await
+            .unwrap();
+        let actions = dry_run.planned().unwrap();
+
+        assert_eq!(deleted.succeeded, vec!["pages/a.md".to_string()]);
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].r#type, "delete-local");
+        assert_eq!(actions[0].size, Some(3));
+        assert!(dir.path().join("pages/a.md").exists());
+        assert!(DryRun::default().planned().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_update_local_files() {
+        let mut graph = test_graph();
+        let remote = |p: &str| graph.encrypt_filename(p).unwrap();
+        let files = json!({
+            remote("pages/a.md"): "content/a",
+            remote("pages/b.md"): "content/b",
+        });
+        let content = graph.encrypt_content(b"- remote").unwrap().into_owned();
+        let server = MockServer::start(move |req| match &*req.path {
+            "get_files" => {
+                let urls = files
+                    .as_object()
+                    .unwrap()
+                    .iter()
+                    .map(|(k, path)| (k.clone(), json!(req.url(path.as_str().unwrap()))));
+                json_body(json!({ "PresignedFileUrls": serde_json::Map::from_iter(urls) }))
+            }
+            "content/a" | "content/b" => content.clone(),
+            _ => json_body(json!({"message": "not found"})),
+        })
+        .await;
+        graph.endpoint = server.endpoint();
+
+        // a.md modified locally since last sync, b.md is new
+        let dir = tempfile::tempdir().unwrap();
+        let base_dir = dir.path().join("logseq/version-files/base/pages");
+        std::fs::create_dir_all(dir.path().join("pages")).unwrap();
+        std::fs::create_dir_all(&base_dir).unwrap();
+        std::fs::write(base_dir.join("a.md"), "- a").unwrap();
+        std::fs::write(dir.path().join("pages/a.md"), "- local").unwrap();
+
+        let plan = |policy: ConflictPolicy| {
+            let graph = graph.clone();
+            let base_path = dir.path().to_path_buf();
+            async move {
+                let options = DownloadOptions {
+                    conflict: ConflictOptions {
+                        policy,
+                        device_name: "phone".into(),
+                    },
+                    dry_run: DryRun::enabled(),
+                    ..Default::default()
+                };
+                let update = graph
+                    .update_local_files(
+                        base_path,
+                        ["pages/a.md", "pages/b.md"],
+                        "token",
+                        &options,
+                        &graph.cancel_handle(None),
+                    )
+                    .await
+                    .unwrap();
+                let written = options
+                    .dry_run
+                    .planned()
+                    .unwrap()
+                    .into_iter()
+                    .map(|a| a.path)
+                    .collect::<Vec<_>>();
+                (update, written)
+            }
+        };
+
+        let (update, written) = plan(ConflictPolicy::KeepLocal).await;
+        assert_eq!(written, vec!["pages/b.md"]);
+        assert_eq!(update.conflicts.len(), 1);
+        assert_eq!(update.conflicts[0].path, "pages/a.md");
+        assert_eq!(update.conflicts[0].resolution, "keep-local");
+
+        let (update, written) = plan(ConflictPolicy::KeepBoth).await;
+        assert_eq!(written, vec!["pages/a.md", "pages/b.md"]);
+        assert_eq!(update.conflicts[0].resolution, "keep-both");
+        assert!(update.conflicts[0].conflict_path.is_some());
+
+        // nothing written, new files are not downloaded
+        let names = std::fs::read_dir(dir.path().join("pages"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["a.md"]);
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("pages/a.md")).unwrap(),
+            "- local"
+        );
+        assert!(server.requests_of("content/b").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_update_remote_files() {
+        let mut graph = test_graph();
+        let server = MockServer::start(|req: &MockRequest| match &*req.path {
+            "get_temp_credential" => json_body(json!({
+                "Credentials": {
+                    "AccessKeyId": "key",
+                    "Expiration": "2099-01-01T00:00:00Z",
+                    "SecretKey": "secret",
+                    "SessionToken": "token",
+                },
+                "S3Prefix": "mock-bucket/prefix",
+            })),
+            _ => json_body(json!({"message": "not found"})),
+        })
+        .await;
+        graph.endpoint = server.endpoint();
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("pages")).unwrap();
+        std::fs::write(dir.path().join("pages/a.md"), "- a").unwrap();
+
+        let options = UploadOptions {
+            force: true,
+            dry_run: DryRun::enabled(),
+            ..Default::default()
+        };
+        let update = graph
+            .update_remote_files(
+                dir.path(),
+                ["pages/a.md"],
+                3,
+                "token",
+                &options,
+                &graph.cancel_handle(None),
+            )
+            .await
+            .unwrap();
+        assert_eq!(update.txid, 3);
+        let actions = options.dry_run.planned().unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].r#type, "upload");
+        assert_eq!(actions[0].size, Some(3));
+
+        let dry_run = DryRun::enabled();
+        let update = graph
+            .delete_remote_files(
+                dir.path(),
+                ["pages/a.md", ".git/config", "../a.md"],
+                3,
+                "token",
+                &dry_run,
+                &graph.cancel_handle(None),
+            )
+            .await
+            .unwrap();
+        assert_eq!(update.txid, 3);
+        assert_eq!(update.files.succeeded, vec!["pages/a.md"]);
+        assert_eq!(update.files.failed[0].path, "../a.md");
+        assert_eq!(
+            update.skipped,
+            vec![SkippedFile::new(".git/config", "ignored")]
+        );
+        let actions = dry_run.planned().unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].r#type, "delete-remote");
+
+        // no changes sent to the server
+        let paths = server
+            .requests()
+            .into_iter()
+            .map(|r| r.path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["get_temp_credential"]);
+        assert!(!dir
+            .path()
+            .join("logseq/version-files/base/pages/a.md")
+            .exists());
+    }
+}
@@ -8,12 +8,49 @@ pub enum Error {
     SyncClient(#[from] sync::Error),
     #[error("graph env not set, uuid not found")]
     GraphNotSet,
+    #[error("base path not set for graph")]
+    BasePathNotSet,
+    #[error("token not set for graph")]
+    TokenNotSet,
     #[error("io: {0}")]
     Io(#[from] std::io::Error),
+    #[error("watch: {0}")]
+    Watch(#[from] notify::Error),
+    #[error("unsafe file path: {0:?}")]
+    UnsafePath(String),
     #[error("invalid arguments")]
     InvalidArg,
+    #[error("skipped {0}")]
+    SkippedFile(&'static str),
     #[error("cancelled")]
     Cancelled,
+    #[error("corrupted content: {0}")]
+    Corrupted(String),
+    #[error("case collision with {0:?}")]
+    CaseCollision(String),
+}
+
+impl Error {
+    /// Short kind of the error, reported with failed files of a batch
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Encryption(_) => "encryption",
+            Error::SyncClient(_) => "sync",
+            Error::GraphNotSet | Error::BasePathNotSet | Error::TokenNotSet => "not-set",
+            Error::Io(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => "not-found",
+                std::io::ErrorKind::PermissionDenied => "permission-denied",
+                _ => "io",
+            },
+            Error::Watch(_) => "watch",
+            Error::UnsafePath(_) => "unsafe-path",
+            Error::InvalidArg => "invalid-arg",
+            Error::SkippedFile(_) => "skipped",
+            Error::Cancelled => "cancelled",
+            Error::Corrupted(_) => "corrupted",
+            Error::CaseCollision(_) => "case-collision",
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,200 @@
+//! Typed sync events, for hosts following the outcome of operations.
+//!
+//! Events are published to every subscriber of the context's event bus. Subscribers are
+//! called from the thread emitting the event, and should hand events off quickly.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "napi")]
+use napi_derive::napi;
+use serde::Serialize;
+
+use crate::batch::{BatchResult, FileError};
+use crate::error::{Error, Result};
+
+/// Sync event of a graph
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SyncEvent {
+    #[serde(rename = "graphUUID")]
+    pub graph_uuid: String,
+    /// "operation-started", "operation-finished", "file-uploaded", "file-downloaded",
+    /// "file-skipped", "conflict", "credential-refreshed", "retry-scheduled" or "error"
+    pub r#type: &'static str,
+    /// operation name, e.g. "update-remote-files"
+    pub operation: Option<String>,
+    pub operation_id: Option<String>,
+    pub path: Option<String>,
+    /// skip reason, conflict resolution, error message, or outcome of a finished operation
+    pub detail: Option<String>,
+    /// milliseconds since epoch
+    pub timestamp: i64,
+}
+
+impl SyncEvent {
+    pub fn new(graph_uuid: &str, r#type: &'static str) -> Self {
+        SyncEvent {
+            graph_uuid: graph_uuid.to_string(),
+            r#type,
+            operation: None,
+            operation_id: None,
+            path: None,
+            detail: None,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn operation(mut self, operation: &str, operation_id: Option<&str>) -> Self {
+        self.operation = Some(operation.to_string());
+        self.operation_id = operation_id.map(str::to_string);
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn detail<S: ToString>(mut self, detail: S) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+}
+
+type Subscriber = Arc<dyn Fn(SyncEvent) + Send + Sync>;
+
+/// Subscribers of sync events
+#[derive(Default)]
+pub(crate) struct EventBus {
+    next_id: AtomicU32,
+    subscribers: RwLock<Vec<(u32, Subscriber)>>,
+}
+
+impl EventBus {
+    /// Return id of the subscription, to unsubscribe
+    pub(crate) fn subscribe(&self, subscriber: Subscriber) -> u32 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.subscribers.write().unwrap().push((id, subscriber));
+        id
+    }
+
+    pub(crate) fn unsubscribe(&self, id: u32) -> bool {
+        let mut subscribers = self.subscribers.write().unwrap();
+        let len = subscribers.len();
+        subscribers.retain(|(i, _)| *i != id);
+        subscribers.len() != len
+    }
+
+    pub(crate) fn emit(&self, event: SyncEvent) {
+        // not holding the lock, subscribers may unsubscribe
+        let subscribers = self
+            .subscribers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(_, s)| s.clone())
+            .collect::<Vec<_>>();
+        for subscriber in subscribers {
+            subscriber(event.clone());
+        }
+    }
+}
+
+/// Result of an operation with failed files, reported as error events
+pub(crate) trait Outcome {
+    fn failed(&self) -> &[FileError];
+}
+
+impl<T> Outcome for BatchResult<T> {
+    fn failed(&self) -> &[FileError] {
+        &self.failed
+    }
+}
+
+/// Outcome of a finished operation
+pub(crate) fn outcome_of<T: Outcome>(ret: &Result<T>) -> String {
+    match ret {
+        Ok(outcome) if outcome.failed().is_empty() => "succeeded".to_string(),
+        Ok(outcome) => format!("{} failed", outcome.failed().len()),
+        Err(Error::Cancelled) => "cancelled".to_string(),
+        Err(_) => "failed".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_event_bus() {
+        let bus = EventBus::default();
+        let events = Arc::new(Mutex::new(vec![]));
+        let id = {
+            let events = events.clone();
+            bus.subscribe(Arc::new(move |e: SyncEvent| events.lock().unwrap().push(e)))
+        };
+        bus.emit(SyncEvent::new("graph", "file-uploaded").path("pages/a.md"));
+        assert!(bus.unsubscribe(id));
+        assert!(!bus.unsubscribe(id));
+        bus.emit(SyncEvent::new("graph", "file-uploaded").path("pages/b.md"));
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].path.as_deref(), Some("pages/a.md"));
+
+        let json = serde_json::to_value(&events[0]).unwrap();
+        assert_eq!(json["graphUUID"], "graph");
+        assert_eq!(json["type"], "file-uploaded");
+    }
+
+    #[tokio::test]
+    async fn test_retry_scheduled() {
+        use serde_json::json;
+        use sync::mock::{json_body, MockServer};
+
+        use crate::graph::UploadOptions;
+
+        let (secret_key, public_key) = lsq_encryption::keygen();
+        let ctx = crate::RsapiContext::new();
+        ctx.set_env("graph", "dev", &secret_key, &public_key, &[], None, None)
+            .unwrap();
+        let mut graph = (*ctx.get_graph("graph").unwrap()).clone();
+        let server = MockServer::start(|_| json_body(json!({"message": "unavailable"}))).await;
+        graph.endpoint = server.endpoint();
+        let events = Arc::new(Mutex::new(vec![]));
+        {
+            let events = events.clone();
+            ctx.subscribe_events(move |e| events.lock().unwrap().push(e.r#type));
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let ret = graph
+            .update_remote_files(
+                dir.path(),
+                ["pages/a.md"],
+                3,
+                "token",
+                &UploadOptions::default(),
+                &graph.cancel_handle(None),
+            )
+            .await;
+        assert!(ret.is_err());
+        assert_eq!(server.requests_of("get_temp_credential").len(), 3);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "operation-started",
+                "retry-scheduled",
+                "retry-scheduled",
+                "error",
+                "operation-finished"
+            ]
+        );
+    }
+}
@@ -0,0 +1,201 @@
+//! Case sensitivity of local filesystems.
+//!
+//! On case-insensitive volumes(default macOS and Windows), `pages/Foo.md` and `pages/foo.md`
+//! are the same file: downloading both clobbers one with the other, and a case-only rename
+//! is a no-op for some APIs.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[cfg(feature = "napi")]
+use napi_derive::napi;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::error::Result;
+
+/// Case sensitivity of filesystems, by base path
+#[derive(Debug, Default)]
+pub(crate) struct CaseSensitivity(Mutex<HashMap<PathBuf, bool>>);
+
+/// A file path colliding with another path of different case
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CaseCollision {
+    pub path: String,
+    /// the path kept, in the same batch or an existing local file
+    pub conflict_with: String,
+}
+
+pub fn fold_case(path: &str) -> String {
+    path.to_lowercase()
+}
+
+impl CaseSensitivity {
+    /// Record case sensitivity of the filesystem of base path, as reported by the host,
+    /// so later checks skip the probe
+    pub(crate) fn set<P: AsRef<Path>>(&self, base_path: P, sensitive: bool) -> Result<()> {
+        let base_path = dunce::canonicalize(base_path.as_ref())?;
+        self.0.lock().unwrap().insert(base_path, sensitive);
+        Ok(())
+    }
+
+    /// Detect case sensitivity of the filesystem of base path, by probing a temp file,
+    /// unless reported by `set`
+    pub(crate) async fn detect<P: AsRef<Path>>(&self, base_path: P) -> Result<bool> {
+        let base_path = dunce::canonicalize(base_path.as_ref())?;
+        let cached = self.0.lock().unwrap().get(&base_path).copied();
+        if let Some(sensitive) = cached {
+            return Ok(sensitive);
+        }
+
+        let probe_name = format!(".rsapi-case-probe-{}", std::process::id());
+        let probe = base_path.join(&probe_name);
+        fs::write(&probe, b"").await?;
+        let sensitive = fs::metadata(base_path.join(probe_name.to_uppercase()))
+            .await
+            .is_err();
+        let _ = fs::remove_file(&probe).await;
+
+        log::debug!("case sensitive of {:?}: {}", base_path, sensitive);
+        self.0.lock().unwrap().insert(base_path, sensitive);
+        Ok(sensitive)
+    }
+}
+
+/// Paths colliding with an earlier path of the batch, or with an existing local file.
+/// `local_variant` returns the existing local path of the same folded case, if any.
+pub fn find_collisions<F>(paths: &[String], local_variant: F) -> Vec<CaseCollision>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    let mut collisions = vec![];
+    for path in paths {
+        let folded = fold_case(path);
+        if let Some(kept) = seen.get(&folded) {
+            if *kept != path {
+                collisions.push(CaseCollision {
+                    path: path.clone(),
+                    conflict_with: kept.to_string(),
+                });
+            }
+            continue;
+        }
+        seen.insert(folded, path);
+        if let Some(local) = local_variant(path) {
+            if local != *path && !paths.contains(&local) {
+                collisions.push(CaseCollision {
+                    path: path.clone(),
+                    conflict_with: local,
+                });
+            }
+        }
+    }
+    collisions
+}
+
+/// Existing local file of the same name but different case, in the same folder
+pub fn local_case_variant(base_path: &Path, path: &str) -> Option<String> {
+    let (dir, fname) = match path.rfind('/') {
+        Some(pos) => (&path[..pos + 1], &path[pos + 1..]),
+        None => ("", path),
+    };
+    let folded = fold_case(fname);
+    std::fs::read_dir(base_path.join(dir))
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().into_string().ok())
+        .find(|name| name != fname && fold_case(name) == folded)
+        .map(|name| format!("{}{}", dir, name))
+}
+
+/// Rename a file, case-only renames go through a temp name
+pub async fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    let is_case_only = from != to
+        && from.parent() == to.parent()
+        && match (from.file_name(), to.file_name()) {
+            (Some(a), Some(b)) => {
+                fold_case(&a.to_string_lossy()) == fold_case(&b.to_string_lossy())
+            }
+            _ => false,
+        };
+    if is_case_only {
+        let temp = to.with_file_name(format!(
+            ".{}.case-rename",
+            to.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        fs::rename(from, &temp).await?;
+        fs::rename(&temp, to).await?;
+    } else {
+        fs::rename(from, to).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_collisions() {
+        let paths = vec![
+            "pages/Foo.md".to_string(),
+            "pages/foo.md".to_string(),
+            "pages/bar.md".to_string(),
+            "pages/baz.md".to_string(),
+        ];
+        let local = |path: &str| match path {
+            "pages/bar.md" => Some("pages/Bar.md".to_string()),
+            "pages/baz.md" => Some("pages/baz.md".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            find_collisions(&paths, local),
+            vec![
+                CaseCollision {
+                    path: "pages/foo.md".into(),
+                    conflict_with: "pages/Foo.md".into()
+                },
+                CaseCollision {
+                    path: "pages/bar.md".into(),
+                    conflict_with: "pages/Bar.md".into()
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reported_case_sensitivity() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CaseSensitivity::default();
+        cache.set(dir.path(), false).unwrap();
+        assert!(!cache.detect(dir.path()).await.unwrap());
+        cache.set(dir.path(), true).unwrap();
+        assert!(cache.detect(dir.path()).await.unwrap());
+        // no probe file left behind
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_case_only_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("foo.md"), "- foo").unwrap();
+        rename(dir.path().join("foo.md"), dir.path().join("Foo.md"))
+            .await
+            .unwrap();
+
+        let names = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["Foo.md"]);
+        assert_eq!(
+            local_case_variant(dir.path(), "foo.md"),
+            Some("Foo.md".to_string())
+        );
+        assert!(CaseSensitivity::default().detect(dir.path()).await.is_ok());
+    }
+}
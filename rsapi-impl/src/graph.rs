@@ -1,43 +1,51 @@
 use std::{
     borrow::Cow,
     collections::HashMap,
-    path::Path,
+    path::{Path, PathBuf},
     sync::Arc,
-    time::{Duration, SystemTime},
 };
 
 #[cfg(feature = "napi")]
 use napi_derive::napi;
-use once_cell::sync::Lazy;
 use tokio::fs;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::watch;
+use tokio::io::AsyncReadExt;
 
 use futures::prelude::*;
 use lsq_encryption::md5_hexdigest;
-use sync::SyncClient;
+use serde::Serialize;
+use sync::{Endpoint, SyncClient};
 use unicode_normalization::UnicodeNormalization;
 
+use crate::atomic_file;
+use crate::backup;
+use crate::batch::{BatchResult, FileError};
+use crate::cancel::CancelHandle;
+use crate::chunked;
+use crate::compression;
+use crate::conflict::{self, ConflictOptions, ConflictPolicy, ConflictedFile, Resolution};
+use crate::context::Shared;
+use crate::dry_run::{DryRun, PlannedAction};
 use crate::error::{Error, Result};
-use crate::{Progress, PROGRESS_CALLBACK};
-
-pub static mut CANCELLACTION_TX: Option<watch::Sender<()>> = None;
-pub static mut CANCELLACTION_RX: Option<watch::Receiver<()>> = None;
-
-pub static mut GRAPHS: Lazy<GraphCatalog> = Lazy::new(|| {
-    let (cancel_tx, cancel_rx) = watch::channel(());
-
-    unsafe {
-        CANCELLACTION_TX = Some(cancel_tx);
-        CANCELLACTION_RX = Some(cancel_rx);
-    }
-    GraphCatalog::default()
-});
+use crate::events::{self, Outcome, SyncEvent};
+use crate::fs_case::{self, CaseCollision, CaseSensitivity};
+use crate::ignore_rules::IgnoreRules;
+use crate::index::{self, ctime_millis, inode, mtime_millis, FileIndex, IndexEntry};
+use crate::large_file::{FileSizeLimits, SizeCheck};
+use crate::merge::{self, MergeReport, PageFormat};
+use crate::planner::{self, RemoteChange, RemoteFile, SyncPlan};
+use crate::progress::BatchTracker;
+use crate::remote_index::{self, RemoteEntries, RemoteEntry};
+use crate::safe_path;
+use crate::special_file::{self, SkippedFile, SymlinkPolicy};
+use crate::unicode_path::{self, UnicodeCollision};
 
 // Public API implementation
 
+// retries of a failed remote update
+const UPLOAD_RETRIES: usize = 2;
+
 #[derive(Default)]
-pub struct GraphCatalog(HashMap<String, Graph>);
+pub struct GraphCatalog(HashMap<String, Arc<Graph>>);
 
 impl GraphCatalog {
     pub fn new() -> Self {
@@ -45,71 +53,236 @@ impl GraphCatalog {
     }
 
     pub fn add_graph(&mut self, graph: Graph) {
-        self.0.insert(graph.uuid.clone(), graph);
+        self.0.insert(graph.uuid.clone(), Arc::new(graph));
+    }
+
+    /// Add a graph, or replace environment, keys, ignore rules and defaults of the graph
+    /// of the same uuid, keeping its other settings. Return the old token of the graph.
+    /// Running operations keep using the old graph.
+    pub fn set_graph_env(&mut self, graph: Graph) -> Option<String> {
+        let Some(existing) = self.0.get_mut(&graph.uuid) else {
+            self.add_graph(graph);
+            return None;
+        };
+        let existing = Arc::make_mut(existing);
+        existing.endpoint = graph.endpoint;
+        existing.base_path = graph.base_path;
+        existing.age_public_key = graph.age_public_key;
+        existing.age_secret_key = graph.age_secret_key;
+        existing.fname_encryption_key = graph.fname_encryption_key;
+        existing.ignore_rules = graph.ignore_rules;
+        std::mem::replace(&mut existing.token, graph.token)
+    }
+
+    pub fn get_graph(&self, graph_uuid: &str) -> Result<Arc<Graph>> {
+        self.0.get(graph_uuid).cloned().ok_or(Error::GraphNotSet)
+    }
+
+    pub fn remove_graph(&mut self, graph_uuid: &str) -> Option<Arc<Graph>> {
+        self.0.remove(graph_uuid)
+    }
+
+    /// Graphs ordered by uuid
+    pub fn list_graphs(&self) -> Vec<GraphInfo> {
+        let mut graphs: Vec<_> = self.0.values().map(|g| g.info()).collect();
+        graphs.sort_by(|a, b| a.graph_uuid.cmp(&b.graph_uuid));
+        graphs
+    }
+
+    /// Replace the token of a graph, return the old one.
+    /// Running operations keep using the old token.
+    pub fn update_token(&mut self, graph_uuid: &str, token: &str) -> Result<Option<String>> {
+        let graph = self.0.get_mut(graph_uuid).ok_or(Error::GraphNotSet)?;
+        Ok(Arc::make_mut(graph).token.replace(token.to_string()))
+    }
+
+    pub fn set_symlink_policy(&mut self, graph_uuid: &str, policy: SymlinkPolicy) -> Result<()> {
+        let graph = self.0.get_mut(graph_uuid).ok_or(Error::GraphNotSet)?;
+        Arc::make_mut(graph).symlink_policy = policy;
+        Ok(())
+    }
+
+    pub fn set_file_size_limits(&mut self, graph_uuid: &str, limits: FileSizeLimits) -> Result<()> {
+        let graph = self.0.get_mut(graph_uuid).ok_or(Error::GraphNotSet)?;
+        Arc::make_mut(graph).size_limits = limits;
+        Ok(())
+    }
+
+    pub fn set_backup_enabled(&mut self, graph_uuid: &str, enabled: bool) -> Result<()> {
+        let graph = self.0.get_mut(graph_uuid).ok_or(Error::GraphNotSet)?;
+        Arc::make_mut(graph).backup_enabled = enabled;
+        Ok(())
+    }
+
+    pub fn set_chunk_threshold(&mut self, graph_uuid: &str, threshold: Option<u64>) -> Result<()> {
+        let graph = self.0.get_mut(graph_uuid).ok_or(Error::GraphNotSet)?;
+        Arc::make_mut(graph).chunk_threshold = threshold;
+        Ok(())
     }
 
-    pub fn get_graph(&self, graph_uuid: &str) -> Result<&Graph> {
-        self.0.get(graph_uuid).ok_or(Error::GraphNotSet)
+    pub fn set_compression_enabled(&mut self, graph_uuid: &str, enabled: bool) -> Result<()> {
+        let graph = self.0.get_mut(graph_uuid).ok_or(Error::GraphNotSet)?;
+        Arc::make_mut(graph).compression_enabled = enabled;
+        Ok(())
+    }
+
+    pub fn uses_token(&self, token: &str) -> bool {
+        self.0.values().any(|g| g.token.as_deref() == Some(token))
     }
 }
 
-#[derive(Debug)]
+/// A graph set by `set_env`
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct GraphInfo {
+    pub graph_uuid: String,
+    /// "dev" or "prod"
+    pub env: String,
+    pub base_path: Option<String>,
+    pub has_token: bool,
+}
+
+#[derive(Debug, Clone)]
 pub struct Graph {
     pub uuid: String,
-    // TODO: base_bash should be bonded to the graph
-    // pub base_path: PathBuf,
+    pub endpoint: Endpoint,
+    /// default base path of operations
+    pub base_path: Option<PathBuf>,
+    /// default auth token of operations
+    pub token: Option<String>,
     pub age_public_key: String,
     pub age_secret_key: String,
     pub fname_encryption_key: [u8; 32],
+    /// extra gitignore-style rules, applied after `logseq/.syncignore`
+    pub ignore_rules: Vec<String>,
+    pub symlink_policy: SymlinkPolicy,
+    pub size_limits: FileSizeLimits,
+    /// back up local files overwritten or deleted by sync to `logseq/bak`
+    pub backup_enabled: bool,
+    /// files of at least this size are stored in chunks, see `chunked`
+    pub chunk_threshold: Option<u64>,
+    /// compress page files before encryption, see `compression`
+    pub compression_enabled: bool,
+    pub(crate) shared: Arc<Shared>,
 }
 
-pub fn cancel_all_requests() -> Result<()> {
-    unsafe { CANCELLACTION_TX.as_ref() }.map(|tx| tx.send(()));
-    log::debug!("cancelling all request");
-    Ok(())
-}
+impl Graph {
+    /// Cancellation handle of a new operation of the graph
+    pub fn cancel_handle(&self, operation_id: Option<String>) -> CancelHandle {
+        self.shared
+            .cancellation
+            .new_handle(&self.uuid, operation_id)
+    }
 
-// Set proxy for all sync requests, convert error to impl::Error
-pub fn set_proxy(proxy: Option<&str>) -> Result<()> {
-    if proxy.is_some() {
-        log::info!("setting proxy: {:?}", proxy);
+    /// Publish an event of the graph to event subscribers
+    pub fn emit_event(&self, event: SyncEvent) {
+        self.shared.events.emit(event);
     }
-    sync::set_proxy(proxy)?;
-    Ok(())
-}
 
-pub fn set_env(graph_uuid: &str, env: &str, secret_key: &str, public_key: &str) -> Result<()> {
-    log::info!("set sync env {:?} for {}", env, graph_uuid);
-    // clean temp credential cache
-    sync::reset_user();
-    // also cancel any pending requests
-    unsafe { CANCELLACTION_TX.as_ref() }.map(|tx| tx.send(()));
+    fn event(&self, r#type: &'static str, operation: &str, cancel: &CancelHandle) -> SyncEvent {
+        SyncEvent::new(&self.uuid, r#type).operation(operation, Some(cancel.id()))
+    }
 
-    match env {
-        "production" | "product" | "prod" => {
-            sync::set_prod();
+    /// Run an operation, emitting its started and finished events,
+    /// and error events of the operation or its failed files
+    async fn run_operation<T: Outcome>(
+        &self,
+        operation: &str,
+        cancel: &CancelHandle,
+        fut: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        self.emit_event(self.event("operation-started", operation, cancel));
+        let ret = fut.await;
+        match &ret {
+            Ok(outcome) => {
+                for f in outcome.failed() {
+                    self.emit_event(
+                        self.event("error", operation, cancel)
+                            .path(&f.path)
+                            .detail(&f.message),
+                    );
+                }
+            }
+            Err(Error::Cancelled) => {}
+            Err(e) => self.emit_event(self.event("error", operation, cancel).detail(e)),
         }
-        "development" | "develop" | "dev" => {
-            sync::set_dev();
+        self.emit_event(
+            self.event("operation-finished", operation, cancel)
+                .detail(events::outcome_of(&ret)),
+        );
+        ret
+    }
+
+    pub fn info(&self) -> GraphInfo {
+        GraphInfo {
+            graph_uuid: self.uuid.clone(),
+            env: self.endpoint.name().to_string(),
+            base_path: self.base_path.as_ref().map(|p| p.display().to_string()),
+            has_token: self.token.is_some(),
         }
-        _ => return Err(Error::InvalidArg),
     }
 
-    let g = Graph {
-        uuid: graph_uuid.into(),
-        age_public_key: public_key.into(),
-        age_secret_key: secret_key.into(),
-        fname_encryption_key: lsq_encryption::to_raw_x25519_key(secret_key)?,
-    };
+    /// The given base path, or the one set for the graph
+    pub fn base_path_or<P: AsRef<Path>>(&self, base_path: Option<P>) -> Result<PathBuf> {
+        match base_path {
+            Some(base_path) => Ok(base_path.as_ref().to_path_buf()),
+            None => self.base_path.clone().ok_or(Error::BasePathNotSet),
+        }
+    }
 
-    unsafe {
-        GRAPHS.add_graph(g);
+    /// The given auth token, or the one set for the graph
+    pub fn token_or(&self, token: Option<&str>) -> Result<String> {
+        token
+            .map(str::to_string)
+            .or_else(|| self.token.clone())
+            .ok_or(Error::TokenNotSet)
     }
 
-    Ok(())
-}
+    fn sync_client(&self, token: &str) -> SyncClient {
+        SyncClient::new(&self.shared.sync, self.endpoint, token)
+    }
+
+    /// Download and decrypt a remote file, chunked files are reassembled from their chunks
+    async fn download_content<F>(
+        &self,
+        client: &SyncClient,
+        url: &str,
+        progress_callback: F,
+    ) -> Result<Vec<u8>>
+    where
+        F: Fn(usize, usize) + Send + Sync + 'static,
+    {
+        let buf = client.download_file(url, progress_callback).await?;
+        let decrypted = self.decrypt_content(&buf)?;
+        match chunked::Manifest::parse(&decrypted) {
+            Some(manifest) => chunked::download(client, self, &manifest?).await,
+            None => Ok(chunked::unescape(decrypted.into_owned())),
+        }
+    }
+
+    /// `encrypted_file_path => file_path`, paths failed to encrypt are failed
+    fn encrypt_batch_paths<T>(
+        &self,
+        file_paths: Vec<String>,
+        batch: &mut BatchResult<T>,
+    ) -> HashMap<String, String> {
+        let mut encrypted_paths = HashMap::new();
+        for p in file_paths {
+            match self.encrypt_filename(&p) {
+                Ok(ep) => {
+                    encrypted_paths.insert(ep, p);
+                }
+                Err(e) => batch.fail(&p, &e),
+            }
+        }
+        encrypted_paths
+    }
+
+    /// Fingerprint of the encryption key, used to invalidate persisted states
+    pub fn key_id(&self) -> String {
+        md5_hexdigest(self.age_public_key.as_bytes())
+    }
 
-impl Graph {
     pub fn encrypt_filename(&self, fname: &str) -> Result<String> {
         Ok(lsq_encryption::encrypt_filename(
             &fname,
@@ -125,6 +298,14 @@ impl Graph {
     }
 
     pub fn encrypt_content<'a, 'b>(&'a self, data: &'b [u8]) -> Result<Cow<'b, [u8]>> {
+        match compression::escape(data) {
+            Cow::Borrowed(data) => self.encrypt_encoded(data),
+            Cow::Owned(escaped) => Ok(self.encrypt_encoded(&escaped)?.into_owned().into()),
+        }
+    }
+
+    /// Encrypt content compressed or escaped by `compression`
+    fn encrypt_encoded<'b>(&self, data: &'b [u8]) -> Result<Cow<'b, [u8]>> {
         if data.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----")
             || data.starts_with(b"age-encryption.org/v1\n")
         {
@@ -140,7 +321,7 @@ impl Graph {
             || data.starts_with(b"age-encryption.org/v1\n")
         {
             if let Ok(decrypted) = lsq_encryption::decrypt_with_x25519(&self.age_secret_key, data) {
-                Ok(decrypted.to_vec().into())
+                Ok(compression::decompress(decrypted.into_vec())?.into())
             } else {
                 Ok(data.into())
             }
@@ -149,65 +330,223 @@ impl Graph {
         }
     }
 
+    /// Metadata of files, `incoming_fname` of each is the path on disk
     pub async fn get_files_meta<P0: AsRef<Path>, P1: AsRef<str>, PS>(
         &self,
         base_path: P0,
         file_paths: PS,
-    ) -> Result<HashMap<String, FileMeta>>
+    ) -> Result<BatchResult<FileMeta>>
     where
         PS: IntoIterator<Item = P1>,
     {
         let base_path = dunce::canonicalize(base_path.as_ref())?;
 
         let futs = file_paths.into_iter().map(|p| {
-            let path: String = p.as_ref().to_string();
+            let path = p.as_ref().to_string();
 
             let meta = {
-                let p = path.clone();
+                let p = unicode_path::on_disk_path(&base_path, p.as_ref());
                 self.get_file_meta(&base_path, p)
             };
             meta.map(move |meta| (path, meta))
         });
 
-        Ok(future::join_all(futs)
-            .await
-            .into_iter()
-            .filter(|(_, m)| m.is_ok())
-            .map(|(p, m)| (p, m.unwrap()))
-            .collect())
+        Ok(future::join_all(futs).await.into_iter().collect())
     }
 
+    /// Get metadata of all files in the graph.
+    ///
+    /// Unchanged files(same size, mtime and inode) are served from the persistent file index,
+    /// set `force_rescan` to rehash every file.
+    /// Files whose paths differ only in Unicode normalization form are synced once.
+    /// Symlinks are scanned by the symlink policy of the graph, special files are skipped.
     pub async fn get_all_files_meta<P: AsRef<Path>>(
         &self,
         base_path: P,
-    ) -> Result<impl Iterator<Item = FileMeta>> {
+        force_rescan: bool,
+    ) -> Result<BatchResult<FileMeta>> {
+        let scan = self.scan_files_meta(base_path, force_rescan).await?;
+        for c in &scan.collisions {
+            log::warn!(
+                "skip {:?}, same normalized name as {:?}",
+                c.path,
+                c.conflict_with
+            );
+        }
+        for f in &scan.skipped {
+            log::warn!("skip {:?}: {}", f.path, f.reason);
+            self.emit_event(
+                SyncEvent::new(&self.uuid, "file-skipped")
+                    .path(&f.path)
+                    .detail(&f.reason),
+            );
+        }
+        Ok(scan.files)
+    }
+
+    /// Local files whose paths differ only in Unicode normalization form
+    pub async fn find_unicode_collisions<P: AsRef<Path>>(
+        &self,
+        base_path: P,
+    ) -> Result<Vec<UnicodeCollision>> {
+        Ok(self.scan_files_meta(base_path, false).await?.collisions)
+    }
+
+    /// Local symlinks and special files not synced
+    pub async fn find_skipped_files<P: AsRef<Path>>(
+        &self,
+        base_path: P,
+    ) -> Result<Vec<SkippedFile>> {
+        Ok(self.scan_files_meta(base_path, false).await?.skipped)
+    }
+
+    async fn scan_files_meta<P: AsRef<Path>>(
+        &self,
+        base_path: P,
+        force_rescan: bool,
+    ) -> Result<Scan> {
         let base_path = dunce::canonicalize(base_path.as_ref())?;
-        let base_path_ref = base_path.clone();
-
-        let futs = {
-            walkdir::WalkDir::new(&base_path)
-                .into_iter()
-                .filter_map(|e| e.ok())
-                .filter_map(move |e| {
-                    e.path()
-                        .strip_prefix(&base_path)
-                        .ok()
-                        .and_then(|p| p.to_str())
-                        .map(|p| p.replace("\\", "/").trim_start_matches('/').to_string())
-                })
-                .filter(|p| {
-                    !(p.starts_with('.')
-                        || p.contains("/.")
-                        || p.starts_with("logseq/bak/")
-                        || p.starts_with("logseq/version-files/"))
-                })
-                .map(|p| self.get_file_meta(&base_path_ref, p))
+
+        let index = if force_rescan {
+            FileIndex::new(&self.key_id())
+        } else {
+            let _guard = index::lock_index(&base_path).await;
+            FileIndex::load(&base_path, &self.key_id()).await
         };
-        Ok(future::join_all(futs)
-            .await
+
+        let ignore_rules = self.load_ignore_rules(&base_path).await?;
+        let policy = self.symlink_policy;
+        // hidden, so ignored, collected on the way
+        let mut stale_temp_files = vec![];
+        let walker = walkdir::WalkDir::new(&base_path)
+            .follow_links(policy == SymlinkPolicy::Follow)
             .into_iter()
-            .filter(Result::is_ok)
-            .map(Result::unwrap))
+            .filter_entry(|e| {
+                if e.file_type().is_file() && atomic_file::is_stale_temp_file(e.path()) {
+                    stale_temp_files.push(e.path().to_path_buf());
+                    return false;
+                }
+                match relative_path(&base_path, e.path()) {
+                    Some(p) => !ignore_rules.is_ignored(&p, e.file_type().is_dir()),
+                    None => true,
+                }
+            });
+        let mut batch = BatchResult::new();
+        let mut paths = vec![];
+        let mut skipped = vec![];
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    let path = e.path().and_then(|p| relative_path(&base_path, p));
+                    match path {
+                        Some(p) if e.loop_ancestor().is_some() => {
+                            skipped.push(SkippedFile::new(&p, "symlink-loop"))
+                        }
+                        Some(p) if !p.is_empty() => batch.fail(&p, &std::io::Error::from(e).into()),
+                        _ => return Err(std::io::Error::from(e).into()),
+                    }
+                    continue;
+                }
+            };
+            let Some(p) = relative_path(&base_path, entry.path()).filter(|p| !p.is_empty()) else {
+                continue;
+            };
+            // file type of link targets when following links
+            let file_type = entry.file_type();
+            if file_type.is_dir() {
+                continue;
+            }
+            if let Some(kind) = special_file::special_kind(&file_type) {
+                skipped.push(SkippedFile::new(&p, kind));
+            } else if entry.path_is_symlink() && policy == SymlinkPolicy::Skip {
+                skipped.push(SkippedFile::new(&p, "symlink"));
+            } else {
+                paths.push(p);
+            }
+        }
+        if !stale_temp_files.is_empty() {
+            let n = atomic_file::remove_temp_files(stale_temp_files).await;
+            log::info!("removed {} stale temp files", n);
+        }
+
+        let futs = paths.into_iter().map(|p| {
+            let index = &index;
+            let base_path = &base_path;
+            let path = p.clone();
+            let meta = async move {
+                let metadata = special_file::metadata(&base_path.join(&p), policy).await?;
+                if metadata.is_dir() {
+                    return Ok(None);
+                }
+                let (size, mtime, inode) = (
+                    metadata.len() as i64,
+                    mtime_millis(&metadata),
+                    inode(&metadata),
+                );
+                if let Some(entry) = index.lookup(&p, size, mtime, inode) {
+                    return Ok(Some((
+                        FileMeta {
+                            size,
+                            mtime,
+                            ctime: ctime_millis(&metadata),
+                            md5: entry.md5.clone(),
+                            normalized_fname: p.nfc().collect(),
+                            encrypted_fname: entry.encrypted_fname.clone(),
+                            incoming_fname: p.clone(),
+                            fname: p,
+                        },
+                        inode,
+                    )));
+                }
+                let meta = self.get_file_meta(base_path, &p).await?;
+                Ok::<_, Error>(Some((meta, inode)))
+            };
+            meta.map(move |meta| (path, meta))
+        });
+        let mut files_meta = vec![];
+        for (path, meta) in future::join_all(futs).await {
+            match meta {
+                Ok(Some(meta)) => files_meta.push(meta),
+                Ok(None) => {}
+                Err(e) => batch.fail(&path, &e),
+            }
+        }
+
+        let collisions =
+            unicode_path::find_collisions(files_meta.iter().map(|(meta, _)| &*meta.fname));
+        files_meta.retain(|(meta, _)| !collisions.iter().any(|c| c.path == meta.fname));
+
+        let nfiles = files_meta.len();
+        let mut hashed = 0;
+        // the index may be changed by other operations while scanning
+        self.update_file_index(&base_path, |index| {
+            for (meta, inode) in &files_meta {
+                if force_rescan
+                    || index
+                        .lookup(&meta.fname, meta.size, meta.mtime, *inode)
+                        .is_none()
+                {
+                    hashed += 1;
+                    index.insert(&meta.fname, index_entry_of(meta, *inode));
+                }
+            }
+            index.retain_paths(
+                &files_meta
+                    .iter()
+                    .map(|(m, _)| m.normalized_fname.clone())
+                    .collect(),
+            );
+        })
+        .await;
+        log::debug!("scanned {} files, {} rehashed", nfiles, hashed);
+
+        batch.succeeded = files_meta.into_iter().map(|(meta, _)| meta).collect();
+        Ok(Scan {
+            files: batch,
+            collisions,
+            skipped,
+        })
     }
 
     pub async fn rename_local_file<P: AsRef<Path>, S0: AsRef<str>, S1: AsRef<str>>(
@@ -217,91 +556,205 @@ impl Graph {
         to: S1,
     ) -> Result<()> {
         let base_path = base_path.as_ref();
-        fs::rename(base_path.join(from.as_ref()), base_path.join(to.as_ref())).await?;
+        fs_case::rename(
+            local_path(base_path, &safe_path::normalize(from.as_ref())?),
+            safe_path::join(base_path, to.as_ref())?,
+        )
+        .await?;
         Ok(())
     }
 
-    // delete local file
+    /// Delete local files, return deleted files
     pub async fn delete_local_files<P: AsRef<Path>, S: AsRef<str>>(
         &self,
         base_path: P,
         file_paths: impl IntoIterator<Item = S>,
-    ) -> Result<()> {
+        dry_run: &DryRun,
+        cancel: &CancelHandle,
+    ) -> Result<BatchResult<String>> {
         let base_path = base_path.as_ref();
-        let futs = file_paths
-            .into_iter()
-            .map(async move |p| fs::remove_file(base_path.join(p.as_ref())).await);
-        future::join_all(futs)
-            .await
-            .into_iter()
-            .collect::<std::result::Result<Vec<_>, _>>()
-            .map_err(Error::from)
-            .map(|_| ())
+        let mut batch = BatchResult::new();
+        let file_paths = normalize_batch(file_paths, &mut batch);
+        if dry_run.is_enabled() {
+            for p in file_paths {
+                let path = local_path(base_path, &p);
+                match fs::metadata(&path).await {
+                    Ok(metadata) => {
+                        dry_run.record(PlannedAction::delete_local(&p, &path, metadata.len()));
+                        batch.succeeded.push(p);
+                    }
+                    Err(e) => batch.fail(&p, &e.into()),
+                }
+            }
+            return Ok(batch);
+        }
+        let futs = file_paths.into_iter().map(async move |p| {
+            let path = local_path(base_path, &p);
+            let ret = async {
+                if self.backup_enabled
+                    && backup::move_to_backup(base_path, &p, &path)
+                        .await?
+                        .is_some()
+                {
+                    return Ok(());
+                }
+                fs::remove_file(&path).await?;
+                Ok::<_, Error>(())
+            };
+            (p.clone(), ret.await.map(|_| p))
+        });
+        let deleted = tokio::select! {
+            deleted = future::join_all(futs) => deleted,
+            _ = cancel.cancelled() => {
+                log::warn!("deleting local files cancelled");
+                return Err(Error::Cancelled);
+            }
+        };
+        for (p, ret) in deleted {
+            batch.push(&p, ret);
+        }
+        self.update_file_index(base_path, |index| {
+            for p in &batch.succeeded {
+                index.remove(p);
+            }
+        })
+        .await;
+        Ok(batch)
     }
 
-    // Delete remote file, and local base version, return txid
+    /// Delete remote files, and local base versions.
+    /// Unsafe paths are failed, ignored paths are skipped.
     pub async fn delete_remote_files<P: AsRef<Path>, S: AsRef<str>>(
         &self,
         base_path: P,
         file_paths: impl IntoIterator<Item = S>,
         txid: i64,
         token: &str,
-    ) -> Result<i64> {
+        dry_run: &DryRun,
+        cancel: &CancelHandle,
+    ) -> Result<RemoteUpdate> {
         let base_path = base_path.as_ref();
-        let mut client = SyncClient::new(&token);
+        let mut client = self.sync_client(token);
         client.set_graph(&self.uuid, txid);
 
-        let file_paths = file_paths
+        let ignore_rules = self.load_ignore_rules(base_path).await?;
+        let mut batch = BatchResult::new();
+        let (ignored, file_paths): (Vec<_>, Vec<_>) = normalize_batch(file_paths, &mut batch)
             .into_iter()
-            .map(|s| s.as_ref().to_string())
+            .partition(|p| ignore_rules.is_ignored(p, false));
+        let skipped = ignored
+            .iter()
+            .map(|p| SkippedFile::new(p, "ignored"))
             .collect::<Vec<_>>();
 
         let encrypted_file_paths = file_paths
             .iter()
             .map(|p| self.encrypt_filename(&p))
             .collect::<Result<Vec<_>>>()?;
+        if dry_run.is_enabled() {
+            for (p, ep) in file_paths.iter().zip(&encrypted_file_paths) {
+                dry_run.record(PlannedAction::delete_remote(p, ep));
+            }
+            batch.succeeded = file_paths;
+            return Ok(RemoteUpdate {
+                txid,
+                files: batch,
+                skipped,
+            });
+        }
 
-        let ret = client.delete_files(encrypted_file_paths).await?;
+        let ret = tokio::select! {
+            ret = client.delete_files(encrypted_file_paths) => ret?,
+            _ = cancel.cancelled() => {
+                log::warn!("deleting remote files cancelled");
+                return Err(Error::Cancelled);
+            }
+        };
 
         for file_rpath in &file_paths {
             let _ =
                 fs::remove_file(base_path.join("logseq/version-files/base").join(file_rpath)).await;
         }
+        self.update_file_index(base_path, |index| {
+            for file_rpath in &file_paths {
+                index.remove(file_rpath);
+            }
+        })
+        .await;
 
-        Ok(ret.txid)
+        batch.succeeded = file_paths;
+        Ok(RemoteUpdate {
+            txid: ret.txid,
+            files: batch,
+            skipped,
+        })
     }
 
     /// Logseq Sync v2: Fetch remote files to local version DB.
     /// To replace `update_local_files`.
     ///
-    /// Return list of local downloaded files, ready to be merged.
+    /// Return downloaded page files, ready to be merged, and failed files.
+    /// Set `preserve_mtime` to keep modified times of remote files.
     pub async fn fetch_remote_files<P: AsRef<Path>, S: AsRef<str>>(
         &self,
         base_path: P,
         file_paths: impl IntoIterator<Item = S>,
         token: &str,
-    ) -> Result<Vec<String>> {
-        let mut cancel_notification = unsafe { CANCELLACTION_RX.as_ref().unwrap().clone() };
-        let _ = cancel_notification.borrow_and_update();
+        preserve_mtime: bool,
+        dry_run: &DryRun,
+        cancel: &CancelHandle,
+    ) -> Result<BatchResult<String>> {
+        let fetch = self.fetch_remote_files_inner(
+            base_path,
+            file_paths,
+            token,
+            preserve_mtime,
+            dry_run,
+            cancel,
+        );
+        self.run_operation("fetch-remote-files", cancel, fetch)
+            .await
+    }
 
+    async fn fetch_remote_files_inner<P: AsRef<Path>, S: AsRef<str>>(
+        &self,
+        base_path: P,
+        file_paths: impl IntoIterator<Item = S>,
+        token: &str,
+        preserve_mtime: bool,
+        dry_run: &DryRun,
+        cancel: &CancelHandle,
+    ) -> Result<BatchResult<String>> {
         let base_path = base_path.as_ref();
-        let mut client = SyncClient::new(&token);
+        let mut client = self.sync_client(token);
         client.set_graph(&self.uuid, 0);
         let client = Arc::new(client);
 
-        // Vec<(encrypted_file_path, file_path)>
-        let encrypted_paths = file_paths
-            .into_iter()
-            .map(|p| {
-                self.encrypt_filename(p.as_ref())
-                    .map(|ep| (ep, p.as_ref().to_string()))
-            })
-            .collect::<Result<HashMap<_, _>>>()?;
+        let ignore_rules = self.load_ignore_rules(base_path).await?;
+        let mut batch = BatchResult::new();
+        let file_paths = normalize_batch(file_paths, &mut batch);
+        let (file_paths, collisions) = skip_case_collisions(
+            &self.shared.case_sensitivity,
+            base_path,
+            filter_ignored(&ignore_rules, file_paths),
+        )
+        .await?;
+        for c in collisions {
+            batch.fail(&c.path, &Error::CaseCollision(c.conflict_with));
+        }
+        let encrypted_paths = self.encrypt_batch_paths(file_paths, &mut batch);
 
         // encrypted_file_path => remote_url
         let remote_files = client.get_files(encrypted_paths.keys()).await?;
         log::debug!("get {} remote files", remote_files.len());
 
+        let remote_entries = if preserve_mtime {
+            self.remote_entries(&client).await?
+        } else {
+            RemoteEntries::default()
+        };
+
+        let tracker = BatchTracker::new(self.shared.clone(), &self.uuid, cancel.id(), "download");
         let mut tasks = vec![];
         for (encrypted_file_path, remote_url) in remote_files {
             let file_path = match encrypted_paths.get(&encrypted_file_path) {
@@ -310,67 +763,108 @@ impl Graph {
             };
 
             let client = client.clone();
-            let graph_uuid = self.uuid.clone();
+            let tracker = tracker.clone();
 
             let target_file_path = if is_page_file(&file_path) {
                 base_path
                     .join("logseq/version-files/incoming")
                     .join(&file_path)
             } else {
-                base_path.join(&file_path)
+                local_path(base_path, &file_path)
             };
 
             // avoid use of moved value
             let file_path1 = file_path.clone();
+            let remote_mtime = remote_entries.get(&encrypted_file_path).map(|f| f.mtime);
+            let progress_callback = tracker.file_callback(&file_path);
 
-            let progress_callback = move |bytes, total| {
-                static mut LAST: usize = 0;
-                let progress = bytes * 100 / total;
-                unsafe {
-                    // reduce callback calling frequency
-                    if LAST / 10 != progress / 10 || bytes == total {
-                        LAST = progress;
-                        if let Some(callback) = &PROGRESS_CALLBACK {
-                            let progress =
-                                Progress::download(&graph_uuid, &file_path, bytes as _, total as _);
-                            callback(progress);
+            tasks.push(async move {
+                let path = file_path1.clone();
+                let ret = async move {
+                    if dry_run.is_enabled() {
+                        dry_run.record(PlannedAction::write_local(
+                            &file_path1,
+                            &target_file_path,
+                            &remote_url,
+                        ));
+                    } else {
+                        let decrypted = self
+                            .download_content(&client, &remote_url, progress_callback)
+                            .await?;
+
+                        if special_file::link_target(&decrypted).is_some() {
+                            // links are written in place, not merged
+                            let link_path = local_path(base_path, &file_path1);
+                            self.backup_before_write(
+                                base_path,
+                                &file_path1,
+                                &link_path,
+                                &decrypted,
+                            )
+                            .await?;
+                            special_file::write_file(
+                                base_path,
+                                &link_path,
+                                &decrypted,
+                                None,
+                                self.symlink_policy,
+                            )
+                            .await?;
+                            return Ok(None);
                         }
-                        log::debug!(
-                            "download progress: {}% {}/{} {:?}",
-                            progress,
-                            bytes,
-                            total,
-                            file_path
-                        );
+                        if !is_page_file(&file_path1) {
+                            self.backup_before_write(
+                                base_path,
+                                &file_path1,
+                                &target_file_path,
+                                &decrypted,
+                            )
+                            .await?;
+                        }
+                        special_file::write_file(
+                            base_path,
+                            &target_file_path,
+                            &decrypted,
+                            remote_mtime,
+                            self.symlink_policy,
+                        )
+                        .await?;
+                        log::debug!("write to file: {:?}", target_file_path);
                     }
-                }
-            };
-
-            tasks.push(async move {
-                let buf = client.download_file(&remote_url, progress_callback).await?;
-                let decrypted = self.decrypt_content(&buf)?;
 
-                if let Some(dir) = target_file_path.parent() {
-                    fs::create_dir_all(dir).await?;
+                    if is_page_file_path(&file_path1) {
+                        Ok::<_, Error>(Some(file_path1.clone()))
+                    } else {
+                        Ok::<_, Error>(None)
+                    }
                 }
-                let mut file = fs::File::create(&target_file_path).await?;
-                file.write_all(&decrypted).await?;
-                file.flush().await?;
-                log::debug!("write to file: {:?}", target_file_path);
-
-                if is_page_file_path(&file_path1) {
-                    Ok::<_, Error>(Some(file_path1.clone()))
-                } else {
-                    Ok::<_, Error>(None)
+                .await;
+                tracker.file_done();
+                if ret.is_ok() && !dry_run.is_enabled() {
+                    self.emit_event(
+                        self.event("file-downloaded", "fetch-remote-files", cancel)
+                            .path(&path),
+                    );
                 }
+                (path, ret)
             });
         }
+        if !dry_run.is_enabled() {
+            tracker.start(tasks.len());
+        }
 
         tokio::select! {
             ret = future::join_all(tasks) => {
-                ret.into_iter().filter_map(|f| f.transpose()).collect()
+                for (path, ret) in ret {
+                    match ret {
+                        Ok(Some(page_file)) => batch.succeeded.push(page_file),
+                        Ok(None) => {}
+                        Err(e) => batch.fail(&path, &e),
+                    }
+                }
+                Ok(batch)
             }
-            _ = cancel_notification.changed() => {
+            _ = cancel.cancelled() => {
                 log::warn!("downloading remote cancelled");
                 Err(Error::Cancelled)
             }
@@ -378,33 +872,69 @@ impl Graph {
     }
 
     /// Download files from remote, and update local files.
+    ///
+    /// Local files modified since last sync are resolved by the conflict policy,
+    /// return updated files and conflicted files.
+    /// Set `preserve_mtime` to keep modified times of remote files.
+    /// A dry run still downloads files existing locally, to report conflicts.
     pub async fn update_local_files<P: AsRef<Path>, S: AsRef<str>>(
         &self,
         base_path: P,
         file_paths: impl IntoIterator<Item = S>,
         token: &str,
-    ) -> Result<()> {
-        let mut cancel_notification = unsafe { CANCELLACTION_RX.as_ref().unwrap().clone() };
-        let _ = cancel_notification.borrow_and_update();
+        options: &DownloadOptions,
+        cancel: &CancelHandle,
+    ) -> Result<LocalUpdate> {
+        let update = self.update_local_files_inner(base_path, file_paths, token, options, cancel);
+        self.run_operation("update-local-files", cancel, update)
+            .await
+    }
 
-        let mut client = SyncClient::new(&token);
+    async fn update_local_files_inner<P: AsRef<Path>, S: AsRef<str>>(
+        &self,
+        base_path: P,
+        file_paths: impl IntoIterator<Item = S>,
+        token: &str,
+        options: &DownloadOptions,
+        cancel: &CancelHandle,
+    ) -> Result<LocalUpdate> {
+        let conflict = &options.conflict;
+        let preserve_mtime = options.preserve_mtime;
+        let dry_run = &options.dry_run;
+        let mut client = self.sync_client(token);
         client.set_graph(&self.uuid, 0);
         let client = Arc::new(client);
 
         let base_path = base_path.as_ref();
-        // Vec<(encrypted_file_path, file_path)>
-        let encrypted_paths = file_paths
-            .into_iter()
-            .map(|p| {
-                self.encrypt_filename(p.as_ref())
-                    .map(|ep| (ep, p.as_ref().to_string()))
-            })
-            .collect::<Result<HashMap<_, _>>>()?;
+        let ignore_rules = self.load_ignore_rules(base_path).await?;
+        let mut batch = BatchResult::new();
+        let file_paths = normalize_batch(file_paths, &mut batch);
+        let (file_paths, collisions) = skip_case_collisions(
+            &self.shared.case_sensitivity,
+            base_path,
+            filter_ignored(&ignore_rules, file_paths),
+        )
+        .await?;
+        let encrypted_paths = self.encrypt_batch_paths(file_paths, &mut batch);
 
         // encrypted_file_path => remote_url
         let remote_files = client.get_files(encrypted_paths.keys()).await?;
         log::debug!("get {} remote files", remote_files.len());
 
+        let base_checksums = {
+            let _guard = index::lock_index(base_path).await;
+            FileIndex::load(base_path, &self.key_id())
+                .await
+                .synced_checksums()
+        };
+        let remote_entries = if preserve_mtime || conflict.policy == ConflictPolicy::NewestWins {
+            self.remote_entries(&client).await?
+        } else {
+            RemoteEntries::default()
+        };
+        let base_checksums = &base_checksums;
+
+        let tracker = BatchTracker::new(self.shared.clone(), &self.uuid, cancel.id(), "download");
         let mut tasks = vec![];
         for (encrypted_file_path, remote_url) in remote_files {
             let file_path = match encrypted_paths.get(&encrypted_file_path) {
@@ -412,51 +942,180 @@ impl Graph {
                 None => continue,
             };
 
-            let absolute_file_path = base_path.join(&file_path);
+            let absolute_file_path = local_path(base_path, &file_path);
             let client = client.clone();
-            let graph_uuid = self.uuid.clone();
-
-            let progress_callback = move |bytes, total| {
-                static mut LAST: usize = 0;
-                let progress = bytes * 100 / total;
-                unsafe {
-                    // reduce callback calling frequency
-                    if LAST / 10 != progress / 10 || bytes == total {
-                        LAST = progress;
-                        if let Some(callback) = &PROGRESS_CALLBACK {
-                            let progress =
-                                Progress::download(&graph_uuid, &file_path, bytes as _, total as _);
-                            callback(progress);
+            let tracker = tracker.clone();
+            // avoid use of moved value
+            let file_path1 = file_path.clone();
+            let remote_mtime = remote_entries.get(&encrypted_file_path).map(|f| f.mtime);
+            let progress_callback = tracker.file_callback(&file_path);
+
+            tasks.push(async move {
+                let path = file_path1.clone();
+                let ret = async move {
+                    let local = special_file::read(&absolute_file_path, self.symlink_policy).await;
+                    // new files are written as-is, existing ones are checked for conflicts
+                    if dry_run.is_enabled() && local.is_err() {
+                        dry_run.record(PlannedAction::write_local(
+                            &file_path1,
+                            &absolute_file_path,
+                            &remote_url,
+                        ));
+                        return Ok((None, None));
+                    }
+                    let decrypted = self
+                        .download_content(&client, &remote_url, progress_callback)
+                        .await?;
+                    let md5 = md5_hexdigest(&decrypted);
+
+                    let mut conflicted = None;
+                    if let Ok(local) = local {
+                        let local_md5 = md5_hexdigest(&local);
+                        let base_md5 = match base_checksums.get(&*unicode_path::nfc(&file_path1)) {
+                            Some(md5) => Some(md5.clone()),
+                            None => fs::read(
+                                base_path
+                                    .join("logseq/version-files/base")
+                                    .join(&file_path1),
+                            )
+                            .await
+                            .ok()
+                            .map(|c| md5_hexdigest(&c)),
+                        };
+                        if conflict::is_conflict(&local_md5, &md5, base_md5.as_deref()) {
+                            let local_mtime = fs::metadata(&absolute_file_path)
+                                .await
+                                .map(|m| mtime_millis(&m))
+                                .unwrap_or_default();
+                            let resolution = conflict.policy.resolve(local_mtime, remote_mtime);
+                            log::warn!(
+                                "conflict {:?}, resolved by {}",
+                                file_path1,
+                                resolution.as_str()
+                            );
+
+                            let mut conflicted_file = ConflictedFile {
+                                path: file_path1.clone(),
+                                resolution: resolution.as_str().to_string(),
+                                conflict_path: None,
+                            };
+                            match resolution {
+                                Resolution::KeepLocal | Resolution::CaseCollision => {
+                                    return Ok::<_, Error>((None, Some(conflicted_file)));
+                                }
+                                Resolution::KeepBoth => {
+                                    let copy_path = conflict::local_conflict_copy_path(
+                                        &file_path1,
+                                        &conflict.device_name,
+                                    );
+                                    let absolute_copy_path =
+                                        safe_path::join(base_path, &copy_path)?;
+                                    if !dry_run.is_enabled() {
+                                        fs::rename(&absolute_file_path, absolute_copy_path).await?;
+                                    }
+                                    conflicted_file.conflict_path = Some(copy_path);
+                                }
+                                Resolution::KeepRemote => {}
+                            }
+                            conflicted = Some(conflicted_file);
                         }
-                        log::debug!(
-                            "download progress: {}% {}/{} {:?}",
-                            progress,
-                            bytes,
-                            total,
-                            file_path
-                        );
                     }
-                }
-            };
-            tasks.push(async move {
-                let buf = client.download_file(&remote_url, progress_callback).await?;
-                let decrypted = self.decrypt_content(&buf)?;
+                    if dry_run.is_enabled() {
+                        dry_run.record(PlannedAction::write_local(
+                            &file_path1,
+                            &absolute_file_path,
+                            &remote_url,
+                        ));
+                        return Ok((None, conflicted));
+                    }
 
-                if let Some(dir) = absolute_file_path.parent() {
-                    fs::create_dir_all(dir).await?;
+                    self.backup_before_write(
+                        base_path,
+                        &file_path1,
+                        &absolute_file_path,
+                        &decrypted,
+                    )
+                    .await?;
+                    special_file::write_file(
+                        base_path,
+                        &absolute_file_path,
+                        &decrypted,
+                        remote_mtime.filter(|_| preserve_mtime),
+                        self.symlink_policy,
+                    )
+                    .await?;
+
+                    let metadata =
+                        special_file::metadata(&absolute_file_path, self.symlink_policy).await?;
+                    // downloaded content is the remote version
+                    let entry = IndexEntry {
+                        size: decrypted.len() as _,
+                        mtime: mtime_millis(&metadata),
+                        inode: inode(&metadata),
+                        md5: md5.clone(),
+                        encrypted_fname: encrypted_file_path,
+                        txid: None,
+                        synced_md5: Some(md5),
+                    };
+                    Ok::<_, Error>((Some(entry), conflicted))
                 }
-                let mut file = fs::File::create(absolute_file_path).await?;
-                file.write_all(&decrypted).await?;
-                file.flush().await?;
-                Ok::<_, Error>(())
+                .await;
+                tracker.file_done();
+                if let Ok((Some(_), _)) = &ret {
+                    self.emit_event(
+                        self.event("file-downloaded", "update-local-files", cancel)
+                            .path(&path),
+                    );
+                }
+                (path, ret)
             });
         }
+        if !dry_run.is_enabled() {
+            tracker.start(tasks.len());
+        }
 
         tokio::select! {
             ret = future::join_all(tasks) => {
-                ret.into_iter().collect::<Result<Vec<_>>>().map(|_| ())
+                let mut update = LocalUpdate {
+                    files: batch,
+                    conflicts: collisions
+                        .into_iter()
+                        .map(|c| ConflictedFile {
+                            path: c.path,
+                            resolution: Resolution::CaseCollision.as_str().to_string(),
+                            conflict_path: Some(c.conflict_with),
+                        })
+                        .collect(),
+                };
+                let mut entries = vec![];
+                for (path, ret) in ret {
+                    match ret {
+                        Ok((entry, conflicted)) => {
+                            entries.extend(entry.map(|entry| (path.clone(), entry)));
+                            update.conflicts.extend(conflicted);
+                            update.files.succeeded.push(path);
+                        }
+                        Err(e) => update.files.fail(&path, &e),
+                    }
+                }
+                if !entries.is_empty() {
+                    self.update_file_index(base_path, |index| {
+                        for (path, entry) in entries {
+                            index.insert(&path, entry);
+                        }
+                    })
+                    .await;
+                }
+                for c in update.conflicts.iter().filter(|_| !dry_run.is_enabled()) {
+                    self.emit_event(
+                        self.event("conflict", "update-local-files", cancel)
+                            .path(&c.path)
+                            .detail(&c.resolution),
+                    );
+                }
+                Ok(update)
             }
-            _ = cancel_notification.changed() => {
+            _ = cancel.cancelled() => {
                 log::warn!("downloading remote cancelled");
                 Err(Error::Cancelled)
             }
@@ -464,146 +1123,675 @@ impl Graph {
     }
 
     /// Logseq Sync v2, update remote files and save to local version-db.
+    ///
+    /// Files failed to upload are left out of the remote update,
+    /// the txid is unchanged if no file is uploaded.
+    /// Files over the size limits are skipped, or deferred on metered connections.
+    /// Files whose content matches the remote copy are skipped as unchanged, unless `force`.
+    /// Failed updates are retried, emitting `retry-scheduled` events.
     pub async fn update_remote_files<P: AsRef<Path>, S: AsRef<str>>(
         &self,
         base_path: P,
         file_paths: impl IntoIterator<Item = S>,
         txid: i64,
         token: &str,
-        _metadata: Option<Metadata>, // TODO
-    ) -> Result<i64> {
-        let mut cancel_notification = unsafe { CANCELLACTION_RX.as_ref().unwrap().clone() };
-        let _ = cancel_notification.borrow_and_update();
+        options: &UploadOptions,
+        cancel: &CancelHandle,
+    ) -> Result<RemoteUpdate> {
+        let base_path = base_path.as_ref();
+        let file_paths = file_paths
+            .into_iter()
+            .map(|p| p.as_ref().to_string())
+            .collect::<Vec<_>>();
+        self.run_operation("update-remote-files", cancel, async {
+            let mut retries = 0;
+            loop {
+                let ret = self
+                    .update_remote_files_inner(base_path, &file_paths, txid, token, options, cancel)
+                    .await;
+                match ret {
+                    Err(e) if retries < UPLOAD_RETRIES && !cancel.is_cancelled() => {
+                        if e.to_string().contains("ExpiredToken") {
+                            log::warn!("token expired, retry");
+                        }
+                        retries += 1;
+                        log::warn!("update remote files(retry={}): {}", retries, e);
+                        // planned actions of the failed attempt
+                        options.dry_run.take_actions();
+                        self.emit_event(
+                            self.event("retry-scheduled", "update-remote-files", cancel)
+                                .detail(format!("retry {}: {}", retries, e)),
+                        );
+                    }
+                    ret => return ret,
+                }
+            }
+        })
+        .await
+    }
 
-        let mut client = SyncClient::new(&token);
+    async fn update_remote_files_inner<P: AsRef<Path>, S: AsRef<str>>(
+        &self,
+        base_path: P,
+        file_paths: impl IntoIterator<Item = S>,
+        txid: i64,
+        token: &str,
+        options: &UploadOptions,
+        cancel: &CancelHandle,
+    ) -> Result<RemoteUpdate> {
+        let metadata = &options.metadata;
+        let force = options.force;
+        let dry_run = &options.dry_run;
+        let mut client = self.sync_client(token);
         client.set_graph(&self.uuid, txid);
-        client.refresh_temp_credential().await?;
+        if client.refresh_temp_credential().await? {
+            self.emit_event(self.event("credential-refreshed", "update-remote-files", cancel));
+        }
 
         let base_path = base_path.as_ref();
+        // chunked storage needs support of the server, files are uploaded whole otherwise
+        let chunk_threshold = match self.chunk_threshold {
+            Some(_) if dry_run.is_enabled() => None,
+            Some(threshold) if chunked::is_supported(&client, &self.uuid).await? => Some(threshold),
+            Some(_) => {
+                log::warn!("chunked storage not supported by the server");
+                None
+            }
+            None => None,
+        };
         let client = Arc::new(client);
 
         // ensure folder and write permission
-        fs::create_dir_all(base_path.join("logseq/version-files/base")).await?;
+        if !dry_run.is_enabled() {
+            fs::create_dir_all(base_path.join("logseq/version-files/base")).await?;
+        }
+        if let Some(metadata) = metadata {
+            self.shared
+                .case_sensitivity
+                .set(base_path, metadata.fs_case_sensitive)?;
+        }
+
+        let ignore_rules = self.load_ignore_rules(base_path).await?;
+        let mut batch = BatchResult::new();
+        let file_paths = normalize_batch(file_paths, &mut batch);
+
+        let file_paths = filter_ignored(&ignore_rules, file_paths);
+        let (file_paths, mut skipped) = self.check_file_sizes(base_path, file_paths, dry_run).await;
+        let file_paths = if force {
+            file_paths
+        } else {
+            let (file_paths, unchanged) = self
+                .check_remote_checksums(&client, base_path, file_paths, txid)
+                .await;
+            skipped.extend(unchanged);
+            file_paths
+        };
+        for f in &skipped {
+            self.emit_event(
+                self.event("file-skipped", "update-remote-files", cancel)
+                    .path(&f.path)
+                    .detail(&f.reason),
+            );
+        }
 
+        let tracker = BatchTracker::new(self.shared.clone(), &self.uuid, cancel.id(), "upload");
         let mut tasks = vec![];
-        let mut page_files = vec![];
         for file_path in file_paths {
             // move in variables
             let client = client.clone();
-            let file_path = file_path.as_ref().to_string();
-            if is_page_file_path(&file_path) {
-                page_files.push(file_path.clone());
-            }
-
-            let full_file_path = base_path.join(&file_path);
-
-            let progress_callback = {
-                let file_path = file_path.clone();
-                let graph_uuid = self.uuid.clone();
-
-                move |bytes, total| {
-                    static mut LAST: usize = 0;
-                    let progress = bytes * 100 / total;
-                    unsafe {
-                        // reduce notifications
-                        if LAST / 10 != progress / 10 || bytes == total {
-                            if let Some(callback) = &PROGRESS_CALLBACK {
-                                let progress = Progress::upload(
-                                    &graph_uuid,
-                                    &file_path,
-                                    bytes as _,
-                                    total as _,
-                                );
-                                callback(progress);
-                            }
-                            LAST = progress;
-                            log::debug!(
-                                "upload progress: {}% {}/{} {}",
-                                progress,
-                                bytes,
-                                total,
-                                file_path
-                            );
-                        }
+            let tracker = tracker.clone();
+
+            let full_file_path = local_path(base_path, &file_path);
+            if dry_run.is_enabled() {
+                let planned = async {
+                    let metadata = fs::metadata(&full_file_path).await?;
+                    Ok::<_, Error>(PlannedAction::upload(
+                        &file_path,
+                        &self.encrypt_filename(&file_path)?,
+                        &full_file_path,
+                        metadata.len(),
+                    ))
+                };
+                match planned.await {
+                    Ok(action) => {
+                        dry_run.record(action);
+                        batch.succeeded.push(file_path);
                     }
+                    Err(e) => batch.fail(&file_path, &e),
                 }
-            };
+                continue;
+            }
+
+            let progress_callback = tracker.file_callback(&file_path);
             tasks.push(async move {
-                let content = fs::read(full_file_path).await?;
-                // stage 1.1: md5 metadata
-                let md5checksum = md5_hexdigest(&content);
-                // stage 1.2: encryption
-                let encrypted = self.encrypt_content(&content)?;
-                if encrypted.len() > 10 * 1024 * 1024 {
-                    log::warn!(
-                        "large file {:?} size: {:.2}MiB encrypted: {:.2}MiB",
-                        file_path,
-                        content.len() as f64 / (1024.0 * 1024.0),
-                        encrypted.len() as f64 / (1024.0 * 1024.0)
-                    );
+                let path = file_path.clone();
+                let ret = async move {
+                    let metadata =
+                        special_file::metadata(&full_file_path, self.symlink_policy).await?;
+                    let content = special_file::read(&full_file_path, self.symlink_policy).await?;
+                    // stage 1.1: md5 metadata
+                    let md5checksum = md5_hexdigest(&content);
+                    // stage 1.2: encryption, of the manifest for chunked files
+                    let chunked = chunk_threshold.is_some_and(|t| content.len() as u64 >= t);
+                    let encrypted = if chunked {
+                        let manifest =
+                            chunked::upload(&*client, self, &content, &progress_callback).await?;
+                        self.encrypt_content(&manifest.to_bytes())?.into_owned()
+                    } else {
+                        // not to be taken as a manifest when downloaded
+                        let stored = chunked::escape(&content);
+                        if self.compression_enabled && is_page_file(&file_path) {
+                            let compressed = compression::compress(&stored);
+                            self.encrypt_encoded(&compressed)?.into_owned()
+                        } else {
+                            self.encrypt_content(&stored)?.into_owned()
+                        }
+                    };
+                    if encrypted.len() > 10 * 1024 * 1024 {
+                        log::warn!(
+                            "large file {:?} size: {:.2}MiB encrypted: {:.2}MiB",
+                            file_path,
+                            content.len() as f64 / (1024.0 * 1024.0),
+                            encrypted.len() as f64 / (1024.0 * 1024.0)
+                        );
+                    }
+                    let remote_temp_url = client
+                        .upload_tempfile(encrypted.into(), move |bytes, total| {
+                            // progress of chunked files is of their chunks
+                            if !chunked {
+                                progress_callback(bytes, total);
+                            }
+                        })
+                        .await?;
+                    let encrypted_file_path = self.encrypt_filename(&file_path)?;
+                    let entry = IndexEntry {
+                        size: content.len() as _,
+                        mtime: mtime_millis(&metadata),
+                        inode: inode(&metadata),
+                        md5: md5checksum.clone(),
+                        encrypted_fname: encrypted_file_path.clone(),
+                        txid: None,
+                        synced_md5: None,
+                    };
+                    Result::Ok((
+                        (encrypted_file_path, remote_temp_url, md5checksum),
+                        (file_path, entry),
+                    ))
                 }
-                let remote_temp_url = client.upload_tempfile(encrypted, progress_callback).await?;
-                let encrypted_file_path = self.encrypt_filename(&file_path)?;
-                Result::Ok((encrypted_file_path, remote_temp_url, md5checksum))
+                .await;
+                tracker.file_done();
+                (path, ret)
+            });
+        }
+
+        if dry_run.is_enabled() {
+            return Ok(RemoteUpdate {
+                txid,
+                files: batch,
+                skipped,
             });
         }
+        tracker.start(tasks.len());
 
         tokio::select! {
             task_results = future::join_all(tasks) => {
-                let temp_remote_files = task_results.into_iter().collect::<Result<Vec<_>>>()?;
-                let update = client.update_files(temp_remote_files).await?;
-                for path in page_files {
-                    let target_path = base_path.join("logseq/version-files/base").join(&path);
-                    if let Some(dir) = target_path.parent() {
-                        fs::create_dir_all(dir).await?;
+                let (temp_remote_files, entries): (Vec<_>, Vec<_>) = task_results
+                    .into_iter()
+                    .filter_map(|(path, ret)| match ret {
+                        Ok(uploaded) => Some(uploaded),
+                        Err(e) => {
+                            batch.fail(&path, &e);
+                            None
+                        }
+                    })
+                    .unzip();
+                if entries.is_empty() {
+                    return Ok(RemoteUpdate { txid, files: batch, skipped });
+                }
+                let mtimes = entries
+                    .iter()
+                    .map(|(_, entry)| (entry.encrypted_fname.clone(), entry.mtime))
+                    .collect();
+                let update = client
+                    .update_files_with_mtimes(temp_remote_files, &mtimes)
+                    .await?;
+                self.shared.remote_index.update(
+                    &self.uuid,
+                    txid,
+                    update.txid,
+                    entries.iter().map(|(_, entry)| {
+                        let remote = RemoteEntry {
+                            checksum: Some(entry.md5.clone()),
+                            mtime: entry.mtime,
+                        };
+                        (entry.encrypted_fname.clone(), remote)
+                    }),
+                );
+                self.update_file_index(base_path, |index| {
+                    for (path, entry) in &entries {
+                        index.insert(path, entry.clone());
+                        index.mark_synced(path, update.txid);
                     }
-                    fs::copy(base_path.join(&path),target_path ).await?;
-                    log::debug!("copy page file to version-files: {:?}", path);
+                })
+                .await;
+                for (path, _) in entries {
+                    if is_page_file_path(&path) {
+                        let target_path = base_path.join("logseq/version-files/base").join(&path);
+                        if let Some(dir) = target_path.parent() {
+                            fs::create_dir_all(dir).await?;
+                        }
+                        fs::copy(local_path(base_path, &path), target_path).await?;
+                        log::debug!("copy page file to version-files: {:?}", path);
+                    }
+                    self.emit_event(self.event("file-uploaded", "update-remote-files", cancel).path(&path));
+                    batch.succeeded.push(path);
                 }
-                Ok(update.txid)
+                self.shared.deferred_uploads.remove(&self.uuid, &batch.succeeded);
+                Ok(RemoteUpdate { txid: update.txid, files: batch, skipped })
             }
-            _ = cancel_notification.changed() => {
+            _ = cancel.cancelled() => {
                 log::warn!("update remote file cancelled");
                 Err(Error::Cancelled)
             }
         }
     }
 
+    /// Split files to upload by size limits, return files to upload and skipped files.
+    /// Deferred files are queued, unless in dry run.
+    async fn check_file_sizes(
+        &self,
+        base_path: &Path,
+        file_paths: Vec<String>,
+        dry_run: &DryRun,
+    ) -> (Vec<String>, Vec<SkippedFile>) {
+        let unmetered = self.shared.is_unmetered();
+        let mut upload_paths = vec![];
+        let mut skipped = vec![];
+        for file_path in file_paths {
+            // files not found fail later in upload
+            let size =
+                special_file::metadata(&local_path(base_path, &file_path), self.symlink_policy)
+                    .await
+                    .map(|metadata| metadata.len())
+                    .unwrap_or_default();
+            match self.size_limits.check(&file_path, size, unmetered) {
+                SizeCheck::Upload => upload_paths.push(file_path),
+                SizeCheck::Skip => {
+                    log::warn!("skip large file {:?}, size: {}", file_path, size);
+                    skipped.push(SkippedFile::new(&file_path, "too-large"));
+                }
+                SizeCheck::Defer => {
+                    log::info!("defer large file {:?}, size: {}", file_path, size);
+                    if !dry_run.is_enabled() {
+                        self.shared.deferred_uploads.defer(&self.uuid, &file_path);
+                    }
+                    skipped.push(SkippedFile::new(&file_path, "deferred"));
+                }
+            }
+        }
+        (upload_paths, skipped)
+    }
+
+    /// Remote files at the current remote txid, listed once per txid
+    async fn remote_entries(&self, client: &SyncClient) -> Result<RemoteEntries> {
+        let txid = client.get_graph_by_uuid(&self.uuid).await?.txid;
+        self.remote_entries_at(client, txid).await
+    }
+
+    async fn remote_entries_at(&self, client: &SyncClient, txid: i64) -> Result<RemoteEntries> {
+        if let Some(entries) = self.shared.remote_index.get(&self.uuid, txid) {
+            return Ok(entries);
+        }
+        let entries = remote_index::from_listing(client.get_all_files().await?);
+        self.shared
+            .remote_index
+            .set(&self.uuid, txid, entries.clone());
+        Ok(entries)
+    }
+
+    /// Split files to upload by remote checksums, return changed files and unchanged files.
+    /// Remote checksums are listed once per remote txid, all files are changed if listing fails.
+    async fn check_remote_checksums(
+        &self,
+        client: &SyncClient,
+        base_path: &Path,
+        file_paths: Vec<String>,
+        txid: i64,
+    ) -> (Vec<String>, Vec<SkippedFile>) {
+        if file_paths.is_empty() {
+            return (file_paths, vec![]);
+        }
+        let remote = match self.remote_entries_at(client, txid).await {
+            Ok(remote) => remote,
+            Err(e) => {
+                log::warn!("cannot list remote checksums: {}", e);
+                return (file_paths, vec![]);
+            }
+        };
+        let index = {
+            let _guard = index::lock_index(base_path).await;
+            FileIndex::load(base_path, &self.key_id()).await
+        };
+
+        let mut changed = vec![];
+        let mut unchanged = vec![];
+        for file_path in file_paths {
+            let remote_md5 = self
+                .encrypt_filename(&file_path)
+                .ok()
+                .and_then(|p| remote.get(&p)?.checksum.as_ref());
+            let local_md5 = match remote_md5 {
+                Some(_) => self.local_md5(&index, base_path, &file_path).await,
+                None => None,
+            };
+            if local_md5.is_some() && local_md5.as_ref() == remote_md5 {
+                log::debug!("skip unchanged file {:?}", file_path);
+                unchanged.push(SkippedFile::new(&file_path, "unchanged"));
+            } else {
+                changed.push(file_path);
+            }
+        }
+        (changed, unchanged)
+    }
+
+    /// md5 of a local file, from the file index if the file is unchanged since indexed
+    async fn local_md5(
+        &self,
+        index: &FileIndex,
+        base_path: &Path,
+        file_path: &str,
+    ) -> Option<String> {
+        let path = local_path(base_path, file_path);
+        let metadata = special_file::metadata(&path, self.symlink_policy)
+            .await
+            .ok()?;
+        let size = metadata.len() as _;
+        match index.lookup(file_path, size, mtime_millis(&metadata), inode(&metadata)) {
+            Some(entry) => Some(entry.md5.clone()),
+            None => {
+                let content = special_file::read(&path, self.symlink_policy).await.ok()?;
+                Some(md5_hexdigest(&content))
+            }
+        }
+    }
+
+    /// Download version files, return downloaded file ids
     pub async fn update_local_version_files<P: AsRef<Path>, S: AsRef<str>>(
         &self,
         base_path: P,
         file_ids: impl IntoIterator<Item = S>,
         token: &str,
-    ) -> Result<()> {
+        cancel: &CancelHandle,
+    ) -> Result<BatchResult<String>> {
         let base_path = base_path.as_ref();
-        let mut client = SyncClient::new(&token);
+        let mut client = self.sync_client(token);
         client.set_graph(&self.uuid, 0);
 
         let files = client.get_version_files(file_ids).await?;
-        for (file_id, file_url) in files {
-            let full_file_path = base_path.join("logseq/version-files").join(&file_id);
-
-            let buf = client.download_file(&file_url, |_, _| {}).await?;
-            let decrypted = self.decrypt_content(&buf)?;
+        let download = async {
+            let mut batch = BatchResult::new();
+            for (file_id, file_url) in files {
+                let ret = async {
+                    let full_file_path =
+                        safe_path::join(base_path.join("logseq/version-files"), &file_id)?;
+
+                    let decrypted = self.download_content(&client, &file_url, |_, _| {}).await?;
+
+                    atomic_file::write_file(full_file_path, &decrypted).await?;
+                    Ok(())
+                };
+                let ret = ret.await.map(|_| file_id.clone());
+                batch.push(&file_id, ret);
+            }
+            batch
+        };
+        tokio::select! {
+            batch = download => Ok(batch),
+            _ = cancel.cancelled() => {
+                log::warn!("downloading version files cancelled");
+                Err(Error::Cancelled)
+            }
+        }
+    }
 
-            if let Some(file_dir) = full_file_path.parent() {
-                fs::create_dir_all(file_dir).await?;
+    /// Back up a local file about to be overwritten by different content, if enabled
+    async fn backup_before_write(
+        &self,
+        base_path: &Path,
+        file_path: &str,
+        local_path: &Path,
+        content: &[u8],
+    ) -> Result<()> {
+        if !self.backup_enabled {
+            return Ok(());
+        }
+        match special_file::read(local_path, self.symlink_policy).await {
+            Ok(local) if local != content => {
+                backup::copy_to_backup(base_path, file_path, local_path).await?;
             }
-            let mut file = fs::File::create(full_file_path).await?;
-            file.write_all(&decrypted).await?;
-            file.flush().await?;
+            _ => {}
         }
         Ok(())
     }
 
-    async fn get_file_meta<P: AsRef<Path>, S: AsRef<str>>(
+    /// Merge incoming page files fetched by `fetch_remote_files` into local files,
+    /// with base versions in `logseq/version-files/base`.
+    ///
+    /// Merged content is written to local files, and the incoming version becomes the new base.
+    /// Conflicting files are left untouched unless `write_conflict_markers` is set.
+    pub async fn merge_remote_files<P: AsRef<Path>, S: AsRef<str>>(
+        &self,
+        base_path: P,
+        file_paths: impl IntoIterator<Item = S>,
+        write_conflict_markers: bool,
+    ) -> Result<BatchResult<MergeReport>> {
+        let base_path = base_path.as_ref();
+
+        let mut batch = BatchResult::new();
+        for file_path in file_paths {
+            let file_path = file_path.as_ref();
+            let report = self
+                .merge_remote_file(base_path, file_path, write_conflict_markers)
+                .await;
+            if let Ok(report) = &report {
+                if report.status == "conflict" {
+                    self.emit_event(
+                        SyncEvent::new(&self.uuid, "conflict")
+                            .path(&report.path)
+                            .detail("merge"),
+                    );
+                }
+            }
+            batch.push(file_path, report);
+        }
+        Ok(batch)
+    }
+
+    async fn merge_remote_file(
+        &self,
+        base_path: &Path,
+        file_path: &str,
+        write_conflict_markers: bool,
+    ) -> Result<MergeReport> {
+        let file_path = &safe_path::normalize(file_path)?;
+        let format = PageFormat::from_path(file_path).ok_or(Error::InvalidArg)?;
+
+        let local_path = local_path(base_path, file_path);
+        let incoming_path = base_path
+            .join("logseq/version-files/incoming")
+            .join(file_path);
+        let base_version_path = base_path.join("logseq/version-files/base").join(file_path);
+
+        let incoming = fs::read_to_string(&incoming_path).await?;
+        let base = read_to_string_or_empty(&base_version_path).await?;
+        let local = read_to_string_or_empty(&local_path).await?;
+
+        let merged = merge::merge_page(&base, &local, &incoming, format);
+        let status = if !merged.is_clean() {
+            log::warn!(
+                "merge conflict: {:?}, {} conflicts",
+                file_path,
+                merged.conflicts.len()
+            );
+            "conflict"
+        } else if merged.content == local {
+            "unchanged"
+        } else {
+            "merged"
+        };
+
+        if merged.is_clean() || write_conflict_markers {
+            if merged.content != local {
+                self.backup_before_write(
+                    base_path,
+                    file_path,
+                    &local_path,
+                    merged.content.as_bytes(),
+                )
+                .await?;
+                atomic_file::write_file(&local_path, &merged.content).await?;
+                log::debug!("write merged file: {:?}", file_path);
+            }
+            if let Some(dir) = base_version_path.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            fs::rename(&incoming_path, &base_version_path).await?;
+        }
+
+        Ok(MergeReport {
+            path: file_path.to_string(),
+            status: status.to_string(),
+            conflicts: merged.conflicts.len() as _,
+        })
+    }
+
+    /// Compute a sync plan of local files against remote.
+    ///
+    /// When `txid` is not positive, the full remote file list is compared,
+    /// otherwise only remote changes since `txid`.
+    pub async fn plan_sync<P: AsRef<Path>>(
+        &self,
+        base_path: P,
+        txid: i64,
+        token: &str,
+    ) -> Result<SyncPlan> {
+        let base_path = dunce::canonicalize(base_path.as_ref())?;
+        let local = self.get_all_files_meta(&base_path, false).await?;
+        if !local.is_all_succeeded() {
+            log::warn!("{} local files not readable", local.failed.len());
+        }
+        let local = local.succeeded;
+        let base = self.get_base_checksums(&base_path).await?;
+
+        let mut client = self.sync_client(token);
+        client.set_graph(&self.uuid, txid);
+
+        if txid <= 0 {
+            let remote_txid = client.get_graph_by_uuid(&self.uuid).await?.txid;
+            let remote = client
+                .get_all_files()
+                .await?
+                .iter()
+                .filter_map(|obj| match RemoteFile::from_file_object(self, obj) {
+                    Ok(f) => Some(f),
+                    Err(e) => {
+                        log::warn!("cannot decrypt remote file name {:?}: {}", obj.key, e);
+                        None
+                    }
+                })
+                .collect::<Vec<_>>();
+            log::debug!("plan sync with {} remote files", remote.len());
+            Ok(planner::plan_with_remote_files(
+                &local,
+                &remote,
+                &base,
+                remote_txid,
+            ))
+        } else {
+            let txns = client.get_diff(txid as _).await?;
+            let remote_txid = txns.iter().map(|t| t.txid).max().unwrap_or(txid);
+            let mut changes = vec![];
+            for txn in &txns {
+                changes.extend(RemoteChange::from_transaction(self, txn)?);
+            }
+            log::debug!("plan sync with {} remote changes", changes.len());
+            Ok(planner::plan_with_remote_changes(
+                &local,
+                &changes,
+                &base,
+                remote_txid,
+            ))
+        }
+    }
+
+    /// md5 of files at last sync, `path => md5`, paths are in NFC form.
+    /// Page files are from `logseq/version-files/base`, others from the file index.
+    pub async fn get_base_checksums<P: AsRef<Path>>(
+        &self,
+        base_path: P,
+    ) -> Result<HashMap<String, String>> {
+        let base_path = base_path.as_ref();
+        let mut checksums = {
+            let _guard = index::lock_index(base_path).await;
+            FileIndex::load(base_path, &self.key_id())
+                .await
+                .synced_checksums()
+        };
+
+        let version_base = base_path.join("logseq/version-files/base");
+        let paths = walkdir::WalkDir::new(&version_base)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter_map(|e| {
+                e.path()
+                    .strip_prefix(&version_base)
+                    .ok()
+                    .and_then(|p| p.to_str())
+                    .map(|p| p.replace("\\", "/").trim_start_matches('/').to_string())
+            })
+            .collect::<Vec<_>>();
+        for path in paths {
+            let content = fs::read(version_base.join(&path)).await?;
+            checksums.insert(
+                unicode_path::nfc(&path).into_owned(),
+                md5_hexdigest(&content),
+            );
+        }
+        Ok(checksums)
+    }
+
+    /// Ignore rules of the graph: default rules, `logseq/.syncignore` and rules from `set_env`
+    pub async fn load_ignore_rules<P: AsRef<Path>>(&self, base_path: P) -> Result<IgnoreRules> {
+        IgnoreRules::load(base_path, &self.ignore_rules).await
+    }
+
+    /// Apply changes to the persistent file index, failures are only logged
+    async fn update_file_index<F: FnOnce(&mut FileIndex)>(&self, base_path: &Path, f: F) {
+        let _guard = index::lock_index(base_path).await;
+        let mut index = FileIndex::load(base_path, &self.key_id()).await;
+        f(&mut index);
+        if let Err(e) = index.save(base_path).await {
+            log::warn!("cannot save file index: {}", e);
+        }
+    }
+
+    pub(crate) async fn get_file_meta<P: AsRef<Path>, S: AsRef<str>>(
         &self,
         base_path: P,
         file_path: S,
     ) -> Result<FileMeta> {
         use md5::{Digest, Md5};
         let base_path = dunce::canonicalize(base_path.as_ref())?;
-        let full_file_path = dunce::canonicalize(base_path.join(file_path.as_ref()))?;
+        let file_path_on_disk = base_path.join(file_path.as_ref());
+        // symlinks keep their own paths, other paths are resolved to the on-disk form
+        let full_file_path = match special_file::find_link(&base_path, &file_path_on_disk).await {
+            Some(_) if self.symlink_policy == SymlinkPolicy::Skip => {
+                return Err(Error::SkippedFile("symlink"));
+            }
+            Some(_) => file_path_on_disk,
+            None => dunce::canonicalize(file_path_on_disk)?,
+        };
 
         let canonicalized_file_path = full_file_path
             .strip_prefix(&base_path)
@@ -613,40 +1801,45 @@ impl Graph {
             .ok_or(Error::InvalidArg)?;
         let normalized_file_path = canonicalized_file_path.nfc().collect::<String>();
 
-        let mut file = fs::File::open(&full_file_path).await?;
-
-        let mut nread = 0;
-        let mut buf = Vec::with_capacity(1024 * 1024);
-        let mut hasher = Md5::new();
-        loop {
-            let n = file.read_buf(&mut buf).await?;
-            if n == 0 {
-                break;
-            }
-            nread += n;
-            hasher.update(&buf[..n]);
-            unsafe {
-                buf.set_len(0);
-            }
-        }
-        let digest = hasher.finalize();
+        let (size, md5, metadata) =
+            match special_file::read_link_content(&full_file_path, self.symlink_policy).await? {
+                Some(content) => (
+                    content.len(),
+                    md5_hexdigest(&content),
+                    fs::symlink_metadata(&full_file_path).await?,
+                ),
+                None => {
+                    // opening a FIFO blocks
+                    let metadata = fs::metadata(&full_file_path).await?;
+                    if let Some(kind) = special_file::special_kind(&metadata.file_type()) {
+                        return Err(Error::SkippedFile(kind));
+                    }
+                    let mut file = fs::File::open(&full_file_path).await?;
+
+                    let mut nread = 0;
+                    let mut buf = Vec::with_capacity(1024 * 1024);
+                    let mut hasher = Md5::new();
+                    loop {
+                        let n = file.read_buf(&mut buf).await?;
+                        if n == 0 {
+                            break;
+                        }
+                        nread += n;
+                        hasher.update(&buf[..n]);
+                        unsafe {
+                            buf.set_len(0);
+                        }
+                    }
+                    let digest = hasher.finalize();
+                    (nread, format!("{:x}", digest), file.metadata().await?)
+                }
+            };
 
-        let metadata = file.metadata().await?;
         Ok(FileMeta {
-            size: nread as _,
-            mtime: metadata
-                .modified()
-                .ok()
-                .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
-                .unwrap_or(Duration::default())
-                .as_millis() as _,
-            ctime: metadata
-                .created()
-                .ok()
-                .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
-                .unwrap_or(Duration::default())
-                .as_millis() as _,
-            md5: format!("{:x}", digest),
+            size: size as _,
+            mtime: mtime_millis(&metadata),
+            ctime: ctime_millis(&metadata),
+            md5,
             fname: canonicalized_file_path.to_owned(),
             incoming_fname: file_path.as_ref().to_string(),
             normalized_fname: normalized_file_path,
@@ -655,11 +1848,8 @@ impl Graph {
     }
 }
 
-unsafe impl Send for Graph {}
-unsafe impl Sync for Graph {}
-
 #[cfg_attr(feature = "napi", napi(object))]
-#[derive(Debug)]
+#[derive(Serialize, Debug)]
 pub struct FileMeta {
     pub size: i64,
     /// modified time, in milliseconds
@@ -676,16 +1866,163 @@ pub struct FileMeta {
     pub encrypted_fname: String,
 }
 
+/// Result of `update_local_files`
+#[derive(Serialize, Debug, Default)]
+pub struct LocalUpdate {
+    /// updated files, including local files kept by the conflict policy
+    #[serde(flatten)]
+    pub files: BatchResult<String>,
+    /// conflicts resolved, and skipped case collisions
+    pub conflicts: Vec<ConflictedFile>,
+}
+
+/// Result of `update_remote_files` and `delete_remote_files`
+#[derive(Serialize, Debug)]
+pub struct RemoteUpdate {
+    pub txid: i64,
+    /// uploaded or deleted files
+    #[serde(flatten)]
+    pub files: BatchResult<String>,
+    /// files over size limits, skipped or deferred, files unchanged remotely, and ignored files
+    pub skipped: Vec<SkippedFile>,
+}
+
+impl Outcome for LocalUpdate {
+    fn failed(&self) -> &[FileError] {
+        &self.files.failed
+    }
+}
+
+impl Outcome for RemoteUpdate {
+    fn failed(&self) -> &[FileError] {
+        &self.files.failed
+    }
+}
+
+/// Result of scanning local files
+struct Scan {
+    files: BatchResult<FileMeta>,
+    collisions: Vec<UnicodeCollision>,
+    skipped: Vec<SkippedFile>,
+}
+
+/// Options of `Graph::update_local_files`
+#[derive(Default)]
+pub struct DownloadOptions {
+    pub conflict: ConflictOptions,
+    /// keep modified times of remote files
+    pub preserve_mtime: bool,
+    pub dry_run: DryRun,
+}
+
+/// Options of `Graph::update_remote_files`
+#[derive(Default)]
+pub struct UploadOptions {
+    pub metadata: Option<Metadata>,
+    /// upload files even if unchanged remotely
+    pub force: bool,
+    pub dry_run: DryRun,
+}
+
 /// Metadata for batch remote update
 #[cfg_attr(feature = "napi", napi(object))]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Metadata {
+    /// case sensitivity of the graph folder, used for case collisions instead of probing
     pub fs_case_sensitive: bool,
     pub version: String,
     pub revision: String,
     pub platform: String,
 }
 
+async fn read_to_string_or_empty(path: &Path) -> Result<String> {
+    match fs::read_to_string(path).await {
+        Ok(s) => Ok(s),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Normalize paths of a batch, unsafe paths are failed
+fn normalize_batch<S: AsRef<str>, T>(
+    file_paths: impl IntoIterator<Item = S>,
+    batch: &mut BatchResult<T>,
+) -> Vec<String> {
+    let mut normalized = vec![];
+    for p in file_paths {
+        match safe_path::normalize(p.as_ref()) {
+            Ok(p) => normalized.push(p),
+            Err(e) => batch.fail(p.as_ref(), &e),
+        }
+    }
+    normalized
+}
+
+/// Local file of a graph relative path, in the Unicode normalization form on disk
+fn local_path(base_path: &Path, file_path: &str) -> PathBuf {
+    base_path.join(unicode_path::on_disk_path(base_path, file_path))
+}
+
+/// Drop paths colliding with other paths of different case, on case-insensitive filesystems
+async fn skip_case_collisions(
+    case_sensitivity: &CaseSensitivity,
+    base_path: &Path,
+    file_paths: Vec<String>,
+) -> Result<(Vec<String>, Vec<CaseCollision>)> {
+    if case_sensitivity.detect(base_path).await? {
+        return Ok((file_paths, vec![]));
+    }
+    let collisions =
+        fs_case::find_collisions(&file_paths, |p| fs_case::local_case_variant(base_path, p));
+    for c in &collisions {
+        log::warn!("skip case collision: {:?} vs {:?}", c.path, c.conflict_with);
+    }
+    let file_paths = file_paths
+        .into_iter()
+        .filter(|p| !collisions.iter().any(|c| &c.path == p))
+        .collect();
+    Ok((file_paths, collisions))
+}
+
+/// Drop ignored file paths
+fn filter_ignored<S: AsRef<str>>(
+    ignore_rules: &IgnoreRules,
+    file_paths: impl IntoIterator<Item = S>,
+) -> Vec<String> {
+    file_paths
+        .into_iter()
+        .filter_map(|p| {
+            let p = p.as_ref();
+            if ignore_rules.is_ignored(p, false) {
+                log::info!("skip ignored file: {:?}", p);
+                None
+            } else {
+                Some(p.to_string())
+            }
+        })
+        .collect()
+}
+
+/// Relative path with `/` as separator
+fn relative_path(base_path: &Path, path: &Path) -> Option<String> {
+    path.strip_prefix(base_path)
+        .ok()
+        .and_then(|p| p.to_str())
+        .map(|p| p.replace("\\", "/").trim_start_matches('/').to_string())
+}
+
+fn index_entry_of(meta: &FileMeta, inode: u64) -> IndexEntry {
+    IndexEntry {
+        size: meta.size,
+        mtime: meta.mtime,
+        inode,
+        md5: meta.md5.clone(),
+        encrypted_fname: meta.encrypted_fname.clone(),
+        txid: None,
+        synced_md5: None,
+    }
+}
+
 fn is_page_file(file_path: &str) -> bool {
     let t = file_path.to_lowercase();
     t.ends_with(".md") || t.ends_with(".org") || t.ends_with(".markdown")
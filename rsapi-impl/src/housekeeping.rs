@@ -0,0 +1,214 @@
+//! Housekeeping of `logseq/version-files` and `logseq/bak`.
+//!
+//! Version and backup files are kept per page folder. They are pruned by age, by count per
+//! page and by total size, oldest first. Base copies of deleted pages are removed as well.
+//! Pending incoming files are never touched.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+#[cfg(feature = "napi")]
+use napi_derive::napi;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::batch::FileError;
+use crate::error::Result;
+use crate::index::mtime_millis;
+use crate::unicode_path;
+
+const VERSION_FILES: &str = "logseq/version-files";
+const BAK: &str = "logseq/bak";
+
+/// Limits of version and backup files, nothing is pruned by default
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct CleanupOptions {
+    /// remove files older than this
+    pub max_age_days: Option<i64>,
+    /// keep the newest files of each page
+    pub max_versions_per_page: Option<i64>,
+    /// total size budget in bytes, of version and backup files
+    pub max_total_size: Option<i64>,
+    /// report files without removing them
+    pub dry_run: Option<bool>,
+}
+
+/// Files removed by a cleanup
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct CleanupReport {
+    /// relative to graph base path
+    pub removed: Vec<String>,
+    pub reclaimed_bytes: i64,
+    /// base copies of deleted pages, among removed files
+    pub orphaned_base_files: i64,
+    pub failed: Vec<FileError>,
+}
+
+#[derive(Debug, Clone)]
+struct VersionFile {
+    path: String,
+    // page folder of the file
+    page: String,
+    mtime: i64,
+    size: u64,
+}
+
+/// Prune version and backup files of a graph
+pub async fn cleanup<P: AsRef<Path>>(
+    base_path: P,
+    options: &CleanupOptions,
+) -> Result<CleanupReport> {
+    let base_path = dunce::canonicalize(base_path.as_ref())?;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+
+    let orphans = list_files(&base_path, &format!("{VERSION_FILES}/base"))
+        .into_iter()
+        .filter(|f| {
+            let page = &f.path[VERSION_FILES.len() + "/base/".len()..];
+            !base_path
+                .join(unicode_path::on_disk_path(&base_path, page))
+                .exists()
+        })
+        .collect::<Vec<_>>();
+
+    let mut versions = list_files(&base_path, VERSION_FILES)
+        .into_iter()
+        .filter(|f| {
+            !f.path.starts_with(&format!("{VERSION_FILES}/base/"))
+                && !f.path.starts_with(&format!("{VERSION_FILES}/incoming/"))
+        })
+        .collect::<Vec<_>>();
+    versions.extend(list_files(&base_path, BAK));
+    let pruned = prune(versions, options, now);
+
+    let mut report = CleanupReport {
+        orphaned_base_files: orphans.len() as _,
+        ..Default::default()
+    };
+    for f in orphans.into_iter().chain(pruned) {
+        if !options.dry_run.unwrap_or_default() {
+            if let Err(e) = fs::remove_file(base_path.join(&f.path)).await {
+                log::warn!("cannot remove {:?}: {}", f.path, e);
+                report.failed.push(FileError::new(&f.path, &e.into()));
+                continue;
+            }
+        }
+        log::debug!("remove {:?}, size: {}", f.path, f.size);
+        report.reclaimed_bytes += f.size as i64;
+        report.removed.push(f.path);
+    }
+    log::info!(
+        "cleanup removed {} files, reclaimed {} bytes",
+        report.removed.len(),
+        report.reclaimed_bytes
+    );
+    Ok(report)
+}
+
+/// Regular files under a folder of the graph
+fn list_files(base_path: &Path, dir: &str) -> Vec<VersionFile> {
+    walkdir::WalkDir::new(base_path.join(dir))
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| {
+            let metadata = e.metadata().ok()?;
+            let path = e.path().strip_prefix(base_path).ok()?;
+            let path = path.to_str()?.replace('\\', "/");
+            let page = path.rsplit_once('/').map(|(page, _)| page)?.to_string();
+            Some(VersionFile {
+                path,
+                page,
+                mtime: mtime_millis(&metadata),
+                size: metadata.len(),
+            })
+        })
+        .collect()
+}
+
+/// Files over the limits, by age, count per page, then total size
+fn prune(mut files: Vec<VersionFile>, options: &CleanupOptions, now: i64) -> Vec<VersionFile> {
+    // newest first
+    files.sort_by(|a, b| (b.mtime, &b.path).cmp(&(a.mtime, &a.path)));
+
+    let min_mtime = options
+        .max_age_days
+        .map(|days| now - days.max(0) * 24 * 60 * 60 * 1000);
+    let max_versions = options.max_versions_per_page.map(|n| n.max(0) as usize);
+    let mut versions = HashMap::<&str, usize>::new();
+    let (kept, mut pruned): (Vec<_>, Vec<_>) = files.iter().partition(|f| {
+        let count = versions.entry(&f.page).or_default();
+        *count += 1;
+        min_mtime.is_none_or(|min| f.mtime >= min) && max_versions.is_none_or(|max| *count <= max)
+    });
+
+    if let Some(budget) = options.max_total_size {
+        let mut total = 0;
+        for f in kept {
+            total += f.size;
+            if total > budget.max(0) as u64 {
+                pruned.push(f);
+            }
+        }
+    }
+    pruned.into_iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune() {
+        let day = 24 * 60 * 60 * 1000;
+        let file = |path: &str, days: i64, size| VersionFile {
+            path: path.to_string(),
+            page: path.rsplit_once('/').unwrap().0.to_string(),
+            mtime: 100 * day - days * day,
+            size,
+        };
+        let files = vec![
+            file("logseq/bak/pages/a/1.md", 1, 10),
+            file("logseq/bak/pages/a/2.md", 2, 10),
+            file("logseq/bak/pages/a/3.md", 3, 10),
+            file("logseq/bak/pages/b/1.md", 1, 10),
+            file("logseq/bak/pages/b/40.md", 40, 10),
+        ];
+        let pruned = |options| {
+            let mut paths = prune(files.clone(), &options, 100 * day)
+                .into_iter()
+                .map(|f| f.path)
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+
+        assert!(pruned(CleanupOptions::default()).is_empty());
+        assert_eq!(
+            pruned(CleanupOptions {
+                max_age_days: Some(30),
+                max_versions_per_page: Some(2),
+                ..Default::default()
+            }),
+            vec!["logseq/bak/pages/a/3.md", "logseq/bak/pages/b/40.md"]
+        );
+        // oldest files go first
+        assert_eq!(
+            pruned(CleanupOptions {
+                max_total_size: Some(25),
+                ..Default::default()
+            }),
+            vec![
+                "logseq/bak/pages/a/2.md",
+                "logseq/bak/pages/a/3.md",
+                "logseq/bak/pages/b/40.md"
+            ]
+        );
+    }
+}
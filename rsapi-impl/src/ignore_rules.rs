@@ -0,0 +1,137 @@
+//! gitignore-style ignore rules of graph files.
+//!
+//! Rules are the default rules, followed by rules from `logseq/.syncignore`,
+//! then rules passed by `set_env`. Later rules take precedence, e.g. `!.gitkeep`.
+
+use std::path::Path;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use tokio::fs;
+
+use crate::error::{Error, Result};
+use crate::index::INDEX_FILE;
+
+/// Per-graph ignore file, relative to graph base path
+pub const SYNCIGNORE_FILE: &str = "logseq/.syncignore";
+
+/// Default rules: hidden files, backups and version files
+pub const DEFAULT_RULES: &[&str] = &[".*", "logseq/bak/", "logseq/version-files/"];
+
+/// Internal files, can not be overridden
+const BUILTIN_IGNORED_FILES: &[&str] = &[INDEX_FILE];
+
+#[derive(Debug, Clone)]
+pub struct IgnoreRules {
+    matcher: Gitignore,
+}
+
+impl Default for IgnoreRules {
+    fn default() -> Self {
+        Self::new(DEFAULT_RULES.iter().copied()).expect("default rules are valid")
+    }
+}
+
+impl IgnoreRules {
+    pub fn new<'a>(rules: impl IntoIterator<Item = &'a str>) -> Result<Self> {
+        let mut builder = GitignoreBuilder::new("");
+        for rule in rules {
+            builder.add_line(None, rule).map_err(|e| {
+                log::warn!("invalid ignore rule {:?}: {}", rule, e);
+                Error::InvalidArg
+            })?;
+        }
+        let matcher = builder.build().map_err(|_| Error::InvalidArg)?;
+        Ok(Self { matcher })
+    }
+
+    /// Load rules of a graph: default rules, `logseq/.syncignore` and extra rules.
+    /// Invalid lines of the ignore file are skipped.
+    pub async fn load<P: AsRef<Path>>(base_path: P, extra_rules: &[String]) -> Result<Self> {
+        let mut builder = GitignoreBuilder::new("");
+        for rule in DEFAULT_RULES {
+            builder
+                .add_line(None, rule)
+                .map_err(|_| Error::InvalidArg)?;
+        }
+
+        let ignore_file = base_path.as_ref().join(SYNCIGNORE_FILE);
+        match fs::read_to_string(&ignore_file).await {
+            Ok(content) => {
+                for line in content.lines() {
+                    if let Err(e) = builder.add_line(None, line) {
+                        log::warn!("invalid rule in {:?}: {}", ignore_file, e);
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        for rule in extra_rules {
+            builder.add_line(None, rule).map_err(|e| {
+                log::warn!("invalid ignore rule {:?}: {}", rule, e);
+                Error::InvalidArg
+            })?;
+        }
+        let matcher = builder.build().map_err(|_| Error::InvalidArg)?;
+        Ok(Self { matcher })
+    }
+
+    /// `path` is relative to graph base path, with `/` as separator
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        let path = path.trim_start_matches('/');
+        if path.is_empty() {
+            return false;
+        }
+        BUILTIN_IGNORED_FILES.contains(&path)
+            || self
+                .matcher
+                .matched_path_or_any_parents(path, is_dir)
+                .is_ignore()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_rules() {
+        let rules = IgnoreRules::default();
+
+        assert!(!rules.is_ignored("pages/contents.md", false));
+        assert!(!rules.is_ignored("logseq/config.edn", false));
+        assert!(rules.is_ignored(".git", true));
+        assert!(rules.is_ignored(".git/config", false));
+        assert!(rules.is_ignored("pages/.DS_Store", false));
+        assert!(rules.is_ignored("logseq/bak/pages/a.md", false));
+        assert!(rules.is_ignored("logseq/version-files/base/pages/a.md", false));
+        assert!(rules.is_ignored(INDEX_FILE, false));
+        // only top level folders are excluded
+        assert!(!rules.is_ignored("pages/logseq/bak/a.md", false));
+    }
+
+    #[tokio::test]
+    async fn test_syncignore_and_extra_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("logseq")).unwrap();
+        std::fs::write(
+            dir.path().join(SYNCIGNORE_FILE),
+            "# large assets\nassets/videos/\n!.gitkeep\n",
+        )
+        .unwrap();
+
+        let rules = IgnoreRules::load(dir.path(), &["*.tmp".to_string()])
+            .await
+            .unwrap();
+        assert!(rules.is_ignored("assets/videos/a.mp4", false));
+        assert!(!rules.is_ignored("assets/image.png", false));
+        assert!(!rules.is_ignored("assets/.gitkeep", false));
+        assert!(rules.is_ignored("pages/a.tmp", false));
+        assert!(rules.is_ignored("pages/.hidden.md", false));
+
+        assert!(IgnoreRules::load(dir.path(), &["pages/[a".to_string()])
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,287 @@
+//! Persistent local file-state index.
+//!
+//! Hashing every file of a big graph on each scan is slow on mobile devices.
+//! The index remembers `path => (size, mtime, inode, md5, encrypted name, txid)`
+//! so that files whose size and mtime are unchanged are not read again.
+//! Paths are keyed by their NFC form.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::Metadata,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::atomic_file;
+use crate::error::Result;
+use crate::unicode_path::nfc;
+
+/// Index file, relative to graph base path
+pub const INDEX_FILE: &str = "logseq/.sync-index.json";
+
+const INDEX_VERSION: u32 = 1;
+
+// locks of index files, by graph base path
+static INDEX_LOCKS: Lazy<std::sync::Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> =
+    Lazy::new(Default::default);
+
+/// Lock the index file of a graph, serializing its load-modify-save cycles.
+/// Hold it only around loading and saving, graphs are locked independently.
+pub(crate) async fn lock_index(base_path: &Path) -> OwnedMutexGuard<()> {
+    let key = dunce::canonicalize(base_path).unwrap_or_else(|_| base_path.to_path_buf());
+    let lock = INDEX_LOCKS.lock().unwrap().entry(key).or_default().clone();
+    lock.lock_owned().await
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IndexEntry {
+    pub size: i64,
+    /// modified time, in milliseconds
+    pub mtime: i64,
+    /// 0 on platforms without inode
+    pub inode: u64,
+    pub md5: String,
+    pub encrypted_fname: String,
+    /// txid of last successful sync of this file
+    #[serde(default)]
+    pub txid: Option<i64>,
+    /// md5 of the file content at last successful sync
+    #[serde(default)]
+    pub synced_md5: Option<String>,
+}
+
+impl IndexEntry {
+    /// Is the entry still valid for a file with given stat info
+    pub fn matches(&self, size: i64, mtime: i64, inode: u64) -> bool {
+        self.size == size && self.mtime == mtime && (self.inode == 0 || self.inode == inode)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FileIndex {
+    version: u32,
+    /// fingerprint of the graph encryption key, encrypted names depend on it
+    key_id: String,
+    files: HashMap<String, IndexEntry>,
+}
+
+impl FileIndex {
+    pub fn new(key_id: &str) -> Self {
+        Self {
+            version: INDEX_VERSION,
+            key_id: key_id.to_string(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Load index of a graph. A missing, corrupted or outdated index is treated as empty.
+    pub async fn load<P: AsRef<Path>>(base_path: P, key_id: &str) -> Self {
+        let index_path = base_path.as_ref().join(INDEX_FILE);
+        let raw = match fs::read(&index_path).await {
+            Ok(raw) => raw,
+            Err(_) => return Self::new(key_id),
+        };
+        match serde_json::from_slice::<FileIndex>(&raw) {
+            Ok(index) if index.version == INDEX_VERSION && index.key_id == key_id => index,
+            Ok(_) => {
+                log::info!("file index outdated, rebuilding: {:?}", index_path);
+                Self::new(key_id)
+            }
+            Err(e) => {
+                log::warn!("cannot parse file index {:?}: {}", index_path, e);
+                Self::new(key_id)
+            }
+        }
+    }
+
+    pub async fn save<P: AsRef<Path>>(&self, base_path: P) -> Result<()> {
+        let raw = serde_json::to_vec(self).map_err(std::io::Error::from)?;
+        atomic_file::write_file(base_path.as_ref().join(INDEX_FILE), raw).await
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn get(&self, path: &str) -> Option<&IndexEntry> {
+        self.files.get(&*nfc(path))
+    }
+
+    /// Get entry if it is still valid for given stat info
+    pub fn lookup(&self, path: &str, size: i64, mtime: i64, inode: u64) -> Option<&IndexEntry> {
+        self.files
+            .get(&*nfc(path))
+            .filter(|entry| entry.matches(size, mtime, inode))
+    }
+
+    /// Insert or replace an entry, keeping the last synced state
+    pub fn insert(&mut self, path: &str, mut entry: IndexEntry) {
+        let path = nfc(path);
+        if let Some(old) = self.files.get(&*path) {
+            if entry.synced_md5.is_none() {
+                entry.txid = old.txid;
+                entry.synced_md5 = old.synced_md5.clone();
+            }
+        }
+        self.files.insert(path.into_owned(), entry);
+    }
+
+    pub fn remove(&mut self, path: &str) -> Option<IndexEntry> {
+        self.files.remove(&*nfc(path))
+    }
+
+    /// Drop entries of files no longer exist, `paths` are in NFC form
+    pub fn retain_paths(&mut self, paths: &HashSet<String>) {
+        self.files.retain(|p, _| paths.contains(p));
+    }
+
+    pub fn mark_synced(&mut self, path: &str, txid: i64) {
+        if let Some(entry) = self.files.get_mut(&*nfc(path)) {
+            entry.txid = Some(txid);
+            entry.synced_md5 = Some(entry.md5.clone());
+        }
+    }
+
+    /// md5 of files at their last successful sync, `path => md5`, paths are in NFC form
+    pub fn synced_checksums(&self) -> HashMap<String, String> {
+        self.files
+            .iter()
+            .filter_map(|(p, e)| e.synced_md5.clone().map(|md5| (p.clone(), md5)))
+            .collect()
+    }
+}
+
+/// modified time, in milliseconds
+pub(crate) fn mtime_millis(metadata: &Metadata) -> i64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .unwrap_or_default()
+        .as_millis() as _
+}
+
+/// set modified time of a file, in milliseconds
+pub(crate) async fn set_mtime_millis<P: AsRef<Path>>(path: P, mtime: i64) -> Result<()> {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .await?
+        .into_std()
+        .await;
+    file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_millis(mtime.max(0) as _))?;
+    Ok(())
+}
+
+/// creation time, in milliseconds
+pub(crate) fn ctime_millis(metadata: &Metadata) -> i64 {
+    metadata
+        .created()
+        .ok()
+        .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .unwrap_or_default()
+        .as_millis() as _
+}
+
+#[cfg(unix)]
+pub(crate) fn inode(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn inode(_metadata: &Metadata) -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(md5: &str) -> IndexEntry {
+        IndexEntry {
+            size: 10,
+            mtime: 1000,
+            inode: 42,
+            md5: md5.to_string(),
+            encrypted_fname: "e.00".to_string(),
+            txid: None,
+            synced_md5: None,
+        }
+    }
+
+    #[test]
+    fn test_lookup_stat_change() {
+        let mut index = FileIndex::new("key");
+        index.insert("pages/a.md", entry("aaa"));
+
+        assert!(index.lookup("pages/a.md", 10, 1000, 42).is_some());
+        assert!(index.lookup("pages/a.md", 11, 1000, 42).is_none());
+        assert!(index.lookup("pages/a.md", 10, 1001, 42).is_none());
+        assert!(index.lookup("pages/a.md", 10, 1000, 43).is_none());
+        assert!(index.lookup("pages/b.md", 10, 1000, 42).is_none());
+    }
+
+    #[test]
+    fn test_insert_keeps_synced_state() {
+        let mut index = FileIndex::new("key");
+        index.insert("pages/a.md", entry("aaa"));
+        index.mark_synced("pages/a.md", 7);
+
+        index.insert("pages/a.md", entry("bbb"));
+        let e = index.get("pages/a.md").unwrap();
+        assert_eq!(e.txid, Some(7));
+        assert_eq!(e.synced_md5.as_deref(), Some("aaa"));
+        assert_eq!(e.md5, "bbb");
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut index = FileIndex::new("key");
+        index.insert("pages/a.md", entry("aaa"));
+        index.save(dir.path()).await.unwrap();
+
+        let loaded = FileIndex::load(dir.path(), "key").await;
+        assert_eq!(loaded.get("pages/a.md"), Some(&entry("aaa")));
+
+        // key changed, encrypted names are stale
+        let loaded = FileIndex::load(dir.path(), "another-key").await;
+        assert!(loaded.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lock_per_graph() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let _guard = lock_index(a.path()).await;
+        // other graphs are not blocked
+        let _other = lock_index(b.path()).await;
+        assert!(
+            INDEX_LOCKS.lock().unwrap()[&dunce::canonicalize(a.path()).unwrap()]
+                .try_lock()
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.md");
+        std::fs::write(&path, "- a").unwrap();
+
+        set_mtime_millis(&path, 1_600_000_000_123).await.unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(mtime_millis(&metadata), 1_600_000_000_123);
+    }
+}
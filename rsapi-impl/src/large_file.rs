@@ -0,0 +1,136 @@
+//! Size limits of uploaded files.
+//!
+//! Files over the size limit of a graph are skipped, or deferred until the host app signals
+//! an unmetered connection. Connections are metered by default on mobile, and unmetered on
+//! desktop, so that desktop apps not tracking the network still upload deferred files.
+//!
+//! Deferred files are queued per graph in memory only. They are not recorded as synced,
+//! so the host app passes them again on later syncs, the queue only lists them meanwhile.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+#[cfg(feature = "napi")]
+use napi_derive::napi;
+use serde::{Deserialize, Serialize};
+
+/// Size limits of uploaded files, no limit by default
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct FileSizeLimits {
+    /// max file size in bytes
+    pub max_size: Option<i64>,
+    /// max file sizes by lowercase extension, e.g. "mp4", overriding `max_size`
+    pub extension_limits: Option<HashMap<String, i64>>,
+    /// defer oversized files to unmetered connections, instead of skipping them
+    pub defer: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeCheck {
+    Upload,
+    Skip,
+    Defer,
+}
+
+impl FileSizeLimits {
+    /// Size limit of a file, by its extension first
+    pub fn limit_of(&self, path: &str) -> Option<u64> {
+        let ext = path
+            .rsplit('/')
+            .next()
+            .and_then(|fname| fname.rsplit_once('.'))
+            .map(|(_, ext)| ext.to_lowercase());
+        ext.and_then(|ext| self.extension_limits.as_ref()?.get(&ext).copied())
+            .or(self.max_size)
+            .map(|limit| limit.max(0) as u64)
+    }
+
+    /// Whether a file of the size is uploaded now
+    pub fn check(&self, path: &str, size: u64, unmetered: bool) -> SizeCheck {
+        match self.limit_of(path) {
+            Some(limit) if size > limit => {
+                if !self.defer.unwrap_or_default() {
+                    SizeCheck::Skip
+                } else if unmetered {
+                    SizeCheck::Upload
+                } else {
+                    SizeCheck::Defer
+                }
+            }
+            _ => SizeCheck::Upload,
+        }
+    }
+}
+
+/// Deferred uploads, by graph uuid
+#[derive(Debug, Default)]
+pub(crate) struct DeferredUploads(Mutex<HashMap<String, BTreeSet<String>>>);
+
+impl DeferredUploads {
+    pub(crate) fn defer(&self, graph_uuid: &str, path: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(graph_uuid.to_string())
+            .or_default()
+            .insert(path.to_string());
+    }
+
+    /// Remove uploaded files from the queue
+    pub(crate) fn remove<S: AsRef<str>>(
+        &self,
+        graph_uuid: &str,
+        paths: impl IntoIterator<Item = S>,
+    ) {
+        if let Some(queue) = self.0.lock().unwrap().get_mut(graph_uuid) {
+            for path in paths {
+                queue.remove(path.as_ref());
+            }
+        }
+    }
+
+    pub(crate) fn clear(&self, graph_uuid: &str) {
+        self.0.lock().unwrap().remove(graph_uuid);
+    }
+
+    /// Deferred files of a graph, ordered by path
+    pub(crate) fn list(&self, graph_uuid: &str) -> Vec<String> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(graph_uuid)
+            .map(|queue| queue.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_size() {
+        let limits = FileSizeLimits {
+            max_size: Some(100),
+            extension_limits: Some([("mp4".to_string(), 1000)].into()),
+            defer: None,
+        };
+        assert_eq!(limits.limit_of("assets/a.MP4"), Some(1000));
+        assert_eq!(limits.limit_of("assets.mp4/a"), Some(100));
+        assert_eq!(limits.check("pages/a.md", 100, false), SizeCheck::Upload);
+        assert_eq!(limits.check("pages/a.md", 101, true), SizeCheck::Skip);
+        assert_eq!(limits.check("assets/a.mp4", 101, false), SizeCheck::Upload);
+
+        let limits = FileSizeLimits {
+            defer: Some(true),
+            ..limits
+        };
+        assert_eq!(limits.check("pages/a.md", 101, false), SizeCheck::Defer);
+        assert_eq!(limits.check("pages/a.md", 101, true), SizeCheck::Upload);
+        assert_eq!(
+            FileSizeLimits::default().check("a.mp4", u64::MAX, false),
+            SizeCheck::Upload
+        );
+    }
+}
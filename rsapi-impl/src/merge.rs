@@ -0,0 +1,548 @@
+//! Three-way merge of page files.
+//!
+//! Pages are split into outline blocks(`- ` bullets for markdown, `*` headings for org),
+//! so that concurrent edits of different blocks merge cleanly. Conflicting blocks are
+//! merged again line by line, and marked with conflict markers when still conflicting.
+
+#[cfg(feature = "napi")]
+use napi_derive::napi;
+use serde::{Deserialize, Serialize};
+
+pub const CONFLICT_MARKER_LOCAL: &str = "<<<<<<< local";
+pub const CONFLICT_MARKER_SEP: &str = "=======";
+pub const CONFLICT_MARKER_INCOMING: &str = ">>>>>>> incoming";
+
+// give up aligning when two versions differ in too many units
+const MAX_EDIT_DISTANCE: usize = 4000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFormat {
+    Markdown,
+    Org,
+}
+
+impl PageFormat {
+    pub fn from_path(file_path: &str) -> Option<Self> {
+        let t = file_path.to_lowercase();
+        if t.ends_with(".md") || t.ends_with(".markdown") {
+            Some(PageFormat::Markdown)
+        } else if t.ends_with(".org") {
+            Some(PageFormat::Org)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeConflict {
+    pub base: String,
+    pub local: String,
+    pub incoming: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeResult {
+    /// merged content, with conflict markers if any conflict
+    pub content: String,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+impl MergeResult {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+/// Merge report of a page file
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MergeReport {
+    pub path: String,
+    /// "merged", "unchanged" or "conflict"
+    pub status: String,
+    pub conflicts: i32,
+}
+
+/// Three-way merge of a page
+pub fn merge_page(base: &str, local: &str, incoming: &str, format: PageFormat) -> MergeResult {
+    if local == incoming || incoming == base {
+        return MergeResult {
+            content: local.to_string(),
+            conflicts: vec![],
+        };
+    }
+    if local == base {
+        return MergeResult {
+            content: incoming.to_string(),
+            conflicts: vec![],
+        };
+    }
+
+    let base_blocks = split_blocks(base, format);
+    let local_blocks = split_blocks(local, format);
+    let incoming_blocks = split_blocks(incoming, format);
+
+    let mut merged = Merged::default();
+    for chunk in diff3(&base_blocks, &local_blocks, &incoming_blocks) {
+        match chunk {
+            Chunk::Stable(blocks) | Chunk::Resolved(blocks) => merged.push_all(blocks),
+            Chunk::Conflict {
+                base,
+                local,
+                incoming,
+            } => {
+                // both inserted blocks at the same position, keep both
+                if base.is_empty() {
+                    merged.push_all(local);
+                    merged.push_all(incoming);
+                    continue;
+                }
+                let (base, local, incoming) = (base.concat(), local.concat(), incoming.concat());
+                merged.merge_lines(&base, &local, &incoming);
+            }
+        }
+    }
+    merged.into_result()
+}
+
+#[derive(Default)]
+struct Merged {
+    content: String,
+    conflicts: Vec<MergeConflict>,
+}
+
+impl Merged {
+    fn push(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if !self.content.is_empty() && !self.content.ends_with('\n') {
+            self.content.push('\n');
+        }
+        self.content.push_str(text);
+    }
+
+    fn push_all(&mut self, texts: &[&str]) {
+        for text in texts {
+            self.push(text);
+        }
+    }
+
+    fn merge_lines(&mut self, base: &str, local: &str, incoming: &str) {
+        let base_lines = base.split_inclusive('\n').collect::<Vec<_>>();
+        let local_lines = local.split_inclusive('\n').collect::<Vec<_>>();
+        let incoming_lines = incoming.split_inclusive('\n').collect::<Vec<_>>();
+
+        for chunk in diff3(&base_lines, &local_lines, &incoming_lines) {
+            match chunk {
+                Chunk::Stable(lines) | Chunk::Resolved(lines) => self.push_all(lines),
+                Chunk::Conflict {
+                    base,
+                    local,
+                    incoming,
+                } => {
+                    let conflict = MergeConflict {
+                        base: base.concat(),
+                        local: local.concat(),
+                        incoming: incoming.concat(),
+                    };
+                    self.push(&format!("{}\n", CONFLICT_MARKER_LOCAL));
+                    self.push(&conflict.local);
+                    self.push(&format!("{}\n", CONFLICT_MARKER_SEP));
+                    self.push(&conflict.incoming);
+                    self.push(&format!("{}\n", CONFLICT_MARKER_INCOMING));
+                    self.conflicts.push(conflict);
+                }
+            }
+        }
+    }
+
+    fn into_result(self) -> MergeResult {
+        MergeResult {
+            content: self.content,
+            conflicts: self.conflicts,
+        }
+    }
+}
+
+/// Split page into outline blocks, each block includes its continuation lines.
+/// Text before the first block(e.g. page properties) is a block itself.
+fn split_blocks(text: &str, format: PageFormat) -> Vec<&str> {
+    let mut blocks = vec![];
+    let mut start = 0;
+    let mut offset = 0;
+    let mut in_code_fence = false;
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        let is_block_start = !in_code_fence
+            && match format {
+                PageFormat::Markdown => trimmed.starts_with("- ") || trimmed.trim_end() == "-",
+                PageFormat::Org => {
+                    line.starts_with('*')
+                        && line
+                            .trim_start_matches('*')
+                            .starts_with(|c: char| c.is_whitespace())
+                }
+            };
+        if trimmed.trim_start_matches("- ").starts_with("```") {
+            in_code_fence = !in_code_fence;
+        }
+        if is_block_start && offset > start {
+            blocks.push(&text[start..offset]);
+            start = offset;
+        }
+        offset += line.len();
+    }
+    if offset > start {
+        blocks.push(&text[start..offset]);
+    }
+    blocks
+}
+
+#[derive(Debug, PartialEq)]
+enum Chunk<'a, 'b> {
+    /// unchanged in both versions
+    Stable(&'b [&'a str]),
+    /// changed in one version only, or same change in both
+    Resolved(&'b [&'a str]),
+    Conflict {
+        base: &'b [&'a str],
+        local: &'b [&'a str],
+        incoming: &'b [&'a str],
+    },
+}
+
+/// Classic diff3 over units(blocks or lines)
+fn diff3<'a, 'b>(o: &'b [&'a str], a: &'b [&'a str], b: &'b [&'a str]) -> Vec<Chunk<'a, 'b>> {
+    let mut ma = vec![None; o.len()];
+    for (i, j) in matches(o, a) {
+        ma[i] = Some(j);
+    }
+    let mut mb = vec![None; o.len()];
+    for (i, j) in matches(o, b) {
+        mb[i] = Some(j);
+    }
+
+    let mut chunks = vec![];
+    let (mut io, mut ia, mut ib) = (0, 0, 0);
+    loop {
+        let mut n = 0;
+        while io + n < o.len() && ma[io + n] == Some(ia + n) && mb[io + n] == Some(ib + n) {
+            n += 1;
+        }
+        if n > 0 {
+            chunks.push(Chunk::Stable(&o[io..io + n]));
+            io += n;
+            ia += n;
+            ib += n;
+            continue;
+        }
+
+        let next = (io..o.len()).find(|&i| ma[i].is_some() && mb[i].is_some());
+        let (no, na, nb) = match next {
+            Some(i) => (i, ma[i].unwrap(), mb[i].unwrap()),
+            None => (o.len(), a.len(), b.len()),
+        };
+        let (co, ca, cb) = (&o[io..no], &a[ia..na], &b[ib..nb]);
+        if co.is_empty() && ca.is_empty() && cb.is_empty() {
+            break;
+        }
+        if ca == co {
+            chunks.push(Chunk::Resolved(cb));
+        } else if cb == co || ca == cb {
+            chunks.push(Chunk::Resolved(ca));
+        } else {
+            chunks.push(Chunk::Conflict {
+                base: co,
+                local: ca,
+                incoming: cb,
+            });
+        }
+        (io, ia, ib) = (no, na, nb);
+    }
+    chunks
+}
+
+/// Matched index pairs of longest common subsequence, Myers' algorithm
+fn matches<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    let mut pairs = (0..prefix).map(|i| (i, i)).collect::<Vec<_>>();
+    let (ma, mb) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    pairs.extend(
+        myers(ma, mb)
+            .into_iter()
+            .map(|(i, j)| (i + prefix, j + prefix)),
+    );
+    pairs.extend((0..suffix).map(|i| (a.len() - suffix + i, b.len() - suffix + i)));
+    pairs
+}
+
+fn myers<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let mut pairs = vec![];
+    if align(a, b, (0, 0), &mut pairs).is_none() {
+        log::warn!("versions differ too much, give up aligning");
+        return vec![];
+    }
+    pairs
+}
+
+/// Linear space refinement: split at the middle snake and align both halves,
+/// `(i, j)` is the offset of `a` and `b` in the original sequences
+fn align<T: PartialEq>(
+    a: &[T],
+    b: &[T],
+    (i, j): (usize, usize),
+    pairs: &mut Vec<(usize, usize)>,
+) -> Option<()> {
+    if a.is_empty() || b.is_empty() {
+        return Some(());
+    }
+    let (d, ((x0, y0), (x1, y1))) = middle_snake(a, b)?;
+    if d <= 1 {
+        // at most one unit inserted or deleted, common prefix and suffix are all the matches
+        let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+        let suffix = a.len().min(b.len()) - prefix;
+        pairs.extend((0..prefix).map(|k| (i + k, j + k)));
+        pairs.extend((0..suffix).map(|k| (i + a.len() - suffix + k, j + b.len() - suffix + k)));
+        return Some(());
+    }
+    align(&a[..x0], &b[..y0], (i, j), pairs)?;
+    pairs.extend((0..x1 - x0).map(|k| (i + x0 + k, j + y0 + k)));
+    align(&a[x1..], &b[y1..], (i + x1, j + y1), pairs)
+}
+
+// start and end of a diagonal run
+type Snake = ((usize, usize), (usize, usize));
+
+/// Edit distance and the middle snake, searching from both ends at once.
+/// None when the distance exceeds `MAX_EDIT_DISTANCE`.
+fn middle_snake<T: PartialEq>(a: &[T], b: &[T]) -> Option<(usize, Snake)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let max = (n + m + 1) / 2;
+    let offset = max + 1;
+    // furthest x of each diagonal, forward from the start and backward from the end
+    let mut vf = vec![0isize; 2 * max as usize + 3];
+    let mut vb = vf.clone();
+
+    for d in 0..=max {
+        if (2 * d - 1).max(0) as usize > MAX_EDIT_DISTANCE {
+            return None;
+        }
+        let mut k = -d;
+        while k <= d {
+            let idx = (k + offset) as usize;
+            let x0 = if k == -d || (k != d && vf[idx - 1] < vf[idx + 1]) {
+                vf[idx + 1]
+            } else {
+                vf[idx - 1] + 1
+            };
+            let y0 = x0 - k;
+            let (mut x, mut y) = (x0, y0);
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            vf[idx] = x;
+            let kb = delta - k;
+            if odd && (-(d - 1)..d).contains(&kb) && x + vb[(kb + offset) as usize] >= n {
+                let snake = ((x0 as usize, y0 as usize), (x as usize, y as usize));
+                return Some(((2 * d - 1) as usize, snake));
+            }
+            k += 2;
+        }
+
+        let mut k = -d;
+        while k <= d {
+            let idx = (k + offset) as usize;
+            let x0 = if k == -d || (k != d && vb[idx - 1] < vb[idx + 1]) {
+                vb[idx + 1]
+            } else {
+                vb[idx - 1] + 1
+            };
+            let y0 = x0 - k;
+            let (mut x, mut y) = (x0, y0);
+            while x < n && y < m && a[(n - x - 1) as usize] == b[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            vb[idx] = x;
+            let kf = delta - k;
+            if !odd && (-d..=d).contains(&kf) && x + vf[(kf + offset) as usize] >= n {
+                let snake = (
+                    ((n - x) as usize, (m - y) as usize),
+                    ((n - x0) as usize, (m - y0) as usize),
+                );
+                return Some(((2 * d) as usize, snake));
+            }
+            k += 2;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let a = ["a", "b", "c", "a", "b", "b", "a"];
+        let b = ["c", "b", "a", "b", "a", "c"];
+        let pairs = matches(&a, &b);
+        assert_eq!(pairs.len(), 4);
+        assert!(pairs.iter().all(|&(i, j)| a[i] == b[j]));
+        assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+    }
+
+    #[test]
+    fn test_matches_longest() {
+        fn lcs_len(a: &[u8], b: &[u8]) -> usize {
+            let mut dp = vec![vec![0; b.len() + 1]; a.len() + 1];
+            for (i, x) in a.iter().enumerate() {
+                for (j, y) in b.iter().enumerate() {
+                    dp[i + 1][j + 1] = if x == y {
+                        dp[i][j] + 1
+                    } else {
+                        dp[i][j + 1].max(dp[i + 1][j])
+                    };
+                }
+            }
+            dp[a.len()][b.len()]
+        }
+
+        let mut seed = 42u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8 % 3
+        };
+        for _ in 0..200 {
+            let a = (0..next() * 5).map(|_| next()).collect::<Vec<_>>();
+            let b = (0..next() * 5).map(|_| next()).collect::<Vec<_>>();
+            let pairs = matches(&a, &b);
+            assert_eq!(pairs.len(), lcs_len(&a, &b), "{a:?} {b:?}");
+            assert!(pairs.iter().all(|&(i, j)| a[i] == b[j]));
+            assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+        }
+    }
+
+    #[test]
+    fn test_matches_large() {
+        let a = (0..20000).map(|i| i.to_string()).collect::<Vec<_>>();
+        let b = (0..20000)
+            .map(|i| {
+                if i % 10 == 0 {
+                    format!("changed {i}")
+                } else {
+                    i.to_string()
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(matches(&a, &b).len(), 18000);
+
+        // too different, no alignment
+        let c = (0..20000).map(|i| format!("other {i}")).collect::<Vec<_>>();
+        assert!(matches(&a, &c).is_empty());
+    }
+
+    #[test]
+    fn test_split_blocks() {
+        let page = "title:: Test\n\n- block 1\n  continued\n\t- child\n- block 2";
+        assert_eq!(
+            split_blocks(page, PageFormat::Markdown),
+            vec![
+                "title:: Test\n\n",
+                "- block 1\n  continued\n",
+                "\t- child\n",
+                "- block 2"
+            ]
+        );
+
+        let page = "* heading\ntext\n** sub\n* heading 2\n";
+        assert_eq!(
+            split_blocks(page, PageFormat::Org),
+            vec!["* heading\ntext\n", "** sub\n", "* heading 2\n"]
+        );
+    }
+
+    #[test]
+    fn test_code_fence_is_not_split() {
+        let page = "- code\n  ```\n  - not a block\n  ```\n- next\n";
+        assert_eq!(
+            split_blocks(page, PageFormat::Markdown),
+            vec!["- code\n  ```\n  - not a block\n  ```\n", "- next\n"]
+        );
+    }
+
+    #[test]
+    fn test_merge_different_blocks() {
+        let base = "- a\n- b\n- c\n";
+        let local = "- a local\n- b\n- c\n";
+        let incoming = "- a\n- b\n- c incoming\n";
+
+        let merged = merge_page(base, local, incoming, PageFormat::Markdown);
+        assert!(merged.is_clean());
+        assert_eq!(merged.content, "- a local\n- b\n- c incoming\n");
+    }
+
+    #[test]
+    fn test_merge_appended_blocks() {
+        let base = "- a\n";
+        let local = "- a\n- local\n";
+        let incoming = "- a\n- incoming\n";
+
+        let merged = merge_page(base, local, incoming, PageFormat::Markdown);
+        assert!(merged.is_clean());
+        assert_eq!(merged.content, "- a\n- local\n- incoming\n");
+    }
+
+    #[test]
+    fn test_merge_same_block_different_lines() {
+        let base = "- a\n  line 1\n  line 2\n  line 3\n";
+        let local = "- a\n  line 1 local\n  line 2\n  line 3\n";
+        let incoming = "- a\n  line 1\n  line 2\n  line 3 incoming\n";
+
+        let merged = merge_page(base, local, incoming, PageFormat::Markdown);
+        assert!(merged.is_clean());
+        assert_eq!(
+            merged.content,
+            "- a\n  line 1 local\n  line 2\n  line 3 incoming\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_conflict() {
+        let base = "- a\n- b\n";
+        let local = "- a local\n- b\n";
+        let incoming = "- a incoming\n- b\n";
+
+        let merged = merge_page(base, local, incoming, PageFormat::Markdown);
+        assert_eq!(merged.conflicts.len(), 1);
+        assert_eq!(
+            merged.content,
+            "<<<<<<< local\n- a local\n=======\n- a incoming\n>>>>>>> incoming\n- b\n"
+        );
+        assert_eq!(merged.conflicts[0].base, "- a\n");
+    }
+
+    #[test]
+    fn test_merge_deleted_block() {
+        let base = "- a\n- b\n- c\n";
+        let local = "- a\n- c\n";
+        let incoming = "- a\n- b\n- c\n- d\n";
+
+        let merged = merge_page(base, local, incoming, PageFormat::Markdown);
+        assert!(merged.is_clean());
+        assert_eq!(merged.content, "- a\n- c\n- d\n");
+    }
+}
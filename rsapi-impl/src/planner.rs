@@ -0,0 +1,640 @@
+//! Sync planner, three-way diff of local files, remote files and base versions.
+//!
+//! The planner only computes what should be done, executing the plan is left to
+//! the bindings, so that every platform makes the same decisions.
+
+use std::collections::{BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+use sync::types::{FileObject, Transaction};
+
+use crate::error::Result;
+use crate::graph::{FileMeta, Graph};
+
+/// Remote state of a file, with decrypted path
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteFile {
+    pub path: String,
+    /// md5 of plain content, `None` when the server doesn't know it
+    pub checksum: Option<String>,
+    pub size: i64,
+    /// last modified time, in milliseconds
+    pub last_modified: i64,
+}
+
+impl RemoteFile {
+    /// Convert from `get_all_files` result, decrypting file name
+    pub fn from_file_object(graph: &Graph, obj: &FileObject) -> Result<Self> {
+        Ok(Self {
+            path: graph.decrypt_filename(strip_remote_prefix(&obj.key))?,
+            checksum: obj.checksum.clone(),
+            size: obj.size as _,
+            last_modified: obj.last_modified.timestamp_millis(),
+        })
+    }
+}
+
+/// Remote change since a txid, from `get_diff` transactions
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteChange {
+    Update {
+        path: String,
+        checksum: Option<String>,
+    },
+    Delete {
+        path: String,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+}
+
+impl RemoteChange {
+    /// Parse a diff transaction.
+    ///
+    /// TXContent is a list of `[to-path, from-path, checksum]`, paths are encrypted
+    /// and prefixed with the graph uuid.
+    pub fn from_transaction(graph: &Graph, txn: &Transaction) -> Result<Vec<Self>> {
+        let items: Vec<Vec<Option<String>>> = match serde_json::from_str(&txn.content) {
+            Ok(items) => items,
+            Err(e) => {
+                log::warn!("cannot parse transaction {}: {}", txn.txid, e);
+                return Ok(vec![]);
+            }
+        };
+        let decrypt = |p: &Option<String>| -> Result<Option<String>> {
+            match p.as_deref() {
+                Some(p) if !p.is_empty() => {
+                    Ok(Some(graph.decrypt_filename(strip_remote_prefix(p))?))
+                }
+                _ => Ok(None),
+            }
+        };
+
+        let mut changes = vec![];
+        for item in &items {
+            let to = decrypt(item.first().unwrap_or(&None))?;
+            let from = decrypt(item.get(1).unwrap_or(&None))?;
+            let checksum = item.get(2).cloned().flatten();
+            let change = match (&*txn.r#type, to, from) {
+                ("update_files", Some(path), _) => RemoteChange::Update { path, checksum },
+                ("delete_files", Some(path), _) => RemoteChange::Delete { path },
+                ("rename_file", Some(to), Some(from)) => RemoteChange::Rename { from, to },
+                (typ, _, _) => {
+                    log::warn!("unknown transaction item {} {:?}", typ, item);
+                    continue;
+                }
+            };
+            changes.push(change);
+        }
+        Ok(changes)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictKind {
+    /// both sides changed the file
+    BothModified,
+    /// local changed, remote deleted
+    LocalModifiedRemoteDeleted,
+    /// remote changed, local deleted
+    RemoteModifiedLocalDeleted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum SyncAction {
+    Upload {
+        path: String,
+    },
+    Download {
+        path: String,
+    },
+    DeleteLocal {
+        path: String,
+    },
+    DeleteRemote {
+        path: String,
+    },
+    /// file renamed remotely, rename local file
+    RenameLocal {
+        from: String,
+        to: String,
+    },
+    /// file renamed locally, rename remote file
+    RenameRemote {
+        from: String,
+        to: String,
+    },
+    Conflict {
+        path: String,
+        kind: ConflictKind,
+    },
+}
+
+impl SyncAction {
+    /// The file path this action writes to
+    pub fn path(&self) -> &str {
+        match self {
+            SyncAction::Upload { path }
+            | SyncAction::Download { path }
+            | SyncAction::DeleteLocal { path }
+            | SyncAction::DeleteRemote { path }
+            | SyncAction::Conflict { path, .. } => path,
+            SyncAction::RenameLocal { to, .. } | SyncAction::RenameRemote { to, .. } => to,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SyncPlan {
+    /// remote txid the plan is based on
+    pub txid: i64,
+    pub actions: Vec<SyncAction>,
+}
+
+impl SyncPlan {
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    pub fn conflicts(&self) -> impl Iterator<Item = &SyncAction> {
+        self.actions
+            .iter()
+            .filter(|a| matches!(a, SyncAction::Conflict { .. }))
+    }
+}
+
+/// Three-way decision for a single file, by content checksum.
+/// `None` means the file doesn't exist on that side.
+fn decide(
+    path: &str,
+    local: Option<&str>,
+    remote: Option<&str>,
+    base: Option<&str>,
+) -> Option<SyncAction> {
+    let path = path.to_string();
+    match (local, remote) {
+        (None, None) => None,
+        (Some(l), Some(r)) if l == r => None,
+        (Some(l), None) => match base {
+            None => Some(SyncAction::Upload { path }),
+            Some(b) if b == l => Some(SyncAction::DeleteLocal { path }),
+            Some(_) => Some(SyncAction::Conflict {
+                path,
+                kind: ConflictKind::LocalModifiedRemoteDeleted,
+            }),
+        },
+        (None, Some(r)) => match base {
+            None => Some(SyncAction::Download { path }),
+            Some(b) if b == r => Some(SyncAction::DeleteRemote { path }),
+            Some(_) => Some(SyncAction::Conflict {
+                path,
+                kind: ConflictKind::RemoteModifiedLocalDeleted,
+            }),
+        },
+        (Some(l), Some(r)) => match base {
+            Some(b) if b == l => Some(SyncAction::Download { path }),
+            Some(b) if b == r => Some(SyncAction::Upload { path }),
+            _ => Some(SyncAction::Conflict {
+                path,
+                kind: ConflictKind::BothModified,
+            }),
+        },
+    }
+}
+
+// unknown remote checksum never equals a local one, i.e. files listed without a checksum
+// are planned as changed remotely: downloaded, or conflicts if changed locally as well
+const UNKNOWN_CHECKSUM: &str = "";
+
+/// Plan from full remote file listing.
+///
+/// `base` is `path => md5` of files at last sync.
+/// Remote files without a checksum are assumed changed, see `FileObject::checksum`.
+pub fn plan_with_remote_files<'a>(
+    local: impl IntoIterator<Item = &'a FileMeta>,
+    remote: impl IntoIterator<Item = &'a RemoteFile>,
+    base: &HashMap<String, String>,
+    txid: i64,
+) -> SyncPlan {
+    let local: HashMap<&str, &str> = local
+        .into_iter()
+        .map(|m| (&*m.normalized_fname, &*m.md5))
+        .collect();
+    let remote: HashMap<&str, &str> = remote
+        .into_iter()
+        .map(|f| (&*f.path, f.checksum.as_deref().unwrap_or(UNKNOWN_CHECKSUM)))
+        .collect();
+
+    let paths = local
+        .keys()
+        .chain(remote.keys())
+        .copied()
+        .chain(base.keys().map(|p| &**p))
+        .collect::<BTreeSet<_>>();
+
+    let actions = paths
+        .into_iter()
+        .filter_map(|p| {
+            decide(
+                p,
+                local.get(p).copied(),
+                remote.get(p).copied(),
+                base.get(p).map(|s| &**s),
+            )
+        })
+        .collect();
+
+    SyncPlan {
+        txid,
+        actions: detect_renames(actions, &local, &remote),
+    }
+}
+
+/// Plan from remote changes since last sync.
+///
+/// Files not touched by `changes` are assumed unchanged remotely, i.e. at their base version.
+pub fn plan_with_remote_changes<'a>(
+    local: impl IntoIterator<Item = &'a FileMeta>,
+    changes: impl IntoIterator<Item = &'a RemoteChange>,
+    base: &HashMap<String, String>,
+    txid: i64,
+) -> SyncPlan {
+    let local: HashMap<&str, &str> = local
+        .into_iter()
+        .map(|m| (&*m.normalized_fname, &*m.md5))
+        .collect();
+
+    // path => Some(checksum) | None(deleted), replay changes in order
+    let mut touched: HashMap<&str, Option<&str>> = HashMap::new();
+    for change in changes {
+        match change {
+            RemoteChange::Update { path, checksum } => {
+                touched.insert(path, Some(checksum.as_deref().unwrap_or(UNKNOWN_CHECKSUM)));
+            }
+            RemoteChange::Delete { path } => {
+                touched.insert(path, None);
+            }
+            RemoteChange::Rename { from, to } => {
+                let content = match touched.get(&**from) {
+                    Some(state) => *state,
+                    None => base.get(from).map(|s| &**s),
+                };
+                touched.insert(from, None);
+                touched.insert(to, content);
+            }
+        }
+    }
+
+    let remote: HashMap<&str, &str> = local
+        .keys()
+        .copied()
+        .chain(base.keys().map(|p| &**p))
+        .chain(touched.keys().copied())
+        .filter_map(|p| match touched.get(p) {
+            Some(state) => state.map(|c| (p, c)),
+            None => base.get(p).map(|c| (p, &**c)),
+        })
+        .collect();
+
+    let paths = local
+        .keys()
+        .chain(remote.keys())
+        .chain(touched.keys())
+        .copied()
+        .collect::<BTreeSet<_>>();
+
+    let actions = paths
+        .into_iter()
+        .filter_map(|p| {
+            decide(
+                p,
+                local.get(p).copied(),
+                remote.get(p).copied(),
+                base.get(p).map(|s| &**s),
+            )
+        })
+        .collect();
+
+    SyncPlan {
+        txid,
+        actions: detect_renames(actions, &local, &remote),
+    }
+}
+
+/// Merge delete + create pairs of identical content into renames
+fn detect_renames(
+    actions: Vec<SyncAction>,
+    local: &HashMap<&str, &str>,
+    remote: &HashMap<&str, &str>,
+) -> Vec<SyncAction> {
+    let mut actions = actions;
+
+    // remote renamed: local file deleted, same content downloaded to new path
+    let mut i = 0;
+    while i < actions.len() {
+        if let SyncAction::DeleteLocal { path: from } = &actions[i] {
+            let checksum = local.get(&**from).copied();
+            let pos = actions.iter().position(|a| match a {
+                SyncAction::Download { path } => {
+                    checksum.is_some() && remote.get(&**path).copied() == checksum
+                }
+                _ => false,
+            });
+            if let Some(pos) = pos {
+                let from = from.clone();
+                let to = actions[pos].path().to_string();
+                actions[i] = SyncAction::RenameLocal { from, to };
+                actions.remove(pos);
+                if pos < i {
+                    i -= 1;
+                }
+            }
+        }
+        i += 1;
+    }
+
+    // local renamed: remote file deleted, same content uploaded to new path
+    let mut i = 0;
+    while i < actions.len() {
+        if let SyncAction::DeleteRemote { path: from } = &actions[i] {
+            let checksum = remote.get(&**from).copied();
+            let pos = actions.iter().position(|a| match a {
+                SyncAction::Upload { path } => {
+                    checksum.is_some() && local.get(&**path).copied() == checksum
+                }
+                _ => false,
+            });
+            if let Some(pos) = pos {
+                let from = from.clone();
+                let to = actions[pos].path().to_string();
+                actions[i] = SyncAction::RenameRemote { from, to };
+                actions.remove(pos);
+                if pos < i {
+                    i -= 1;
+                }
+            }
+        }
+        i += 1;
+    }
+
+    actions
+}
+
+/// Remote keys are prefixed, e.g. `<graph-uuid>/e.xxxx`
+pub(crate) fn strip_remote_prefix(key: &str) -> &str {
+    key.rsplit('/').next().unwrap_or(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(path: &str, md5: &str) -> FileMeta {
+        FileMeta {
+            size: 0,
+            mtime: 0,
+            ctime: 0,
+            md5: md5.to_string(),
+            fname: path.to_string(),
+            incoming_fname: path.to_string(),
+            normalized_fname: path.to_string(),
+            encrypted_fname: String::new(),
+        }
+    }
+
+    fn remote(path: &str, md5: &str) -> RemoteFile {
+        RemoteFile {
+            path: path.to_string(),
+            checksum: Some(md5.to_string()),
+            size: 0,
+            last_modified: 0,
+        }
+    }
+
+    fn base(items: &[(&str, &str)]) -> HashMap<String, String> {
+        items
+            .iter()
+            .map(|(p, m)| (p.to_string(), m.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_three_way_decisions() {
+        let local = [
+            local("same.md", "1"),
+            local("new-local.md", "4"),
+            local("local-changed.md", "2"),
+            local("remote-changed.md", "1"),
+            local("both-changed.md", "2"),
+            local("remote-deleted.md", "7"),
+        ];
+        let remote = [
+            remote("same.md", "1"),
+            remote("new-remote.md", "5"),
+            remote("local-changed.md", "1"),
+            remote("remote-changed.md", "2"),
+            remote("both-changed.md", "3"),
+            remote("local-deleted.md", "6"),
+        ];
+        let base = base(&[
+            ("same.md", "1"),
+            ("local-changed.md", "1"),
+            ("remote-changed.md", "1"),
+            ("both-changed.md", "1"),
+            ("remote-deleted.md", "7"),
+            ("local-deleted.md", "6"),
+        ]);
+
+        let plan = plan_with_remote_files(&local, &remote, &base, 10);
+        assert_eq!(plan.txid, 10);
+        assert_eq!(
+            plan.actions,
+            vec![
+                SyncAction::Conflict {
+                    path: "both-changed.md".into(),
+                    kind: ConflictKind::BothModified
+                },
+                SyncAction::Upload {
+                    path: "local-changed.md".into()
+                },
+                SyncAction::DeleteRemote {
+                    path: "local-deleted.md".into()
+                },
+                SyncAction::Upload {
+                    path: "new-local.md".into()
+                },
+                SyncAction::Download {
+                    path: "new-remote.md".into()
+                },
+                SyncAction::Download {
+                    path: "remote-changed.md".into()
+                },
+                SyncAction::DeleteLocal {
+                    path: "remote-deleted.md".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rename_detection() {
+        let local = [local("pages/new.md", "1")];
+        let remote = [remote("pages/old.md", "1")];
+        let base = base(&[("pages/old.md", "1")]);
+
+        let plan = plan_with_remote_files(&local, &remote, &base, 0);
+        assert_eq!(
+            plan.actions,
+            vec![SyncAction::RenameRemote {
+                from: "pages/old.md".into(),
+                to: "pages/new.md".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_plan_with_remote_changes() {
+        let local = [
+            local("a.md", "1"),
+            local("b.md", "1"),
+            local("c.md", "2"),
+            local("untouched.md", "1"),
+        ];
+        let base = base(&[
+            ("a.md", "1"),
+            ("b.md", "1"),
+            ("c.md", "1"),
+            ("untouched.md", "1"),
+        ]);
+        let changes = [
+            RemoteChange::Update {
+                path: "a.md".into(),
+                checksum: Some("2".into()),
+            },
+            RemoteChange::Rename {
+                from: "b.md".into(),
+                to: "b2.md".into(),
+            },
+            RemoteChange::Update {
+                path: "c.md".into(),
+                checksum: Some("3".into()),
+            },
+        ];
+
+        let plan = plan_with_remote_changes(&local, &changes, &base, 5);
+        assert_eq!(
+            plan.actions,
+            vec![
+                SyncAction::Download {
+                    path: "a.md".into()
+                },
+                SyncAction::RenameLocal {
+                    from: "b.md".into(),
+                    to: "b2.md".into()
+                },
+                SyncAction::Conflict {
+                    path: "c.md".into(),
+                    kind: ConflictKind::BothModified
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_plan_sync_with_mock_server() {
+        use lsq_encryption::md5_hexdigest;
+        use serde_json::json;
+        use sync::mock::{json_body, MockServer};
+
+        let (secret_key, public_key) = lsq_encryption::keygen();
+        let ctx = crate::RsapiContext::new();
+        ctx.set_env("graph", "dev", &secret_key, &public_key, &[], None, None)
+            .unwrap();
+        let mut graph = (*ctx.get_graph("graph").unwrap()).clone();
+
+        let key = |p: &str| format!("graph/{}", graph.encrypt_filename(p).unwrap());
+        let object = |p: &str, content: &str| {
+            json!({
+                "ETag": "",
+                "Key": key(p),
+                "LastModified": "2024-01-01T00:00:00Z",
+                "Size": content.len(),
+                "Checksum": md5_hexdigest(content.as_bytes()),
+            })
+        };
+        let objects = json!([object("pages/a.md", "- a"), object("pages/c.md", "- c")]);
+        let txn = json!([[key("pages/a.md"), null, md5_hexdigest(b"- a2")]]).to_string();
+        let server = MockServer::start(move |req| match &*req.path {
+            "get_graph" => json_body(json!({"GraphUUID": "graph", "TXId": 3})),
+            "get_all_files" => json_body(json!({ "Objects": objects })),
+            "get_diff" => json_body(json!({"Transactions": [
+                {"TXId": 4, "TXType": "update_files", "TXContent": txn}
+            ]})),
+            _ => json_body(json!({"message": "not found"})),
+        })
+        .await;
+        graph.endpoint = server.endpoint();
+
+        let dir = tempfile::tempdir().unwrap();
+        let base_dir = dir.path().join("logseq/version-files/base/pages");
+        std::fs::create_dir_all(dir.path().join("pages")).unwrap();
+        std::fs::create_dir_all(&base_dir).unwrap();
+        std::fs::write(dir.path().join("pages/a.md"), "- a").unwrap();
+        std::fs::write(dir.path().join("pages/b.md"), "- b").unwrap();
+        std::fs::write(base_dir.join("a.md"), "- a").unwrap();
+
+        // full listing
+        let plan = graph.plan_sync(dir.path(), 0, "token").await.unwrap();
+        assert_eq!(plan.txid, 3);
+        assert_eq!(
+            plan.actions,
+            vec![
+                SyncAction::Upload {
+                    path: "pages/b.md".into()
+                },
+                SyncAction::Download {
+                    path: "pages/c.md".into()
+                },
+            ]
+        );
+
+        // changes since txid
+        let plan = graph.plan_sync(dir.path(), 3, "token").await.unwrap();
+        assert_eq!(plan.txid, 4);
+        assert_eq!(
+            plan.actions,
+            vec![
+                SyncAction::Download {
+                    path: "pages/a.md".into()
+                },
+                SyncAction::Upload {
+                    path: "pages/b.md".into()
+                },
+            ]
+        );
+        let diff = &server.requests_of("get_diff")[0];
+        assert_eq!(diff.json()["FromTXId"], 3);
+        assert_eq!(diff.json()["GraphUUID"], "graph");
+    }
+
+    #[test]
+    fn test_unknown_remote_checksum() {
+        let local = [local("a.md", "1")];
+        let remote = [RemoteFile {
+            checksum: None,
+            ..remote("a.md", "")
+        }];
+
+        let plan = plan_with_remote_files(&local, &remote, &base(&[("a.md", "1")]), 0);
+        assert_eq!(
+            plan.actions,
+            vec![SyncAction::Download {
+                path: "a.md".into()
+            }]
+        );
+    }
+}
@@ -0,0 +1,292 @@
+//! Progress of file transfers.
+//!
+//! Each transfer reports its own progress, throttled to every 10 percent. A batch operation
+//! also reports its aggregate progress: files and bytes done, throughput and ETA.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "napi")]
+use napi_derive::napi;
+use serde::{Deserialize, Serialize};
+
+use crate::context::Shared;
+
+// min interval of batch progress notifications
+const BATCH_NOTIFY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Download/Upload Progress Info
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Progress {
+    #[serde(rename = "graphUUID")]
+    pub graph_uuid: String,
+    pub file: String,
+    /// "download" or "upload" of a file, "batch-download" or "batch-upload" of a batch
+    pub r#type: &'static str,
+    pub progress: i64,
+    pub total: i64,
+    pub percent: i32,
+    /// aggregate progress, for batch types
+    pub batch: Option<BatchProgress>,
+}
+
+/// Aggregate progress of a batch operation
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BatchProgress {
+    pub operation_id: String,
+    pub files_done: i64,
+    pub files_total: i64,
+    pub bytes_done: i64,
+    /// bytes of started transfers, grows as transfers start
+    pub bytes_total: i64,
+    /// bytes per second
+    pub throughput: f64,
+    /// estimated seconds remaining, none until throughput is known
+    pub eta: Option<f64>,
+}
+
+fn percent(progress: i64, total: i64) -> i32 {
+    if total == 0 {
+        return 100;
+    }
+    (progress as f64 / total as f64 * 100.0) as i32
+}
+
+impl Progress {
+    pub fn download(graph_uuid: &str, file: &str, progress: i64, total: i64) -> Self {
+        Self {
+            graph_uuid: graph_uuid.into(),
+            file: file.into(),
+            r#type: "download",
+            progress,
+            total,
+            percent: percent(progress, total),
+            batch: None,
+        }
+    }
+
+    pub fn upload(graph_uuid: &str, file: &str, progress: i64, total: i64) -> Self {
+        Self {
+            graph_uuid: graph_uuid.into(),
+            file: file.into(),
+            r#type: "upload",
+            progress,
+            total,
+            percent: percent(progress, total),
+            batch: None,
+        }
+    }
+
+    /// type: "download" or "upload"
+    pub fn batch(graph_uuid: &str, r#type: &str, batch: BatchProgress) -> Self {
+        Self {
+            graph_uuid: graph_uuid.into(),
+            file: String::new(),
+            r#type: if r#type == "upload" {
+                "batch-upload"
+            } else {
+                "batch-download"
+            },
+            progress: batch.bytes_done,
+            total: batch.bytes_total,
+            percent: percent(batch.bytes_done, batch.bytes_total),
+            batch: Some(batch),
+        }
+    }
+}
+
+#[derive(Default)]
+struct BatchState {
+    files_total: usize,
+    // file => (bytes done, bytes total)
+    files: HashMap<String, (usize, usize)>,
+    files_done: usize,
+    last_notify: Option<Instant>,
+    // bytes done at last notification, for throughput
+    last_bytes: usize,
+    throughput: f64,
+}
+
+/// Progress tracker of a batch of downloads or uploads
+pub(crate) struct BatchTracker {
+    shared: Arc<Shared>,
+    graph_uuid: String,
+    operation_id: String,
+    // "download" or "upload"
+    r#type: &'static str,
+    started: Instant,
+    state: Mutex<BatchState>,
+}
+
+impl BatchTracker {
+    pub(crate) fn new(
+        shared: Arc<Shared>,
+        graph_uuid: &str,
+        operation_id: &str,
+        r#type: &'static str,
+    ) -> Arc<Self> {
+        Arc::new(BatchTracker {
+            shared,
+            graph_uuid: graph_uuid.to_string(),
+            operation_id: operation_id.to_string(),
+            r#type,
+            started: Instant::now(),
+            state: Default::default(),
+        })
+    }
+
+    /// Start notifying batch progress, once number of files is known
+    pub(crate) fn start(&self, files_total: usize) {
+        self.state.lock().unwrap().files_total = files_total;
+    }
+
+    /// Progress callback of a file transfer, notifying per-file and batch progress
+    pub(crate) fn file_callback(
+        self: &Arc<Self>,
+        file_path: &str,
+    ) -> impl Fn(usize, usize) + Send + Sync + 'static {
+        let tracker = self.clone();
+        let file_path = file_path.to_string();
+        // last notified tenth plus one, 0 before any notification
+        let last = AtomicUsize::new(0);
+        move |bytes, total| {
+            let tenth = if total == 0 { 10 } else { bytes * 10 / total };
+            if last.swap(tenth + 1, Ordering::Relaxed) != tenth + 1 || bytes == total {
+                let progress = match tracker.r#type {
+                    "upload" => Progress::upload,
+                    _ => Progress::download,
+                };
+                tracker.shared.notify_progress(progress(
+                    &tracker.graph_uuid,
+                    &file_path,
+                    bytes as _,
+                    total as _,
+                ));
+                log::debug!(
+                    "{} progress: {}/{} {:?}",
+                    tracker.r#type,
+                    bytes,
+                    total,
+                    file_path
+                );
+            }
+            tracker.update(&file_path, bytes, total);
+        }
+    }
+
+    fn update(&self, file_path: &str, bytes: usize, total: usize) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .files
+            .insert(file_path.to_string(), (bytes, total.max(bytes)));
+        self.notify(&mut state, false);
+    }
+
+    /// A file is done, whether succeeded or failed
+    pub(crate) fn file_done(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.files_done += 1;
+        let finished = state.files_done >= state.files_total;
+        self.notify(&mut state, finished);
+    }
+
+    fn notify(&self, state: &mut BatchState, force: bool) {
+        if state.files_total == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let since_last = now.duration_since(state.last_notify.unwrap_or(self.started));
+        if !force && since_last < BATCH_NOTIFY_INTERVAL {
+            return;
+        }
+
+        let (bytes_done, bytes_total) = state
+            .files
+            .values()
+            .fold((0, 0), |(done, total), (d, t)| (done + d, total + t));
+        if !since_last.is_zero() {
+            let current =
+                bytes_done.saturating_sub(state.last_bytes) as f64 / since_last.as_secs_f64();
+            // smooth out bursts of chunks
+            state.throughput = if state.last_notify.is_none() {
+                current
+            } else {
+                state.throughput * 0.7 + current * 0.3
+            };
+        }
+        state.last_notify = Some(now);
+        state.last_bytes = bytes_done;
+
+        // sizes of files not started are estimated by the average of started ones
+        let files_started = state.files.len().max(state.files_done);
+        let estimated_total = if files_started == 0 {
+            0.0
+        } else {
+            bytes_total as f64 * state.files_total as f64 / files_started as f64
+        };
+        let eta = (state.throughput > 0.0)
+            .then(|| (estimated_total - bytes_done as f64).max(0.0) / state.throughput);
+
+        self.shared.notify_progress(Progress::batch(
+            &self.graph_uuid,
+            self.r#type,
+            BatchProgress {
+                operation_id: self.operation_id.clone(),
+                files_done: state.files_done as _,
+                files_total: state.files_total as _,
+                bytes_done: bytes_done as _,
+                bytes_total: bytes_total as _,
+                throughput: state.throughput,
+                eta,
+            },
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_progress() {
+        let shared = Arc::new(Shared::default());
+        let events = Arc::new(Mutex::new(vec![]));
+        {
+            let events = events.clone();
+            shared
+                .set_progress_callback(Arc::new(move |p: Progress| events.lock().unwrap().push(p)));
+        }
+
+        let tracker = BatchTracker::new(shared, "graph", "op-1", "upload");
+        tracker.start(2);
+        let a = tracker.file_callback("a");
+        let b = tracker.file_callback("b");
+        // transfers are throttled separately
+        a(1, 100);
+        b(1, 100);
+        a(2, 100);
+        b(100, 100);
+        tracker.file_done();
+        a(100, 100);
+        tracker.file_done();
+
+        let events = events.lock().unwrap();
+        let files = events
+            .iter()
+            .filter(|p| p.batch.is_none())
+            .map(|p| (&*p.file, p.progress))
+            .collect::<Vec<_>>();
+        assert_eq!(files, vec![("a", 1), ("b", 1), ("b", 100), ("a", 100)]);
+
+        let last = events.last().unwrap();
+        assert_eq!(last.r#type, "batch-upload");
+        let batch = last.batch.as_ref().unwrap();
+        assert_eq!((batch.files_done, batch.files_total), (2, 2));
+        assert_eq!((batch.bytes_done, batch.bytes_total), (200, 200));
+        assert_eq!(batch.operation_id, "op-1");
+    }
+}
@@ -0,0 +1,192 @@
+//! Cached listing of remote files.
+//!
+//! Listing all remote files of a big graph on every upload or download is slow, so the
+//! checksums and modified times are cached per graph, along with the txid they were listed
+//! at. A cache of another txid is outdated, e.g. after changes from other devices, and the
+//! files are listed again.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use sync::types::FileObject;
+
+use crate::planner;
+
+/// Checksum and modified time of a remote file
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RemoteEntry {
+    /// md5 of the plain content, none if not stored by the server
+    pub checksum: Option<String>,
+    /// modified time in milliseconds
+    pub mtime: i64,
+}
+
+/// encrypted path => remote file
+pub(crate) type RemoteEntries = Arc<HashMap<String, RemoteEntry>>;
+
+/// Remote files from `get_all_files` result
+pub(crate) fn from_listing(files: Vec<FileObject>) -> RemoteEntries {
+    Arc::new(
+        files
+            .into_iter()
+            .map(|obj| {
+                let entry = RemoteEntry {
+                    mtime: obj.mtime_millis(),
+                    checksum: obj.checksum,
+                };
+                (planner::strip_remote_prefix(&obj.key).to_string(), entry)
+            })
+            .collect(),
+    )
+}
+
+/// Remote files of graphs, by graph uuid
+#[derive(Debug, Default)]
+pub(crate) struct RemoteIndex(Mutex<HashMap<String, (i64, RemoteEntries)>>);
+
+impl RemoteIndex {
+    /// Remote files of a graph at txid, none if not cached
+    pub(crate) fn get(&self, graph_uuid: &str, txid: i64) -> Option<RemoteEntries> {
+        match self.0.lock().unwrap().get(graph_uuid) {
+            Some((cached_txid, entries)) if *cached_txid == txid => Some(entries.clone()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn set(&self, graph_uuid: &str, txid: i64, entries: RemoteEntries) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(graph_uuid.to_string(), (txid, entries));
+    }
+
+    /// Record uploaded files of the transaction from txid to new_txid,
+    /// an outdated cache is dropped
+    pub(crate) fn update(
+        &self,
+        graph_uuid: &str,
+        txid: i64,
+        new_txid: i64,
+        uploaded: impl IntoIterator<Item = (String, RemoteEntry)>,
+    ) {
+        let mut index = self.0.lock().unwrap();
+        match index.get_mut(graph_uuid) {
+            Some((cached_txid, entries)) if *cached_txid == txid => {
+                Arc::make_mut(entries).extend(uploaded);
+                *cached_txid = new_txid;
+            }
+            _ => {
+                index.remove(graph_uuid);
+            }
+        }
+    }
+
+    pub(crate) fn clear(&self, graph_uuid: &str) {
+        self.0.lock().unwrap().remove(graph_uuid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote_entry(checksum: &str, mtime: i64) -> RemoteEntry {
+        RemoteEntry {
+            checksum: Some(checksum.to_string()),
+            mtime,
+        }
+    }
+
+    #[test]
+    fn test_remote_index() {
+        let index = RemoteIndex::default();
+        index.set(
+            "graph",
+            1,
+            Arc::new([("a".into(), remote_entry("md5-a", 1000))].into()),
+        );
+        assert!(index.get("graph", 2).is_none());
+
+        index.update(
+            "graph",
+            1,
+            2,
+            [("b".to_string(), remote_entry("md5-b", 2000))],
+        );
+        let entries = index.get("graph", 2).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries["b"], remote_entry("md5-b", 2000));
+
+        // changes of other devices
+        index.update("graph", 3, 4, []);
+        assert!(index.get("graph", 4).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_remote_mtimes_listed_once_per_txid() {
+        use serde_json::json;
+        use sync::mock::{json_body, MockServer};
+
+        use crate::graph::DownloadOptions;
+
+        let (secret_key, public_key) = lsq_encryption::keygen();
+        let ctx = crate::RsapiContext::new();
+        ctx.set_env("graph", "dev", &secret_key, &public_key, &[], None, None)
+            .unwrap();
+        let mut graph = (*ctx.get_graph("graph").unwrap()).clone();
+
+        let encrypted_path = graph.encrypt_filename("assets/a.txt").unwrap();
+        let content = graph.encrypt_content(b"remote").unwrap().into_owned();
+        let server = MockServer::start({
+            let encrypted_path = encrypted_path.clone();
+            move |req| match &*req.path {
+                "get_graph" => json_body(json!({"GraphUUID": "graph", "TXId": 3})),
+                "get_all_files" => json_body(json!({ "Objects": [{
+                    "ETag": "",
+                    "Key": format!("graph/{}", encrypted_path),
+                    "LastModified": "2024-01-01T00:00:00Z",
+                    "Size": content.len(),
+                    "Mtime": 1_600_000_000_000i64,
+                }]})),
+                "get_files" => json_body(json!({ "PresignedFileUrls": {
+                    &encrypted_path: req.url("content"),
+                }})),
+                "content" => content.clone(),
+                _ => json_body(json!({"message": "not found"})),
+            }
+        })
+        .await;
+        graph.endpoint = server.endpoint();
+
+        let dir = tempfile::tempdir().unwrap();
+        for _ in 0..2 {
+            let update = graph
+                .update_local_files(
+                    dir.path(),
+                    ["assets/a.txt"],
+                    "token",
+                    &DownloadOptions {
+                        preserve_mtime: true,
+                        ..Default::default()
+                    },
+                    &graph.cancel_handle(None),
+                )
+                .await
+                .unwrap();
+            assert_eq!(update.files.succeeded, vec!["assets/a.txt".to_string()]);
+        }
+
+        let path = dir.path().join("assets/a.txt");
+        assert_eq!(std::fs::read(&path).unwrap(), b"remote");
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        assert_eq!(
+            mtime
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+            1_600_000_000_000
+        );
+        assert_eq!(server.requests_of("get_graph").len(), 2);
+        assert_eq!(server.requests_of("get_all_files").len(), 1);
+    }
+}
@@ -0,0 +1,109 @@
+//! Validation of graph relative file paths.
+//!
+//! File paths from callers and from the server are joined onto the graph base path,
+//! so they must stay inside the graph: no absolute paths, no `..` escaping the root,
+//! and, on Windows, no reserved device names. Other platforms accept `con.md` etc. as
+//! plain file names.
+
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+#[cfg(windows)]
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[cfg(windows)]
+fn is_reserved_name(component: &str) -> bool {
+    // `con.md` is also reserved
+    let stem = component.split('.').next().unwrap_or_default().trim_end();
+    WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|name| stem.eq_ignore_ascii_case(name))
+}
+
+#[cfg(not(windows))]
+fn is_reserved_name(_component: &str) -> bool {
+    false
+}
+
+/// Normalize a graph relative path to `/` separated form, `.` and inner `..` are resolved.
+pub fn normalize(path: &str) -> Result<String> {
+    let unsafe_path = || Error::UnsafePath(path.to_string());
+
+    if path.is_empty() || path.contains('\0') || path.starts_with(['/', '\\']) {
+        return Err(unsafe_path());
+    }
+    // drive prefix, `C:\` or `C:foo`
+    let mut chars = path.chars();
+    if let (Some(drive), Some(':')) = (chars.next(), chars.next()) {
+        if drive.is_ascii_alphabetic() {
+            return Err(unsafe_path());
+        }
+    }
+
+    let mut components: Vec<&str> = vec![];
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop().ok_or_else(unsafe_path)?;
+            }
+            _ if is_reserved_name(component) => return Err(unsafe_path()),
+            _ => components.push(component),
+        }
+    }
+    if components.is_empty() {
+        return Err(unsafe_path());
+    }
+    Ok(components.join("/"))
+}
+
+/// Join a graph relative path onto base path, rejecting paths escaping base path
+pub fn join<P: AsRef<Path>>(base_path: P, path: &str) -> Result<PathBuf> {
+    Ok(base_path.as_ref().join(normalize(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("pages/a.md").unwrap(), "pages/a.md");
+        assert_eq!(normalize("./pages//a.md").unwrap(), "pages/a.md");
+        assert_eq!(normalize("pages\\sub\\a.md").unwrap(), "pages/sub/a.md");
+        assert_eq!(normalize("pages/sub/../a.md").unwrap(), "pages/a.md");
+        assert_eq!(normalize("pages/a..b.md").unwrap(), "pages/a..b.md");
+        assert_eq!(normalize("pages/console.md").unwrap(), "pages/console.md");
+
+        for path in [
+            "",
+            ".",
+            "../../.ssh/authorized_keys",
+            "pages/../../a.md",
+            "/etc/passwd",
+            "\\\\server\\share\\a.md",
+            "C:\\Windows\\a.md",
+            "c:a.md",
+            "pages/a\0.md",
+        ] {
+            assert!(
+                matches!(normalize(path), Err(Error::UnsafePath(_))),
+                "{:?}",
+                path
+            );
+        }
+
+        for path in ["pages/CON", "pages/nul.md", "aux/a.md"] {
+            assert_eq!(
+                matches!(normalize(path), Err(Error::UnsafePath(_))),
+                cfg!(windows),
+                "{:?}",
+                path
+            );
+        }
+    }
+}
@@ -0,0 +1,316 @@
+//! Symlinks and special files(sockets, FIFOs and devices) in a graph.
+//!
+//! Special files are never synced. Symlinks are handled by the `SymlinkPolicy` of the graph,
+//! both when scanning local files and when writing downloaded files. A link synced as link
+//! is stored remotely as a small file holding the link target.
+
+use std::fs::{FileType, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[cfg(feature = "napi")]
+use napi_derive::napi;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::atomic_file;
+use crate::error::{Error, Result};
+use crate::index::set_mtime_millis;
+use crate::safe_path;
+
+// header of synced link content, followed by the link target
+const LINK_HEADER: &[u8] = b"logseq-symlink:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// symlinks are neither synced nor written through
+    Skip,
+    /// sync link targets as regular files and folders, link loops are skipped.
+    /// Downloaded files are written through links, even to targets outside of the graph
+    #[default]
+    Follow,
+    /// sync the link itself, as its target path
+    Link,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "link" => Ok(SymlinkPolicy::Link),
+            _ => Err(Error::InvalidArg),
+        }
+    }
+}
+
+/// Local file not synced
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SkippedFile {
+    pub path: String,
+    /// "symlink", "symlink-loop", "socket", "fifo" or "device" when scanning,
+    /// "too-large", "deferred" or "unchanged" when uploading
+    pub reason: String,
+}
+
+impl SkippedFile {
+    pub fn new(path: &str, reason: &str) -> Self {
+        SkippedFile {
+            path: path.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Kind of a special file, None for regular files, folders and symlinks
+#[cfg(unix)]
+pub(crate) fn special_kind(file_type: &FileType) -> Option<&'static str> {
+    use std::os::unix::fs::FileTypeExt;
+    if file_type.is_socket() {
+        Some("socket")
+    } else if file_type.is_fifo() {
+        Some("fifo")
+    } else if file_type.is_block_device() || file_type.is_char_device() {
+        Some("device")
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn special_kind(_file_type: &FileType) -> Option<&'static str> {
+    None
+}
+
+/// Synced content of a link
+pub(crate) fn link_content(target: &Path) -> Vec<u8> {
+    let target = target.to_string_lossy().replace('\\', "/");
+    [LINK_HEADER, target.as_bytes()].concat()
+}
+
+/// Link target of synced link content
+pub(crate) fn link_target(content: &[u8]) -> Option<&str> {
+    std::str::from_utf8(content.strip_prefix(LINK_HEADER)?).ok()
+}
+
+/// Content of the link at path, for `SymlinkPolicy::Link`
+pub(crate) async fn read_link_content(
+    path: &Path,
+    policy: SymlinkPolicy,
+) -> io::Result<Option<Vec<u8>>> {
+    if policy != SymlinkPolicy::Link || !fs::symlink_metadata(path).await?.is_symlink() {
+        return Ok(None);
+    }
+    Ok(Some(link_content(&fs::read_link(path).await?)))
+}
+
+/// Read a local file, links are read as link content for `SymlinkPolicy::Link`
+pub(crate) async fn read(path: &Path, policy: SymlinkPolicy) -> io::Result<Vec<u8>> {
+    match read_link_content(path, policy).await? {
+        Some(content) => Ok(content),
+        None => fs::read(path).await,
+    }
+}
+
+/// Metadata of a local file, of the link itself for `SymlinkPolicy::Link`
+pub(crate) async fn metadata(path: &Path, policy: SymlinkPolicy) -> io::Result<Metadata> {
+    match policy {
+        SymlinkPolicy::Link => fs::symlink_metadata(path).await,
+        _ => fs::metadata(path).await,
+    }
+}
+
+/// The first symlink on path under base path, the file itself included
+pub(crate) async fn find_link(base_path: &Path, path: &Path) -> Option<PathBuf> {
+    let relative = path.strip_prefix(base_path).ok()?;
+    let mut path = base_path.to_path_buf();
+    for component in relative.components() {
+        path.push(component);
+        match fs::symlink_metadata(&path).await {
+            Ok(metadata) if metadata.is_symlink() => return Some(path),
+            Ok(_) => {}
+            Err(_) => return None,
+        }
+    }
+    None
+}
+
+/// Write a downloaded file of the graph, atomically.
+///
+/// Existing symlinks on the path are skipped, written through, or replaced by the file,
+/// according to the policy. Link content is written as a symlink for `SymlinkPolicy::Link`,
+/// and skipped otherwise. Special files are never replaced.
+///
+/// Writing through a link may write outside of `base_path`, where the link points to.
+/// These are the files scanned through the link when uploading. Downloaded links never
+/// point outside: link targets must be relative and stay inside `base_path`.
+pub(crate) async fn write_file(
+    base_path: &Path,
+    path: &Path,
+    content: &[u8],
+    mtime: Option<i64>,
+    policy: SymlinkPolicy,
+) -> Result<()> {
+    let link = find_link(base_path, path).await;
+    // links in parent folders are only written through when following links
+    let through_link = link.as_deref().is_some_and(|link| link != path);
+
+    if let Some(target) = link_target(content) {
+        if policy != SymlinkPolicy::Link || through_link {
+            return Err(Error::SkippedFile("symlink"));
+        }
+        check_link_target(base_path, path, target)?;
+        return write_link(path, target).await;
+    }
+
+    let path = match (link, policy) {
+        (None, _) => path.to_path_buf(),
+        (Some(_), SymlinkPolicy::Skip) => return Err(Error::SkippedFile("symlink")),
+        // the file may not exist yet, resolve the link and keep the rest of the path
+        (Some(link), SymlinkPolicy::Follow) => {
+            let target = fs::canonicalize(&link).await?;
+            match path.strip_prefix(&link) {
+                Ok(rest) if !rest.as_os_str().is_empty() => target.join(rest),
+                _ => target,
+            }
+        }
+        (Some(_), SymlinkPolicy::Link) if through_link => {
+            return Err(Error::SkippedFile("symlink"))
+        }
+        // the link is replaced by the file
+        (Some(_), SymlinkPolicy::Link) => path.to_path_buf(),
+    };
+    if let Ok(metadata) = fs::symlink_metadata(&path).await {
+        if let Some(kind) = special_kind(&metadata.file_type()) {
+            return Err(Error::SkippedFile(kind));
+        }
+    }
+
+    atomic_file::write_file(&path, content).await?;
+    if let Some(mtime) = mtime {
+        set_mtime_millis(&path, mtime).await?;
+    }
+    Ok(())
+}
+
+/// Reject link targets that are absolute or resolve outside of base path
+fn check_link_target(base_path: &Path, path: &Path, target: &str) -> Result<()> {
+    let unsafe_target = || Error::UnsafePath(target.to_string());
+    if Path::new(target).has_root() || target.starts_with(['/', '\\']) {
+        return Err(unsafe_target());
+    }
+    // relative to the folder of the link
+    let dir = path
+        .parent()
+        .and_then(|dir| dir.strip_prefix(base_path).ok())
+        .and_then(|dir| dir.to_str())
+        .ok_or_else(unsafe_target)?;
+    let resolved = if dir.is_empty() {
+        target.to_string()
+    } else {
+        format!("{}/{}", dir, target)
+    };
+    safe_path::normalize(&resolved).map_err(|_| unsafe_target())?;
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn write_link(path: &Path, target: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    if fs::symlink_metadata(path).await.is_ok() {
+        fs::remove_file(path).await?;
+    }
+    fs::symlink(target, path).await?;
+    Ok(())
+}
+
+// creating symlinks needs extra privileges on Windows
+#[cfg(not(unix))]
+async fn write_link(_path: &Path, _target: &str) -> Result<()> {
+    Err(Error::SkippedFile("symlink"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_file_by_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let base_path = dunce::canonicalize(dir.path()).unwrap();
+        std::fs::create_dir_all(base_path.join("assets")).unwrap();
+        std::fs::write(base_path.join("assets/a.png"), "a").unwrap();
+        std::os::unix::fs::symlink("a.png", base_path.join("assets/b.png")).unwrap();
+        let link = base_path.join("assets/b.png");
+
+        assert!(matches!(
+            write_file(&base_path, &link, b"b", None, SymlinkPolicy::Skip).await,
+            Err(Error::SkippedFile("symlink"))
+        ));
+        write_file(&base_path, &link, b"b", None, SymlinkPolicy::Follow)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(base_path.join("assets/a.png")).unwrap(), b"b");
+
+        let content = read(&link, SymlinkPolicy::Link).await.unwrap();
+        assert_eq!(link_target(&content), Some("a.png"));
+        let c = base_path.join("assets/c.png");
+        write_file(&base_path, &c, &content, None, SymlinkPolicy::Link)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_link(&c).unwrap(), Path::new("a.png"));
+        assert!(matches!(
+            write_file(&base_path, &c, &content, None, SymlinkPolicy::Follow).await,
+            Err(Error::SkippedFile("symlink"))
+        ));
+
+        // links to targets outside of the graph are not written
+        std::fs::create_dir_all(base_path.join("assets/sub")).unwrap();
+        let d = base_path.join("assets/sub/d.png");
+        for target in ["/etc/passwd", "../../../a.png", "x/../../../../a.png"] {
+            let content = link_content(Path::new(target));
+            assert!(
+                matches!(
+                    write_file(&base_path, &d, &content, None, SymlinkPolicy::Link).await,
+                    Err(Error::UnsafePath(_))
+                ),
+                "{:?}",
+                target
+            );
+        }
+        assert!(!d.exists() && std::fs::symlink_metadata(&d).is_err());
+        let content = link_content(Path::new("../a.png"));
+        write_file(&base_path, &d, &content, None, SymlinkPolicy::Link)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&d).unwrap(), b"b");
+    }
+
+    #[tokio::test]
+    async fn test_write_new_file_through_linked_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let base_path = dunce::canonicalize(dir.path()).unwrap();
+        // linked folder outside of the graph
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), base_path.join("assets")).unwrap();
+        let path = base_path.join("assets/sub/new.png");
+
+        write_file(&base_path, &path, b"new", None, SymlinkPolicy::Follow)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(outside.path().join("sub/new.png")).unwrap(),
+            b"new"
+        );
+        assert!(matches!(
+            write_file(&base_path, &path, b"new", None, SymlinkPolicy::Link).await,
+            Err(Error::SkippedFile("symlink"))
+        ));
+    }
+}
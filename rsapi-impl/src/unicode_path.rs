@@ -0,0 +1,136 @@
+//! Unicode normalization of file paths.
+//!
+//! macOS may store file names decomposed(NFD), while the server and other platforms use
+//! the composed form(NFC). Paths are keyed by their NFC form, and the on-disk form is kept
+//! when writing files back.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+
+#[cfg(feature = "napi")]
+use napi_derive::napi;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+/// Local files whose paths differ only in Unicode normalization form
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UnicodeCollision {
+    /// on-disk path of the skipped file
+    pub path: String,
+    /// on-disk path of the file kept
+    pub conflict_with: String,
+}
+
+pub fn nfc(path: &str) -> Cow<'_, str> {
+    if is_nfc(path) {
+        Cow::Borrowed(path)
+    } else {
+        Cow::Owned(path.nfc().collect())
+    }
+}
+
+/// Find on-disk paths with the same NFC form. The NFC-form path is kept if present,
+/// otherwise the smallest one.
+pub fn find_collisions<'a>(paths: impl IntoIterator<Item = &'a str>) -> Vec<UnicodeCollision> {
+    let mut groups: HashMap<Cow<str>, Vec<&str>> = HashMap::new();
+    for path in paths {
+        groups.entry(nfc(path)).or_default().push(path);
+    }
+
+    let mut collisions = vec![];
+    for (normalized, mut paths) in groups.into_iter().filter(|(_, v)| v.len() > 1) {
+        paths.sort();
+        let kept = paths
+            .iter()
+            .position(|p| *p == normalized)
+            .unwrap_or_default();
+        let kept = paths.remove(kept);
+        collisions.extend(paths.into_iter().map(|p| UnicodeCollision {
+            path: p.to_string(),
+            conflict_with: kept.to_string(),
+        }));
+    }
+    collisions.sort_by(|a, b| a.path.cmp(&b.path));
+    collisions
+}
+
+/// Resolve a graph relative path to the form of existing files and folders on disk,
+/// components not found on disk are kept as is.
+pub fn on_disk_path(base_path: &Path, path: &str) -> String {
+    if path.is_ascii() {
+        return path.to_string();
+    }
+    let mut resolved: Vec<String> = vec![];
+    let mut exists = true;
+    for component in path.split('/') {
+        let found = if exists {
+            let dir = base_path.join(resolved.join("/"));
+            let normalized = nfc(component);
+            std::fs::read_dir(dir).ok().and_then(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .filter_map(|e| e.file_name().into_string().ok())
+                    .find(|name| name == component || nfc(name) == normalized)
+            })
+        } else {
+            None
+        };
+        exists = found.is_some();
+        resolved.push(found.unwrap_or_else(|| component.to_string()));
+    }
+    resolved.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAFE_NFC: &str = "pages/caf\u{e9}.md";
+    const CAFE_NFD: &str = "pages/cafe\u{301}.md";
+
+    #[test]
+    fn test_find_collisions() {
+        assert_eq!(nfc(CAFE_NFD), CAFE_NFC);
+        assert_eq!(
+            find_collisions([CAFE_NFD, "pages/a.md", CAFE_NFC]),
+            vec![UnicodeCollision {
+                path: CAFE_NFD.into(),
+                conflict_with: CAFE_NFC.into()
+            }]
+        );
+        assert!(find_collisions([CAFE_NFD, "pages/a.md"]).is_empty());
+    }
+
+    #[test]
+    fn test_on_disk_path() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("pages")).unwrap();
+        std::fs::write(dir.path().join(CAFE_NFD), "").unwrap();
+
+        assert_eq!(on_disk_path(dir.path(), CAFE_NFC), CAFE_NFD);
+        assert_eq!(
+            on_disk_path(dir.path(), "pages/na\u{ef}ve.md"),
+            "pages/na\u{ef}ve.md"
+        );
+        assert_eq!(on_disk_path(dir.path(), "pages/a.md"), "pages/a.md");
+    }
+
+    #[tokio::test]
+    async fn test_base_checksums_in_nfc() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("logseq/version-files/base");
+        std::fs::create_dir_all(base.join("pages")).unwrap();
+        std::fs::write(base.join(CAFE_NFD), "- base").unwrap();
+
+        let (secret_key, public_key) = lsq_encryption::keygen();
+        let ctx = crate::RsapiContext::new();
+        ctx.set_env("graph", "dev", &secret_key, &public_key, &[], None, None)
+            .unwrap();
+        let graph = ctx.get_graph("graph").unwrap();
+        let checksums = graph.get_base_checksums(dir.path()).await.unwrap();
+        assert!(checksums.contains_key(CAFE_NFC));
+        assert!(!checksums.contains_key(CAFE_NFD));
+    }
+}
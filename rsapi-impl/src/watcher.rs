@@ -0,0 +1,415 @@
+//! Filesystem watcher of graph base path.
+//!
+//! Raw events are debounced per path: a path is reported once it has been quiet
+//! for the debounce interval, so an editor save burst (write temp file, rename it
+//! over the page) is reported as a single "change".
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "napi")]
+use napi_derive::napi;
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use walkdir::WalkDir;
+
+use crate::error::Result;
+use crate::graph::{FileMeta, Graph};
+use crate::ignore_rules::{IgnoreRules, SYNCIGNORE_FILE};
+
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Local file change, pushed by the watcher
+#[cfg_attr(feature = "napi", napi(object))]
+#[derive(Debug)]
+pub struct FileChangeEvent {
+    pub graph_uuid: String,
+    /// "add", "change", "unlink" or "rename"
+    pub r#type: &'static str,
+    /// relative to graph base path, a folder path for "unlink" if the whole folder is gone
+    pub path: String,
+    /// old path, for "rename"
+    pub from: Option<String>,
+    /// none for "unlink"
+    pub meta: Option<FileMeta>,
+}
+
+/// Start watching base path of a graph, changes are reported until the watcher is dropped
+pub fn watch<P: AsRef<Path>>(
+    graph: Arc<Graph>,
+    base_path: P,
+    debounce: Duration,
+) -> Result<RecommendedWatcher> {
+    let base_path = dunce::canonicalize(base_path.as_ref())?;
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = tx.send(res);
+    })?;
+    watcher.watch(&base_path, RecursiveMode::Recursive)?;
+
+    log::info!("start watching graph {} at {:?}", graph.uuid, base_path);
+    std::thread::Builder::new()
+        .name("rsapi-watcher".into())
+        .spawn(move || event_loop(graph, base_path, debounce, rx))?;
+
+    Ok(watcher)
+}
+
+fn event_loop(
+    graph: Arc<Graph>,
+    base_path: PathBuf,
+    debounce: Duration,
+    rx: Receiver<notify::Result<Event>>,
+) {
+    let rt = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(rt) => rt,
+        Err(e) => {
+            log::error!("cannot start watcher runtime: {}", e);
+            return;
+        }
+    };
+    let load_rules = || {
+        rt.block_on(graph.load_ignore_rules(&base_path))
+            .unwrap_or_else(|e| {
+                log::warn!("cannot load ignore rules, use default: {}", e);
+                IgnoreRules::default()
+            })
+    };
+    let mut ignore_rules = load_rules();
+    let mut debouncer = Debouncer::new(debounce);
+
+    loop {
+        let timeout = debouncer
+            .next_deadline()
+            .map(|t| t.saturating_duration_since(Instant::now()))
+            .unwrap_or(Duration::from_secs(3600));
+        let disconnected = match rx.recv_timeout(timeout) {
+            Ok(Ok(event)) => {
+                if event.paths.iter().any(|p| p.ends_with(SYNCIGNORE_FILE)) {
+                    ignore_rules = load_rules();
+                }
+                debouncer.push(&base_path, &ignore_rules, event, Instant::now());
+                false
+            }
+            Ok(Err(e)) => {
+                log::warn!("watcher error: {}", e);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        let now = if disconnected {
+            Instant::now() + debounce
+        } else {
+            Instant::now()
+        };
+        for (path, change) in debouncer.take_ready(now) {
+            if let Some(event) = rt.block_on(to_file_change_event(&graph, &base_path, path, change))
+            {
+                graph.shared.notify_file_change(event);
+            }
+        }
+        if disconnected {
+            break;
+        }
+    }
+}
+
+async fn to_file_change_event(
+    graph: &Graph,
+    base_path: &Path,
+    path: String,
+    change: Change,
+) -> Option<FileChangeEvent> {
+    let full_path = base_path.join(&path);
+    if full_path.is_dir() {
+        // files in new folders are pushed when the folder event arrives
+        return None;
+    }
+    let meta = if full_path.exists() {
+        match graph.get_file_meta(base_path, &path).await {
+            Ok(meta) => Some(meta),
+            Err(e) => {
+                log::warn!("cannot get meta of changed file {:?}: {}", path, e);
+                return None;
+            }
+        }
+    } else {
+        None
+    };
+
+    let (r#type, from) = match (change, &meta) {
+        // a transient file
+        (Change::Created, None) => return None,
+        (_, None) => ("unlink", None),
+        (Change::Created, Some(_)) => ("add", None),
+        (Change::Renamed { from }, Some(_)) if from != path => ("rename", Some(from)),
+        (_, Some(_)) => ("change", None),
+    };
+    Some(FileChangeEvent {
+        graph_uuid: graph.uuid.clone(),
+        r#type,
+        path,
+        from,
+        meta,
+    })
+}
+
+/// Coalesced change of a path, in the debounce window
+#[derive(Debug, Clone, PartialEq)]
+enum Change {
+    Created,
+    Modified,
+    Removed,
+    Renamed { from: String },
+}
+
+struct Debouncer {
+    debounce: Duration,
+    pending: HashMap<String, (Change, Instant)>,
+    // pending change of rename sources, before the rename is paired
+    moved_from: HashMap<String, Option<Change>>,
+}
+
+impl Debouncer {
+    fn new(debounce: Duration) -> Self {
+        Self {
+            debounce,
+            pending: HashMap::new(),
+            moved_from: HashMap::new(),
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|(_, t)| *t + self.debounce).min()
+    }
+
+    /// Paths quiet for the debounce interval, sorted
+    fn take_ready(&mut self, now: Instant) -> Vec<(String, Change)> {
+        let debounce = self.debounce;
+        let mut ready: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, t))| *t + debounce <= now)
+            .map(|(p, (c, _))| (p.clone(), c.clone()))
+            .collect();
+        for (path, _) in &ready {
+            self.pending.remove(path);
+            self.moved_from.remove(path);
+        }
+        ready.sort_by(|a, b| a.0.cmp(&b.0));
+        ready
+    }
+
+    fn created(&mut self, path: String, now: Instant) {
+        let change = match self.pending.remove(&path) {
+            None => Change::Created,
+            // delete then create, a replaced file
+            Some((Change::Removed, _)) => Change::Modified,
+            Some((change, _)) => change,
+        };
+        self.pending.insert(path, (change, now));
+    }
+
+    fn modified(&mut self, path: String, now: Instant) {
+        let change = match self.pending.remove(&path) {
+            None | Some((Change::Removed, _)) => Change::Modified,
+            Some((change, _)) => change,
+        };
+        self.pending.insert(path, (change, now));
+    }
+
+    fn removed(&mut self, path: String, now: Instant) {
+        match self.pending.remove(&path) {
+            // created and removed in the window, never reported
+            Some((Change::Created, _)) => {}
+            Some((Change::Renamed { from }, _)) => {
+                self.pending.insert(from, (Change::Removed, now));
+            }
+            _ => {
+                self.pending.insert(path, (Change::Removed, now));
+            }
+        }
+    }
+
+    /// Source of a rename, the destination may arrive later or never (moved out)
+    fn moved_away(&mut self, path: String, now: Instant) {
+        let prior = self.pending.get(&path).map(|(c, _)| c.clone());
+        self.moved_from.insert(path.clone(), prior);
+        self.removed(path, now);
+    }
+
+    fn renamed(&mut self, from: String, to: String, now: Instant) {
+        let prior = match self.moved_from.remove(&from) {
+            Some(prior) => prior,
+            None => self.pending.get(&from).map(|(c, _)| c.clone()),
+        };
+        let change = match prior {
+            Some(Change::Created) => Change::Created,
+            Some(Change::Renamed { from }) => Change::Renamed { from },
+            _ => Change::Renamed { from: from.clone() },
+        };
+        self.pending.remove(&from);
+        if let Some(Change::Renamed { from: origin }) = &self.pending.get(&to).map(|(c, _)| c) {
+            // renamed over a renamed file
+            if *origin != from {
+                self.pending.insert(origin.clone(), (Change::Removed, now));
+            }
+        }
+        self.pending.insert(to, (change, now));
+    }
+
+    /// Feed a raw event, paths are made relative and ignored paths are dropped
+    fn push(&mut self, base_path: &Path, ignore_rules: &IgnoreRules, event: Event, now: Instant) {
+        let rel = |path: &Path| -> Option<String> {
+            let rel = path.strip_prefix(base_path).ok()?.to_str()?;
+            let rel = rel.replace('\\', "/");
+            if rel.is_empty() || ignore_rules.is_ignored(&rel, path.is_dir()) {
+                None
+            } else {
+                Some(rel)
+            }
+        };
+        // files under a folder, for folders created or moved in
+        let files_under = |path: &Path| -> Vec<String> {
+            WalkDir::new(path)
+                .into_iter()
+                .filter_entry(|e| rel(e.path()).is_some())
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .filter_map(|e| rel(e.path()))
+                .collect()
+        };
+
+        match event.kind {
+            EventKind::Access(_) => {}
+            EventKind::Create(_) => {
+                for path in &event.paths {
+                    if path.is_dir() {
+                        for file in files_under(path) {
+                            self.created(file, now);
+                        }
+                    } else if let Some(p) = rel(path) {
+                        self.created(p, now);
+                    }
+                }
+            }
+            EventKind::Remove(_) => {
+                for p in event.paths.iter().filter_map(|p| rel(p)) {
+                    self.removed(p, now);
+                }
+            }
+            EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Metadata(_) | ModifyKind::Any) => {
+                for p in event.paths.iter().filter_map(|p| rel(p)) {
+                    self.modified(p, now);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                for p in event.paths.iter().filter_map(|p| rel(p)) {
+                    self.moved_away(p, now);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                for path in &event.paths {
+                    if path.is_dir() {
+                        for file in files_under(path) {
+                            self.created(file, now);
+                        }
+                    } else if let Some(p) = rel(path) {
+                        self.created(p, now);
+                    }
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                let (from, to) = (&event.paths[0], &event.paths[1]);
+                match (rel(from), rel(to)) {
+                    (Some(from), Some(to_rel)) if to.is_dir() => {
+                        self.pending.remove(&from);
+                        self.moved_from.remove(&from);
+                        for file in files_under(to) {
+                            let old = format!("{}{}", from, &file[to_rel.len()..]);
+                            self.renamed(old, file, now);
+                        }
+                    }
+                    (Some(from), Some(to)) => self.renamed(from, to, now),
+                    (None, Some(_)) if to.is_dir() => {
+                        for file in files_under(to) {
+                            self.created(file, now);
+                        }
+                    }
+                    // saved via an ignored temp file
+                    (None, Some(to)) => {
+                        self.pending.insert(to, (Change::Modified, now));
+                    }
+                    (Some(from), None) => self.removed(from, now),
+                    (None, None) => {}
+                }
+            }
+            // unknown kind of event, check the path later
+            _ => {
+                for path in &event.paths {
+                    if let Some(p) = rel(path) {
+                        if path.exists() {
+                            self.modified(p, now);
+                        } else {
+                            self.removed(p, now);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debounce_save_burst() {
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+        let mut debouncer = Debouncer::new(ms(100));
+
+        // write temp file, rename over page
+        debouncer.modified("pages/a.md".into(), t0);
+        debouncer.created("pages/b.md".into(), t0);
+        debouncer.moved_away("pages/b.md".into(), t0 + ms(10));
+        debouncer.created("pages/c.md".into(), t0 + ms(10));
+        debouncer.renamed("pages/b.md".into(), "pages/c.md".into(), t0 + ms(10));
+        // delete and recreate
+        debouncer.removed("pages/d.md".into(), t0);
+        debouncer.created("pages/d.md".into(), t0 + ms(50));
+        // rename twice
+        debouncer.renamed("pages/e.md".into(), "pages/f.md".into(), t0);
+        debouncer.renamed("pages/f.md".into(), "pages/g.md".into(), t0 + ms(20));
+
+        assert!(debouncer.take_ready(t0 + ms(90)).is_empty());
+        assert_eq!(
+            debouncer.take_ready(t0 + ms(120)),
+            vec![
+                ("pages/a.md".into(), Change::Modified),
+                ("pages/c.md".into(), Change::Created),
+                (
+                    "pages/g.md".into(),
+                    Change::Renamed {
+                        from: "pages/e.md".into()
+                    }
+                ),
+            ]
+        );
+        assert_eq!(debouncer.next_deadline(), Some(t0 + ms(150)));
+        assert_eq!(
+            debouncer.take_ready(t0 + ms(150)),
+            vec![("pages/d.md".into(), Change::Modified)]
+        );
+        assert_eq!(debouncer.next_deadline(), None);
+    }
+}
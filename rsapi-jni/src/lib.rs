@@ -2,14 +2,23 @@
 #![feature(result_flattening)]
 #![allow(non_snake_case)]
 
+use std::cell::RefCell;
 use std::path::PathBuf;
+use std::sync::{LazyLock, OnceLock};
 
 use jni::objects::{JClass, JObject, JString, JValue};
-use jni::sys::{jbyteArray, jint, jlong, jobjectArray, jstring, JNI_VERSION_1_6};
+use jni::sys::{jboolean, jbyteArray, jint, jlong, jobjectArray, jstring, JNI_VERSION_1_6};
 use jni::{JNIEnv, JavaVM};
+use serde::Serialize;
 
 use rsapi_impl as implementation;
-pub use rsapi_impl::{FileMeta, Progress};
+use rsapi_impl::conflict::ConflictOptions;
+use rsapi_impl::dry_run::DryRun;
+use rsapi_impl::graph::{DownloadOptions, Graph, UploadOptions};
+use rsapi_impl::housekeeping::{self, CleanupOptions};
+use rsapi_impl::large_file::FileSizeLimits;
+use rsapi_impl::{fs_case, safe_path, watcher, RsapiContext};
+pub use rsapi_impl::{FileChangeEvent, FileMeta, Progress, SyncEvent};
 
 use crate::error::Error;
 
@@ -17,19 +26,38 @@ pub mod error;
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
-/// Used for error handling
-static mut LAST_ERROR: Option<Error> = None;
+thread_local! {
+    /// Used for error handling, errors are read back on the thread of the failed call
+    static LAST_ERROR: RefCell<Option<Error>> = const { RefCell::new(None) };
+}
+
+static VM: OnceLock<JavaVM> = OnceLock::new();
 
-static mut VM: Option<JavaVM> = Option::None;
+static RUNNER: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
 
-static mut RUNNER: Option<tokio::runtime::Runtime> = Option::None;
+static CONTEXT: LazyLock<RsapiContext> = LazyLock::new(RsapiContext::new);
 
 fn runtime() -> &'static tokio::runtime::Runtime {
-    unsafe {
-        RUNNER
-            .as_ref()
-            .expect("tokio runner is inited in JNI_OnLoad")
+    RUNNER.get().expect("tokio runner is inited in JNI_OnLoad")
+}
+
+fn java_vm() -> &'static JavaVM {
+    VM.get().expect("VM is set in JNI_OnLoad")
+}
+
+fn set_last_error<E: Into<Error>>(err: E) {
+    let err = err.into();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(err));
+}
+
+/// Result of a batch operation as JSON string: `succeeded` and `failed` files,
+/// along with other fields of the result, and `planned` changes of a dry run
+fn batch_json<T: Serialize>(env: JNIEnv, result: &T, dry_run: &DryRun) -> Result<jstring> {
+    let mut json = serde_json::to_value(result).map_err(|e| Error::Other(e.to_string()))?;
+    if let Some(planned) = dry_run.planned() {
+        json["planned"] = serde_json::to_value(planned).map_err(|e| Error::Other(e.to_string()))?;
     }
+    Ok(env.new_string(json.to_string())?.into_raw())
 }
 
 pub struct AndroidLogger;
@@ -70,9 +98,7 @@ impl log::Log for AndroidLogger {
 
 #[no_mangle]
 pub extern "system" fn JNI_OnLoad(vm: JavaVM, _reserved: *mut ()) -> jint {
-    unsafe {
-        VM = Some(vm);
-    }
+    let _ = VM.set(vm);
 
     log::set_logger(&AndroidLogger).expect("JNI_OnLoad is called at most once");
     log::set_max_level(log::LevelFilter::Debug);
@@ -91,12 +117,10 @@ pub extern "system" fn JNI_OnLoad(vm: JavaVM, _reserved: *mut ()) -> jint {
         .enable_all()
         .build()
         .expect("tokio runtime");
-    unsafe {
-        RUNNER = Some(rt);
-    }
+    let _ = RUNNER.set(rt);
 
     // Ref: https://developer.android.com/training/articles/perf-jni#faq_FindClass
-    let env: JNIEnv = unsafe { VM.as_ref().unwrap().get_env().unwrap() };
+    let env: JNIEnv = java_vm().get_env().unwrap();
     let inst = {
         env.call_static_method(
             "com/logseq/app/filesync/FileSyncPlugin",
@@ -110,13 +134,28 @@ pub extern "system" fn JNI_OnLoad(vm: JavaVM, _reserved: *mut ()) -> jint {
     };
     let inst = env.new_global_ref(inst).unwrap();
 
+    let watch_inst = inst.clone();
+    let event_inst = inst.clone();
     let (tx, rx) = std::sync::mpsc::channel::<Progress>();
     std::thread::spawn(move || {
-        let env = unsafe { VM.as_ref().unwrap() }
+        let env = java_vm()
             .attach_current_thread()
             .expect("VM cannot attach to current thread");
 
         while let Ok(info) = rx.recv() {
+            // batch progress is passed as JSON, with its `batch` field
+            if info.batch.is_some() {
+                let json = serde_json::to_string(&info).unwrap_or_default();
+                let _ret = env
+                    .call_method(
+                        &inst,
+                        "batchProgressNotify",
+                        "(Ljava/lang/String;)V",
+                        &[JValue::Object(env.new_string(&json).unwrap().into())],
+                    )
+                    .expect("batchProgressNotify");
+                continue;
+            }
             let _ret = env
                 .call_method(
                     &inst,
@@ -138,7 +177,69 @@ pub extern "system" fn JNI_OnLoad(vm: JavaVM, _reserved: *mut ()) -> jint {
         tx.send(info).unwrap();
     };
 
-    implementation::set_progress_callback(progress_callback);
+    CONTEXT.set_progress_callback(progress_callback);
+
+    let (tx, rx) = std::sync::mpsc::channel::<FileChangeEvent>();
+    std::thread::spawn(move || {
+        let env = java_vm()
+            .attach_current_thread()
+            .expect("VM cannot attach to current thread");
+
+        while let Ok(event) = rx.recv() {
+            let meta = match &event.meta {
+                Some(meta) => to_java_file_meta(*env, meta).expect("FileMeta"),
+                None => JObject::null(),
+            };
+            let from = match &event.from {
+                Some(from) => env.new_string(from).unwrap().into(),
+                None => JObject::null(),
+            };
+            let _ret = env
+                .call_method(
+                    &watch_inst,
+                    "fileChangeNotify",
+                    "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;Lcom/logseq/sync/FileMeta;)V",
+                    &[
+                        JValue::Object(env.new_string(&event.graph_uuid).unwrap().into()),
+                        JValue::Object(env.new_string(event.r#type).unwrap().into()),
+                        JValue::Object(env.new_string(&event.path).unwrap().into()),
+                        JValue::Object(from),
+                        JValue::Object(meta),
+                    ],
+                )
+                .expect("fileChangeNotify");
+        }
+    });
+
+    let watch_callback = move |event: FileChangeEvent| {
+        tx.send(event).unwrap();
+    };
+
+    CONTEXT.set_watch_callback(watch_callback);
+
+    // sync events are passed as JSON
+    let (tx, rx) = std::sync::mpsc::channel::<SyncEvent>();
+    std::thread::spawn(move || {
+        let env = java_vm()
+            .attach_current_thread()
+            .expect("VM cannot attach to current thread");
+
+        while let Ok(event) = rx.recv() {
+            let json = serde_json::to_string(&event).unwrap_or_default();
+            let _ret = env
+                .call_method(
+                    &event_inst,
+                    "syncEventNotify",
+                    "(Ljava/lang/String;)V",
+                    &[JValue::Object(env.new_string(&json).unwrap().into())],
+                )
+                .expect("syncEventNotify");
+        }
+    });
+
+    CONTEXT.subscribe_events(move |event: SyncEvent| {
+        tx.send(event).unwrap();
+    });
 
     JNI_VERSION_1_6
 }
@@ -149,14 +250,10 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_getLastError(
     env: JNIEnv,
     _class: JClass,
 ) -> jstring {
-    unsafe {
-        if let Some(err) = &LAST_ERROR {
-            let ret = env.new_string(err.to_string()).unwrap().into_raw();
-            LAST_ERROR = None;
-            ret
-        } else {
-            JObject::null().into_raw()
-        }
+    if let Some(err) = LAST_ERROR.with(|last| last.borrow_mut().take()) {
+        env.new_string(err.to_string()).unwrap().into_raw()
+    } else {
+        JObject::null().into_raw()
     }
 }
 
@@ -167,17 +264,52 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_cancelAllRequests(
     _class: JClass,
 ) -> jlong {
     debug_log("cancel all requests");
-    match implementation::cancel_all_requests() {
+    match CONTEXT.cancel_all_requests() {
         Ok(()) => 0,
         Err(err) => {
-            unsafe {
-                LAST_ERROR = Some(err.into());
-            }
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_cancelGraphRequests(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+) -> jlong {
+    fn inner(env: JNIEnv, graph_uuid: JString) -> Result<()> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+        CONTEXT.cancel_graph_requests(&graph_uuid)?;
+        Ok(())
+    }
+
+    match inner(env, graph_uuid) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
             -1
         }
     }
 }
 
+/// Cancel an operation by the operationId passed to it, return false if it is not running
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_cancelOperation(
+    env: JNIEnv,
+    _class: JClass,
+    operation_id: JString,
+) -> jboolean {
+    match env.get_string(operation_id) {
+        Ok(operation_id) => CONTEXT.cancel_operation(&String::from(operation_id)) as jboolean,
+        Err(err) => {
+            set_last_error(err);
+            0
+        }
+    }
+}
+
 /// String[secret, public], won't fail
 #[no_mangle]
 pub extern "system" fn Java_com_logseq_sync_RSFileSync_keygen(
@@ -204,6 +336,9 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_setEnvironment(
     variant: JString,
     secret_key: JString,
     public_key: JString,
+    ignore_rules: jobjectArray, // nullable
+    base_path: JString,         // nullable
+    token: JString,             // nullable
 ) -> jlong {
     let variant = env.get_string(variant).map(String::from).expect("env");
     let graph_uuid = env
@@ -219,18 +354,253 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_setEnvironment(
         .get_string(public_key)
         .map(String::from)
         .expect("public key must set");
+    let ignore_rules = jarray_to_string_vec(env, ignore_rules).expect("ignore rules");
 
-    match implementation::set_env(&graph_uuid, &variant, &secret_key, &public_key) {
+    let base_path = if base_path.is_null() {
+        None
+    } else {
+        Some(uri_to_full_path(env, base_path).expect("base path"))
+    };
+    let token = get_optional_string(env, token).expect("token");
+
+    match CONTEXT.set_env(
+        &graph_uuid,
+        &variant,
+        &secret_key,
+        &public_key,
+        &ignore_rules,
+        base_path.as_deref(),
+        token.as_deref(),
+    ) {
         Ok(()) => 0,
         Err(err) => {
-            unsafe {
-                LAST_ERROR = Some(err.into());
-            }
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_removeGraph(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+) -> jlong {
+    fn inner(env: JNIEnv, graph_uuid: JString) -> Result<()> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+        CONTEXT.remove_graph(&graph_uuid)?;
+        Ok(())
+    }
+
+    match inner(env, graph_uuid) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+/// Return graphs as JSON string, null when error
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_listGraphs(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    fn inner(env: JNIEnv) -> Result<jstring> {
+        let json = serde_json::to_string(&CONTEXT.list_graphs())
+            .map_err(|e| Error::Other(e.to_string()))?;
+        Ok(env.new_string(json)?.into_raw())
+    }
+
+    match inner(env) {
+        Ok(graphs) => graphs,
+        Err(err) => {
+            set_last_error(err);
+            JObject::null().into_raw()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_updateToken(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+    token: JString,
+) -> jlong {
+    fn inner(env: JNIEnv, graph_uuid: JString, token: JString) -> Result<()> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+        let token: String = env.get_string(token)?.into();
+        CONTEXT.update_token(&graph_uuid, &token)?;
+        Ok(())
+    }
+
+    match inner(env, graph_uuid, token) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+/// policy: "follow", "skip" or "link"
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_setSymlinkPolicy(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+    policy: JString,
+) -> jlong {
+    fn inner(env: JNIEnv, graph_uuid: JString, policy: JString) -> Result<()> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+        let policy: String = env.get_string(policy)?.into();
+        CONTEXT.set_symlink_policy(&graph_uuid, policy.parse()?)?;
+        Ok(())
+    }
+
+    match inner(env, graph_uuid, policy) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_setBackupEnabled(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+    enabled: jboolean,
+) -> jlong {
+    fn inner(env: JNIEnv, graph_uuid: JString, enabled: jboolean) -> Result<()> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+        CONTEXT.set_backup_enabled(&graph_uuid, enabled != 0)?;
+        Ok(())
+    }
+
+    match inner(env, graph_uuid, enabled) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+/// min_size: files of at least this size are stored in chunks, not positive to disable.
+/// Versions without chunked storage write manifests of chunked files over local files.
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_setChunkedStorage(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+    min_size: jlong,
+) -> jlong {
+    fn inner(env: JNIEnv, graph_uuid: JString, min_size: jlong) -> Result<()> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+        let min_size = (min_size > 0).then_some(min_size as u64);
+        CONTEXT.set_chunked_storage(&graph_uuid, min_size)?;
+        Ok(())
+    }
+
+    match inner(env, graph_uuid, min_size) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_setCompressionEnabled(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+    enabled: jboolean,
+) -> jlong {
+    fn inner(env: JNIEnv, graph_uuid: JString, enabled: jboolean) -> Result<()> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+        CONTEXT.set_compression_enabled(&graph_uuid, enabled != 0)?;
+        Ok(())
+    }
+
+    match inner(env, graph_uuid, enabled) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+/// limits: JSON of `FileSizeLimits`, e.g. {"max_size": 10485760, "defer": true}
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_setFileSizeLimits(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+    limits: JString,
+) -> jlong {
+    fn inner(env: JNIEnv, graph_uuid: JString, limits: JString) -> Result<()> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+        let limits: String = env.get_string(limits)?.into();
+        let limits: FileSizeLimits =
+            serde_json::from_str(&limits).map_err(|e| Error::Other(e.to_string()))?;
+        CONTEXT.set_file_size_limits(&graph_uuid, limits)?;
+        Ok(())
+    }
+
+    match inner(env, graph_uuid, limits) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
             -1
         }
     }
 }
 
+/// Connections are metered until set otherwise on mobile
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_setMeteredConnection(
+    _env: JNIEnv,
+    _class: JClass,
+    metered: jboolean,
+) {
+    CONTEXT.set_metered_connection(metered != 0);
+}
+
+/// Return large files deferred to an unmetered connection, null when error
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_getDeferredUploads(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+) -> jobjectArray {
+    fn inner(env: JNIEnv, graph_uuid: JString) -> Result<jobjectArray> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+        let deferred = CONTEXT.deferred_uploads(&graph_uuid);
+        let array =
+            env.new_object_array(deferred.len() as i32, "java/lang/String", JObject::null())?;
+        for (i, path) in deferred.iter().enumerate() {
+            env.set_object_array_element(array, i as i32, env.new_string(path)?)?;
+        }
+        Ok(array)
+    }
+
+    match inner(env, graph_uuid) {
+        Ok(array) => array,
+        Err(err) => {
+            set_last_error(err);
+            JObject::null().into_raw()
+        }
+    }
+}
+
 /// Return null when error
 #[no_mangle]
 pub extern "system" fn Java_com_logseq_sync_RSFileSync_encryptFilenames(
@@ -247,7 +617,7 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_encryptFilenames(
         let graph_uuid: String = env.get_string(graph_uuid)?.into();
         let fnames = jlist_to_string_vec(env, fnames)?;
 
-        let graph = implementation::get_graph(&graph_uuid)?;
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
         let array =
             env.new_object_array(fnames.len() as i32, "java/lang/String", JObject::null())?;
         for (i, fname) in fnames.iter().enumerate() {
@@ -261,9 +631,7 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_encryptFilenames(
     match inner(env, graph_uuid, fnames) {
         Ok(array) => array,
         Err(err) => {
-            unsafe {
-                LAST_ERROR = Some(err.into());
-            }
+            set_last_error(err);
             JObject::null().into_raw()
         }
     }
@@ -285,7 +653,7 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_decryptFilenames(
         let graph_uuid: String = env.get_string(graph_uuid)?.into();
         let fnames = jlist_to_string_vec(env, fnames)?;
 
-        let graph = implementation::get_graph(&graph_uuid)?;
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
         let array =
             env.new_object_array(fnames.len() as i32, "java/lang/String", JObject::null())?;
         for (i, fname) in fnames.iter().enumerate() {
@@ -299,14 +667,13 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_decryptFilenames(
     match inner(env, graph_uuid, encrypted_fnames) {
         Ok(array) => array,
         Err(err) => {
-            unsafe {
-                LAST_ERROR = Some(err.into());
-            }
+            set_last_error(err);
             JObject::null().into_raw()
         }
     }
 }
 
+/// Return files' metadata as JSON string `{succeeded, failed}`, null when error
 #[no_mangle]
 pub extern "system" fn Java_com_logseq_sync_RSFileSync_getLocalFilesMeta(
     env: JNIEnv,
@@ -314,14 +681,13 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_getLocalFilesMeta(
     graph_uuid: JString,
     base_path: JString,
     file_paths: JObject, // List<String>
-) -> jobjectArray {
+) -> jstring {
     fn inner(
         env: JNIEnv,
         graph_uuid: JString,
         base_path: JString,
         file_paths: JObject,
-    ) -> Result<jobjectArray> {
-        let base_path = uri_to_full_path(env, base_path)?;
+    ) -> Result<jstring> {
         let graph_uuid: String = env.get_string(graph_uuid)?.into();
         let mut file_paths = jlist_to_string_vec(env, file_paths)?;
 
@@ -329,61 +695,49 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_getLocalFilesMeta(
         file_paths.dedup();
         // NOTE: Assume Android is using a case-sensitive fs.
 
-        let graph = implementation::get_graph(&graph_uuid)?;
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
+        let base_path = graph_base_path(env, &graph, base_path)?;
         let files_meta = runtime().block_on(graph.get_files_meta(base_path, file_paths))?;
-        let nfiles = files_meta.len();
-        let array =
-            env.new_object_array(nfiles as i32, "com/logseq/sync/FileMeta", JObject::null())?;
-        // TODO: use real filename key
-        for (i, (_file_name, file_meta)) in files_meta.iter().enumerate() {
-            env.set_object_array_element(array, i as i32, to_java_file_meta(env, file_meta)?)?;
-        }
-
-        Ok(array)
+        batch_json(env, &files_meta, &DryRun::default())
     }
 
     match inner(env, graph_uuid, base_path, file_paths) {
-        Ok(array) => array,
+        Ok(json) => json,
         Err(err) => {
-            unsafe {
-                LAST_ERROR = Some(err.into());
-            }
+            set_last_error(err);
             JObject::null().into_raw()
         }
     }
 }
 
+/// Return all files' metadata as JSON string `{succeeded, failed}`, null when error.
+/// Unchanged files are served from the local file index, unless `force_rescan` is set.
 #[no_mangle]
 pub extern "system" fn Java_com_logseq_sync_RSFileSync_getLocalAllFilesMeta(
     env: JNIEnv,
     _class: JClass,
     graph_uuid: JString,
     base_path: JString,
-) -> jobjectArray {
-    fn inner(env: JNIEnv, graph_uuid: JString, base_path: JString) -> Result<jobjectArray> {
-        let base_path = uri_to_full_path(env, base_path)?;
+    force_rescan: jboolean,
+) -> jstring {
+    fn inner(
+        env: JNIEnv,
+        graph_uuid: JString,
+        base_path: JString,
+        force_rescan: bool,
+    ) -> Result<jstring> {
         let graph_uuid: String = env.get_string(graph_uuid)?.into();
 
-        let graph = implementation::get_graph(&graph_uuid)?;
-        let files_meta = runtime()
-            .block_on(graph.get_all_files_meta(base_path))?
-            .collect::<Vec<_>>();
-        let nfiles = files_meta.len();
-        let array =
-            env.new_object_array(nfiles as i32, "com/logseq/sync/FileMeta", JObject::null())?;
-        for (i, file_meta) in files_meta.iter().enumerate() {
-            env.set_object_array_element(array, i as i32, to_java_file_meta(env, file_meta)?)?;
-        }
-
-        Ok(array)
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
+        let base_path = graph_base_path(env, &graph, base_path)?;
+        let files_meta = runtime().block_on(graph.get_all_files_meta(base_path, force_rescan))?;
+        batch_json(env, &files_meta, &DryRun::default())
     }
 
-    match inner(env, graph_uuid, base_path) {
-        Ok(array) => array,
+    match inner(env, graph_uuid, base_path, force_rescan != 0) {
+        Ok(json) => json,
         Err(err) => {
-            unsafe {
-                LAST_ERROR = Some(err.into());
-            }
+            set_last_error(err);
             JObject::null().into_raw()
         }
     }
@@ -398,41 +752,197 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_renameLocalFile(
     old_file_path: JString,
     new_file_path: JString,
 ) -> jlong {
-    use std::fs;
-
     let base_path = uri_to_full_path(env, base_path).unwrap();
     let from: String = env.get_string(old_file_path).unwrap().into();
     let to: String = env.get_string(new_file_path).unwrap().into();
 
-    match fs::rename(base_path.join(&from), base_path.join(&to)) {
-        Ok(()) => 0,
-        Err(err) => {
+    let (from, to) = match (
+        safe_path::join(&base_path, &from),
+        safe_path::join(&base_path, &to),
+    ) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(err), _) | (_, Err(err)) => {
             debug_log(format!("cannot rename: {:?}", err));
-            -1
+            set_last_error(err);
+            return -1;
+        }
+    };
+
+    match runtime().block_on(fs_case::rename(from, to)) {
+        Ok(()) => 0,
+        Err(err) => {
+            debug_log(format!("cannot rename: {:?}", err));
+            -1
+        }
+    }
+}
+
+/// Return unicode collisions as JSON string, null when error
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_getLocalUnicodeCollisions(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+    base_path: JString,
+) -> jstring {
+    fn inner(env: JNIEnv, graph_uuid: JString, base_path: JString) -> Result<jstring> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
+        let base_path = graph_base_path(env, &graph, base_path)?;
+
+        let collisions = runtime().block_on(graph.find_unicode_collisions(base_path))?;
+        let json = serde_json::to_string(&collisions).map_err(|e| Error::Other(e.to_string()))?;
+        Ok(env.new_string(json)?.into_raw())
+    }
+
+    match inner(env, graph_uuid, base_path) {
+        Ok(collisions) => collisions,
+        Err(err) => {
+            set_last_error(err);
+            JObject::null().into_raw()
+        }
+    }
+}
+
+/// Return skipped symlinks and special files as JSON string, null when error
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_getLocalSkippedFiles(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+    base_path: JString,
+) -> jstring {
+    fn inner(env: JNIEnv, graph_uuid: JString, base_path: JString) -> Result<jstring> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
+        let base_path = graph_base_path(env, &graph, base_path)?;
+
+        let skipped = runtime().block_on(graph.find_skipped_files(base_path))?;
+        let json = serde_json::to_string(&skipped).map_err(|e| Error::Other(e.to_string()))?;
+        Ok(env.new_string(json)?.into_raw())
+    }
+
+    match inner(env, graph_uuid, base_path) {
+        Ok(skipped) => skipped,
+        Err(err) => {
+            set_last_error(err);
+            JObject::null().into_raw()
+        }
+    }
+}
+
+/// Prune version and backup files, options as JSON string.
+/// Return cleanup report as JSON string, null when error
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_cleanupVersionFiles(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+    base_path: JString,
+    options: JString,
+) -> jstring {
+    fn inner(
+        env: JNIEnv,
+        graph_uuid: JString,
+        base_path: JString,
+        options: JString,
+    ) -> Result<jstring> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+        let options: String = env.get_string(options)?.into();
+        let options: CleanupOptions =
+            serde_json::from_str(&options).map_err(|e| Error::Other(e.to_string()))?;
+
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
+        let base_path = graph_base_path(env, &graph, base_path)?;
+
+        let report = runtime().block_on(housekeeping::cleanup(base_path, &options))?;
+        let json = serde_json::to_string(&report).map_err(|e| Error::Other(e.to_string()))?;
+        Ok(env.new_string(json)?.into_raw())
+    }
+
+    match inner(env, graph_uuid, base_path, options) {
+        Ok(report) => report,
+        Err(err) => {
+            set_last_error(err);
+            JObject::null().into_raw()
+        }
+    }
+}
+
+/// 1 for case sensitive, 0 for case insensitive, -1 when error
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_isFsCaseSensitive(
+    env: JNIEnv,
+    _class: JClass,
+    base_path: JString,
+) -> jlong {
+    fn inner(env: JNIEnv, base_path: JString) -> Result<bool> {
+        let base_path = uri_to_full_path(env, base_path)?;
+        Ok(runtime().block_on(CONTEXT.is_case_sensitive(base_path))?)
+    }
+
+    match inner(env, base_path) {
+        Ok(sensitive) => sensitive as _,
+        Err(err) => {
+            set_last_error(err);
+            -1
         }
     }
 }
 
+/// Return deleted files as JSON string `{succeeded, failed}`, with `planned` changes of
+/// a dry run, null when error
 #[no_mangle]
 pub extern "system" fn Java_com_logseq_sync_RSFileSync_deleteLocalFiles(
     env: JNIEnv,
     _class: JClass,
-    _graph_uuid: JString,
+    graph_uuid: JString,
     base_path: JString,
     file_paths: JObject, // List<String>
-) {
-    use std::fs;
+    dry_run: jboolean,
+    operation_id: JString, // nullable
+) -> jstring {
+    fn inner(
+        env: JNIEnv,
+        graph_uuid: JString,
+        base_path: JString,
+        file_paths: JObject,
+        dry_run: bool,
+        operation_id: JString,
+    ) -> Result<jstring> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+        let file_paths = jlist_to_string_vec(env, file_paths)?;
 
-    let base_path = uri_to_full_path(env, base_path).unwrap();
-    let file_paths = jlist_to_string_vec(env, file_paths).unwrap();
-    for file_path in file_paths {
-        let full_path = base_path.join(file_path);
-        debug_log(format!("delete file {:?}", full_path));
-        let _ = fs::remove_file(full_path); // ignore any errors
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
+        let base_path = graph_base_path(env, &graph, base_path)?;
+        let cancel = graph.cancel_handle(get_optional_string(env, operation_id)?);
+        let dry_run = DryRun::new(dry_run);
+
+        let deleted = runtime()
+            .block_on(graph.delete_local_files(base_path, file_paths, &dry_run, &cancel))?;
+        batch_json(env, &deleted, &dry_run)
+    }
+
+    match inner(
+        env,
+        graph_uuid,
+        base_path,
+        file_paths,
+        dry_run != 0,
+        operation_id,
+    ) {
+        Ok(json) => json,
+        Err(err) => {
+            set_last_error(err);
+            JObject::null().into_raw()
+        }
     }
 }
 
-// returns String[]
+/// Return fetched files, ready to be merged, as JSON string `{succeeded, failed}`,
+/// null when error
 #[no_mangle]
 pub extern "system" fn Java_com_logseq_sync_RSFileSync_fetchRemoteFiles(
     env: JNIEnv,
@@ -441,45 +951,59 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_fetchRemoteFiles(
     base_path: JString,
     file_paths: JObject, // List<String>
     token: JString,
-) -> jobjectArray {
+    preserve_mtime: jboolean,
+    dry_run: jboolean,
+    operation_id: JString, // nullable
+) -> jstring {
+    #[allow(clippy::too_many_arguments)]
     fn inner(
         env: JNIEnv,
         graph_uuid: JString,
         base_path: JString,
         file_paths: JObject, // List<String>
         token: JString,
-    ) -> Result<jobjectArray> {
-        let base_path = uri_to_full_path(env, base_path)?;
+        preserve_mtime: bool,
+        dry_run: bool,
+        operation_id: JString,
+    ) -> Result<jstring> {
         let graph_uuid: String = env.get_string(graph_uuid)?.into();
-        let token: String = env.get_string(token)?.into();
         let file_paths = jlist_to_string_vec(env, file_paths)?;
-
-        let graph = implementation::get_graph(&graph_uuid)?;
-
-        let files_to_be_merged =
-            runtime().block_on(graph.fetch_remote_files(base_path, file_paths, &token))?;
-        let array = env.new_object_array(
-            files_to_be_merged.len() as i32,
-            "java/lang/String",
-            JObject::null(),
-        )?;
-        for (i, fname) in files_to_be_merged.iter().enumerate() {
-            env.set_object_array_element(array, i as i32, env.new_string(fname)?)?;
-        }
-        Ok(array)
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
+        let base_path = graph_base_path(env, &graph, base_path)?;
+        let token = graph_token(env, &graph, token)?;
+        let cancel = graph.cancel_handle(get_optional_string(env, operation_id)?);
+        let dry_run = DryRun::new(dry_run);
+
+        let fetched = runtime().block_on(graph.fetch_remote_files(
+            base_path,
+            file_paths,
+            &token,
+            preserve_mtime,
+            &dry_run,
+            &cancel,
+        ))?;
+        batch_json(env, &fetched, &dry_run)
     }
 
-    match inner(env, graph_uuid, base_path, file_paths, token) {
-        Ok(array) => array,
+    match inner(
+        env,
+        graph_uuid,
+        base_path,
+        file_paths,
+        token,
+        preserve_mtime != 0,
+        dry_run != 0,
+        operation_id,
+    ) {
+        Ok(json) => json,
         Err(err) => {
-            unsafe {
-                LAST_ERROR = Some(err.into());
-            }
+            set_last_error(err);
             JObject::null().into_raw()
         }
     }
 }
 
+/// Return updated files as JSON string `{succeeded, failed, conflicts}`, null when error
 #[no_mangle]
 pub extern "system" fn Java_com_logseq_sync_RSFileSync_updateLocalFiles(
     env: JNIEnv,
@@ -488,37 +1012,131 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_updateLocalFiles(
     base_path: JString,
     file_paths: JObject, // List<String>
     token: JString,
-) -> jlong {
+    preserve_mtime: jboolean,
+    dry_run: jboolean,
+    operation_id: JString, // nullable
+) -> jstring {
+    #[allow(clippy::too_many_arguments)]
     fn inner(
         env: JNIEnv,
         graph_uuid: JString,
         base_path: JString,
         file_paths: JObject, // List<String>
         token: JString,
-    ) -> Result<()> {
-        let base_path = uri_to_full_path(env, base_path)?;
+        preserve_mtime: bool,
+        dry_run: bool,
+        operation_id: JString,
+    ) -> Result<jstring> {
         let graph_uuid: String = env.get_string(graph_uuid)?.into();
-        let token: String = env.get_string(token)?.into();
         let file_paths = jlist_to_string_vec(env, file_paths)?;
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
+        let base_path = graph_base_path(env, &graph, base_path)?;
+        let token = graph_token(env, &graph, token)?;
+        let cancel = graph.cancel_handle(get_optional_string(env, operation_id)?);
+        let options = DownloadOptions {
+            conflict: ConflictOptions::default(),
+            preserve_mtime,
+            dry_run: DryRun::new(dry_run),
+        };
+
+        let update = runtime()
+            .block_on(graph.update_local_files(base_path, file_paths, &token, &options, &cancel))?;
+        batch_json(env, &update, &options.dry_run)
+    }
 
-        let graph = implementation::get_graph(&graph_uuid)?;
-
-        runtime().block_on(graph.update_local_files(base_path, file_paths, &token))?;
+    match inner(
+        env,
+        graph_uuid,
+        base_path,
+        file_paths,
+        token,
+        preserve_mtime != 0,
+        dry_run != 0,
+        operation_id,
+    ) {
+        Ok(json) => json,
+        Err(err) => {
+            set_last_error(err);
+            JObject::null().into_raw()
+        }
+    }
+}
 
-        Ok(())
+/// Return updated files as JSON string `{succeeded, failed, conflicts}`, null when error
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_updateLocalFilesWithPolicy(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+    base_path: JString,
+    file_paths: JObject, // List<String>
+    token: JString,
+    conflict_policy: JString,
+    device_name: JString,
+    preserve_mtime: jboolean,
+    dry_run: jboolean,
+    operation_id: JString, // nullable
+) -> jstring {
+    #[allow(clippy::too_many_arguments)]
+    fn inner(
+        env: JNIEnv,
+        graph_uuid: JString,
+        base_path: JString,
+        file_paths: JObject, // List<String>
+        token: JString,
+        conflict_policy: JString,
+        device_name: JString,
+        preserve_mtime: bool,
+        dry_run: bool,
+        operation_id: JString,
+    ) -> Result<jstring> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+        let file_paths = jlist_to_string_vec(env, file_paths)?;
+        let conflict_policy: String = env.get_string(conflict_policy)?.into();
+        let device_name: String = env.get_string(device_name)?.into();
+        let operation_id = get_optional_string(env, operation_id)?;
+
+        let conflict = ConflictOptions {
+            policy: conflict_policy.parse()?,
+            device_name,
+        };
+
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
+        let base_path = graph_base_path(env, &graph, base_path)?;
+        let token = graph_token(env, &graph, token)?;
+        let cancel = graph.cancel_handle(operation_id);
+        let options = DownloadOptions {
+            conflict,
+            preserve_mtime,
+            dry_run: DryRun::new(dry_run),
+        };
+
+        let update = runtime()
+            .block_on(graph.update_local_files(base_path, file_paths, &token, &options, &cancel))?;
+        batch_json(env, &update, &options.dry_run)
     }
 
-    match inner(env, graph_uuid, base_path, file_paths, token) {
-        Ok(_) => 0,
+    match inner(
+        env,
+        graph_uuid,
+        base_path,
+        file_paths,
+        token,
+        conflict_policy,
+        device_name,
+        preserve_mtime != 0,
+        dry_run != 0,
+        operation_id,
+    ) {
+        Ok(json) => json,
         Err(err) => {
-            unsafe {
-                LAST_ERROR = Some(err.into());
-            }
-            -1
+            set_last_error(err);
+            JObject::null().into_raw()
         }
     }
 }
 
+/// Return downloaded version files as JSON string `{succeeded, failed}`, null when error
 #[no_mangle]
 pub extern "system" fn Java_com_logseq_sync_RSFileSync_updateLocalVersionFiles(
     env: JNIEnv,
@@ -527,35 +1145,40 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_updateLocalVersionFiles(
     base_path: JString,
     file_paths: JObject, // List<String>
     token: JString,
-) -> jlong {
+    operation_id: JString, // nullable
+) -> jstring {
     fn inner(
         env: JNIEnv,
         graph_uuid: JString,
         base_path: JString,
         file_paths: JObject,
         token: JString,
-    ) -> Result<()> {
-        let base_path = uri_to_full_path(env, base_path)?;
+        operation_id: JString,
+    ) -> Result<jstring> {
         let graph_uuid: String = env.get_string(graph_uuid)?.into();
-        let token: String = env.get_string(token)?.into();
         let file_paths = jlist_to_string_vec(env, file_paths)?;
 
-        let graph = implementation::get_graph(&graph_uuid)?;
-
-        runtime().block_on(graph.update_local_version_files(base_path, file_paths, &token))?;
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
+        let base_path = graph_base_path(env, &graph, base_path)?;
+        let token = graph_token(env, &graph, token)?;
+        let cancel = graph.cancel_handle(get_optional_string(env, operation_id)?);
 
-        Ok(())
+        let downloaded = runtime()
+            .block_on(graph.update_local_version_files(base_path, file_paths, &token, &cancel))?;
+        batch_json(env, &downloaded, &DryRun::default())
     }
 
-    match inner(env, graph_uuid, base_path, file_paths, token) {
-        Ok(_) => 0,
+    match inner(env, graph_uuid, base_path, file_paths, token, operation_id) {
+        Ok(json) => json,
         Err(err) => {
-            unsafe { LAST_ERROR = Some(err) };
-            return -1;
+            set_last_error(err);
+            JObject::null().into_raw()
         }
     }
 }
 
+/// Return deleted files as JSON string `{txid, succeeded, failed, skipped}`,
+/// with `planned` changes of a dry run, null when error
 #[no_mangle]
 pub extern "system" fn Java_com_logseq_sync_RSFileSync_deleteRemoteFiles(
     env: JNIEnv,
@@ -565,7 +1188,10 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_deleteRemoteFiles(
     file_paths: JObject, // List<String>
     token: JString,
     txid: jlong,
-) -> jlong {
+    dry_run: jboolean,
+    operation_id: JString, // nullable
+) -> jstring {
+    #[allow(clippy::too_many_arguments)]
     fn inner(
         env: JNIEnv,
         graph_uuid: JString,
@@ -573,29 +1199,43 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_deleteRemoteFiles(
         file_paths: JObject, // List<String>
         token: JString,
         txid: jlong,
-    ) -> Result<i64> {
-        let base_path = uri_to_full_path(env, base_path)?;
+        dry_run: bool,
+        operation_id: JString,
+    ) -> Result<jstring> {
         let file_paths = jlist_to_string_vec(env, file_paths)?;
         let graph_uuid: String = env.get_string(graph_uuid)?.into();
-        let token: String = env.get_string(token)?.into();
-
-        let graph = implementation::get_graph(&graph_uuid)?;
 
-        let txid =
-            runtime().block_on(graph.delete_remote_files(base_path, file_paths, txid, &token))?;
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
+        let base_path = graph_base_path(env, &graph, base_path)?;
+        let token = graph_token(env, &graph, token)?;
+        let cancel = graph.cancel_handle(get_optional_string(env, operation_id)?);
+        let dry_run = DryRun::new(dry_run);
 
-        Ok(txid)
+        let update = runtime().block_on(
+            graph.delete_remote_files(base_path, file_paths, txid, &token, &dry_run, &cancel),
+        )?;
+        batch_json(env, &update, &dry_run)
     }
 
-    match inner(env, graph_uuid, base_path, file_paths, token, txid) {
-        Ok(txid) => txid,
+    match inner(
+        env,
+        graph_uuid,
+        base_path,
+        file_paths,
+        token,
+        txid,
+        dry_run != 0,
+        operation_id,
+    ) {
+        Ok(json) => json,
         Err(err) => {
-            unsafe { LAST_ERROR = Some(err) };
-            return -1;
+            set_last_error(err);
+            JObject::null().into_raw()
         }
     }
 }
 
+/// Return uploaded files as JSON string `{txid, succeeded, failed, skipped}`, null when error
 #[no_mangle]
 pub extern "system" fn Java_com_logseq_sync_RSFileSync_updateRemoteFiles(
     env: JNIEnv,
@@ -605,7 +1245,11 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_updateRemoteFiles(
     file_paths: JObject, // List<String>
     token: JString,
     txid: jlong,
-) -> jlong {
+    force: jboolean,
+    dry_run: jboolean,
+    operation_id: JString, // nullable
+) -> jstring {
+    #[allow(clippy::too_many_arguments)]
     fn inner(
         env: JNIEnv,
         graph_uuid: JString,
@@ -613,24 +1257,184 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_updateRemoteFiles(
         file_paths: JObject, // List<String>
         token: JString,
         txid: jlong,
-    ) -> Result<i64> {
-        let base_path = uri_to_full_path(env, base_path)?;
+        force: bool,
+        dry_run: bool,
+        operation_id: JString,
+    ) -> Result<jstring> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+        let file_paths = jlist_to_string_vec(env, file_paths)?;
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
+        let base_path = graph_base_path(env, &graph, base_path)?;
+        let token = graph_token(env, &graph, token)?;
+        let cancel = graph.cancel_handle(get_optional_string(env, operation_id)?);
+        let options = UploadOptions {
+            force,
+            dry_run: DryRun::new(dry_run),
+            ..Default::default()
+        };
+
+        let update = runtime().block_on(
+            graph.update_remote_files(base_path, file_paths, txid, &token, &options, &cancel),
+        )?;
+        batch_json(env, &update, &options.dry_run)
+    }
+
+    match inner(
+        env,
+        graph_uuid,
+        base_path,
+        file_paths,
+        token,
+        txid,
+        force != 0,
+        dry_run != 0,
+        operation_id,
+    ) {
+        Ok(json) => json,
+        Err(err) => {
+            set_last_error(err);
+            JObject::null().into_raw()
+        }
+    }
+}
+
+/// Return merge reports as JSON string `{succeeded, failed}`, null when error
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_mergeRemoteFiles(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+    base_path: JString,
+    file_paths: JObject, // List<String>
+    write_conflict_markers: jboolean,
+) -> jstring {
+    fn inner(
+        env: JNIEnv,
+        graph_uuid: JString,
+        base_path: JString,
+        file_paths: JObject,
+        write_conflict_markers: bool,
+    ) -> Result<jstring> {
         let graph_uuid: String = env.get_string(graph_uuid)?.into();
-        let token: String = env.get_string(token)?.into();
         let file_paths = jlist_to_string_vec(env, file_paths)?;
 
-        let graph = implementation::get_graph(&graph_uuid)?;
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
+        let base_path = graph_base_path(env, &graph, base_path)?;
 
-        let txid = runtime()
-            .block_on(graph.update_remote_files(base_path, file_paths, txid, &token, None))?;
-        Ok(txid)
+        let reports = runtime().block_on(graph.merge_remote_files(
+            base_path,
+            file_paths,
+            write_conflict_markers,
+        ))?;
+        batch_json(env, &reports, &DryRun::default())
     }
 
-    match inner(env, graph_uuid, base_path, file_paths, token, txid) {
-        Ok(txid) => txid,
+    match inner(
+        env,
+        graph_uuid,
+        base_path,
+        file_paths,
+        write_conflict_markers != 0,
+    ) {
+        Ok(reports) => reports,
         Err(err) => {
-            unsafe { LAST_ERROR = Some(err) };
-            return -1;
+            set_last_error(err);
+            JObject::null().into_raw()
+        }
+    }
+}
+
+/// Return sync plan as JSON string, null when error
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_planSync(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+    base_path: JString,
+    token: JString,
+    txid: jlong,
+) -> jstring {
+    fn inner(
+        env: JNIEnv,
+        graph_uuid: JString,
+        base_path: JString,
+        token: JString,
+        txid: jlong,
+    ) -> Result<jstring> {
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+
+        let graph = CONTEXT.get_graph(&graph_uuid)?;
+        let base_path = graph_base_path(env, &graph, base_path)?;
+        let token = graph_token(env, &graph, token)?;
+
+        let plan = runtime().block_on(graph.plan_sync(base_path, txid, &token))?;
+        let json = serde_json::to_string(&plan).map_err(|e| Error::Other(e.to_string()))?;
+        Ok(env.new_string(json)?.into_raw())
+    }
+
+    match inner(env, graph_uuid, base_path, token, txid) {
+        Ok(plan) => plan,
+        Err(err) => {
+            set_last_error(err);
+            JObject::null().into_raw()
+        }
+    }
+}
+
+/// Watch local file changes, events are pushed via `fileChangeNotify`
+/// debounceMs: quiet interval before a changed file is reported, 0 for default
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_watchGraph(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+    base_path: JString,
+    debounce_ms: jlong,
+) -> jlong {
+    fn inner(
+        env: JNIEnv,
+        graph_uuid: JString,
+        base_path: JString,
+        debounce_ms: jlong,
+    ) -> Result<()> {
+        let base_path = if base_path.is_null() {
+            None
+        } else {
+            Some(uri_to_full_path(env, base_path)?)
+        };
+        let graph_uuid: String = env.get_string(graph_uuid)?.into();
+
+        let debounce = if debounce_ms > 0 {
+            std::time::Duration::from_millis(debounce_ms as _)
+        } else {
+            watcher::DEFAULT_DEBOUNCE
+        };
+        CONTEXT.watch(&graph_uuid, base_path, debounce)?;
+        Ok(())
+    }
+
+    match inner(env, graph_uuid, base_path, debounce_ms) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_logseq_sync_RSFileSync_unwatchGraph(
+    env: JNIEnv,
+    _class: JClass,
+    graph_uuid: JString,
+) -> jlong {
+    let graph_uuid: String = env.get_string(graph_uuid).unwrap().into();
+
+    match CONTEXT.unwatch(&graph_uuid) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
         }
     }
 }
@@ -648,7 +1452,7 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_ageEncryptWithPassphrase(
     match lsq_encryption::encrypt_with_user_passphrase(&pass, &buf, true) {
         Ok(ret) => env.byte_array_from_slice(&ret).unwrap(),
         Err(e) => {
-            unsafe { LAST_ERROR = Some(e.into()) };
+            set_last_error(e);
             JObject::null().into_raw()
         }
     }
@@ -667,7 +1471,7 @@ pub extern "system" fn Java_com_logseq_sync_RSFileSync_ageDecryptWithPassphrase(
     match lsq_encryption::decrypt_with_user_passphrase(&pass, &buf) {
         Ok(ret) => env.byte_array_from_slice(&ret).unwrap(),
         Err(e) => {
-            unsafe { LAST_ERROR = Some(e.into()) };
+            set_last_error(e);
             JObject::null().into_raw()
         }
     }
@@ -709,6 +1513,42 @@ fn jlist_to_string_vec(env: JNIEnv, list: JObject) -> jni::errors::Result<Vec<St
         .collect()
 }
 
+/// convert nullable java:String[] to rust:Vec<String>, empty when null
+fn jarray_to_string_vec(env: JNIEnv, array: jobjectArray) -> jni::errors::Result<Vec<String>> {
+    if array.is_null() {
+        return Ok(vec![]);
+    }
+    (0..env.get_array_length(array)?)
+        .map(|i| {
+            let s = env.get_object_array_element(array, i)?;
+            env.get_string(s.into()).map(String::from)
+        })
+        .collect()
+}
+
+/// convert nullable java:String to rust:Option<String>
+fn get_optional_string(env: JNIEnv, s: JString) -> jni::errors::Result<Option<String>> {
+    if s.is_null() {
+        Ok(None)
+    } else {
+        env.get_string(s).map(|s| Some(s.into()))
+    }
+}
+
+/// base path passed, or the one set for the graph when null
+fn graph_base_path(env: JNIEnv, graph: &Graph, base_path: JString) -> Result<PathBuf> {
+    if base_path.is_null() {
+        Ok(graph.base_path_or(None::<PathBuf>)?)
+    } else {
+        Ok(uri_to_full_path(env, base_path)?)
+    }
+}
+
+/// token passed, or the one set for the graph when null
+fn graph_token(env: JNIEnv, graph: &Graph, token: JString) -> Result<String> {
+    Ok(graph.token_or(get_optional_string(env, token)?.as_deref())?)
+}
+
 /// convert Uri to path
 fn uri_to_full_path<'a>(env: JNIEnv<'a>, path: JString) -> jni::errors::Result<PathBuf> {
     let uri_class = env.find_class("android/net/Uri").unwrap();
@@ -1,9 +1,11 @@
 pub use error::SyncError as Error;
-pub use sync::{reset_user, set_dev, set_prod, set_proxy, SyncClient};
+pub use sync::{Endpoint, SyncClient, SyncContext};
 
 mod doh;
 mod error;
 pub mod helpers;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 pub mod sync;
 pub mod types;
 
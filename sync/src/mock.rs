@@ -0,0 +1,159 @@
+//! Mock of the sync API server, for tests.
+//!
+//! Serves plain HTTP/1.1 on localhost, one request per connection. Requests of API calls
+//! and presigned URLs are answered by a handler, and recorded for assertions.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::Endpoint;
+
+const API_PREFIX: &str = "/file-sync/";
+
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub method: String,
+    /// path after the API prefix, e.g. "get_all_files"
+    pub path: String,
+    pub body: Vec<u8>,
+    url_base: &'static str,
+}
+
+impl MockRequest {
+    /// URL served by the mock server, e.g. for presigned URLs in responses
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.url_base, path)
+    }
+
+    /// JSON payload of an API call
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap_or_default()
+    }
+}
+
+type Handler = dyn Fn(&MockRequest) -> Vec<u8> + Send + Sync;
+
+pub struct MockServer {
+    url_base: &'static str,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+impl MockServer {
+    /// Start serving on a random port, in the current tokio runtime.
+    /// The handler returns the response body, always with status 200.
+    pub async fn start<F>(handler: F) -> MockServer
+    where
+        F: Fn(&MockRequest) -> Vec<u8> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind mock server");
+        let addr = listener.local_addr().expect("mock server address");
+        // endpoints are static
+        let url_base: &str = Box::leak(format!("http://{}{}", addr, API_PREFIX).into_boxed_str());
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let handler: Arc<Handler> = Arc::new(handler);
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, url_base, &*handler, &recorded).await {
+                        log::warn!("mock server: {}", e);
+                    }
+                });
+            }
+        });
+        MockServer { url_base, requests }
+    }
+
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            name: "mock",
+            url_base: self.url_base,
+            bucket: "mock-bucket",
+            region: "us-east-1",
+        }
+    }
+
+    /// URL served by the mock server, e.g. for presigned URLs
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.url_base, path)
+    }
+
+    /// Requests received, in order
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Received requests of a path
+    pub fn requests_of(&self, path: &str) -> Vec<MockRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.path == path)
+            .collect()
+    }
+}
+
+/// JSON response body
+pub fn json_body(value: serde_json::Value) -> Vec<u8> {
+    value.to_string().into_bytes()
+}
+
+async fn serve(
+    stream: TcpStream,
+    url_base: &'static str,
+    handler: &Handler,
+    recorded: &Mutex<Vec<MockRequest>>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default();
+    let path = path.strip_prefix(API_PREFIX).unwrap_or(path).to_string();
+
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        reader.read_line(&mut line).await?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or_default();
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+
+    let request = MockRequest {
+        method,
+        path,
+        body,
+        url_base,
+    };
+    let response = handler(&request);
+    recorded.lock().unwrap().push(request);
+
+    let stream = reader.get_mut();
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                response.len()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.write_all(&response).await?;
+    stream.shutdown().await
+}
@@ -1,6 +1,6 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
-// use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 use serde_json::json;
@@ -10,108 +10,146 @@ use crate::helpers::ProgressedBytesStream;
 use crate::types::{self, Credentials, TempCredential};
 use crate::Result;
 
-static mut TEMP_CREDENTIAL: Option<TempCredential> = None;
+/// Sync environment: API URL base, S3 bucket and region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoint {
+    pub(crate) name: &'static str,
+    pub(crate) url_base: &'static str,
+    pub(crate) bucket: &'static str,
+    pub(crate) region: &'static str,
+}
 
-static mut HTTPS_PROXY: Option<String> = None;
+impl Endpoint {
+    pub const DEV: Endpoint = Endpoint {
+        name: "dev",
+        url_base: "https://api-dev.logseq.com/file-sync/",
+        bucket: "logseq-file-sync-bucket",
+        region: "us-east-2",
+    };
+
+    pub const PROD: Endpoint = Endpoint {
+        name: "prod",
+        url_base: "https://api.logseq.com/file-sync/",
+        bucket: "logseq-file-sync-bucket-prod",
+        region: "us-east-1",
+    };
+
+    /// Parse env name, e.g. "production", "prod", "development" or "dev"
+    pub fn from_env(env: &str) -> Option<Endpoint> {
+        match env {
+            "production" | "product" | "prod" => Some(Endpoint::PROD),
+            "development" | "develop" | "dev" => Some(Endpoint::DEV),
+            _ => None,
+        }
+    }
 
-// API URL base
-static mut URL_BASE: &str = "https://api-dev.logseq.com/file-sync/";
-static mut BUCKET: &str = "logseq-file-sync-bucket";
-static mut REGION: &str = "us-east-2";
+    /// "dev" or "prod"
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
 
-const URL_BASE_DEV: &str = "https://api-dev.logseq.com/file-sync/";
-const BUCKET_DEV: &str = "logseq-file-sync-bucket";
-const REGION_DEV: &str = "us-east-2";
+fn build_http_client(proxy: Option<&str>) -> Result<reqwest::Client> {
+    let accept_invalid_certs = true;
+    // env::var("NODE_TLS_REJECT_UNAUTHORIZED").unwrap_or_default() == "0";
+    let mut builder = reqwest::Client::builder()
+        .user_agent("Logseq-sync/0.3")
+        .connection_verbose(false)
+        // .dns_resolver(Arc::new(crate::doh::DoHResolver))
+        .timeout(Duration::from_secs(30))
+        .connect_timeout(Duration::from_secs(15))
+        .http2_keep_alive_interval(Duration::from_secs(10))
+        .http2_keep_alive_timeout(Duration::from_secs(60))
+        .http2_keep_alive_while_idle(true);
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::https(proxy)?);
+    }
+    if accept_invalid_certs {
+        // log::info!("NODE_TLS_REJECT_UNAUTHORIZED=0, won't validate certs");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    Ok(builder.build()?)
+}
 
-const URL_BASE_PROD: &str = "https://api.logseq.com/file-sync/";
-const BUCKET_PROD: &str = "logseq-file-sync-bucket-prod";
-const REGION_PROD: &str = "us-east-1";
+// (API URL base, auth token) => temp credential
+type CredentialCache = HashMap<(&'static str, String), TempCredential>;
 
-fn url_base() -> &'static str {
-    unsafe { URL_BASE }
+/// Shared state of sync clients: temp credential cache and http client
+pub struct SyncContext {
+    client: RwLock<reqwest::Client>,
+    temp_credentials: Mutex<CredentialCache>,
 }
 
-fn bucket() -> &'static str {
-    unsafe { BUCKET }
+impl Default for SyncContext {
+    fn default() -> Self {
+        SyncContext {
+            client: RwLock::new(build_http_client(None).expect("http client")),
+            temp_credentials: Mutex::new(HashMap::new()),
+        }
+    }
 }
 
-fn region() -> &'static str {
-    unsafe { REGION }
-}
+impl SyncContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set https proxy, clients created afterwards use the new proxy
+    pub fn set_proxy(&self, proxy: Option<&str>) -> Result<()> {
+        let client = build_http_client(proxy)?;
+        *self.client.write().unwrap() = client;
+        Ok(())
+    }
 
-/// set environment to prod
-pub fn set_prod() {
-    unsafe {
-        URL_BASE = URL_BASE_PROD;
-        BUCKET = BUCKET_PROD;
-        REGION = REGION_PROD;
+    pub fn reset_user(&self) {
+        self.temp_credentials.lock().unwrap().clear();
     }
-}
 
-pub fn set_dev() {
-    unsafe {
-        URL_BASE = URL_BASE_DEV;
-        BUCKET = BUCKET_DEV;
-        REGION = REGION_DEV;
+    /// Drop cached temp credentials of an auth token, of all environments
+    pub fn forget_token(&self, token: &str) {
+        self.temp_credentials
+            .lock()
+            .unwrap()
+            .retain(|(_, t), _| t != token);
     }
-}
 
-pub fn set_proxy(proxy: Option<&str>) -> Result<()> {
-    unsafe {
-        if let Some(proxy) = proxy {
-            // Check proxy
-            let _ = reqwest::Proxy::https(proxy)?;
-            HTTPS_PROXY = Some(proxy.to_string());
-        } else {
-            HTTPS_PROXY = None;
-        }
+    fn temp_credential(&self, endpoint: &Endpoint, token: &str) -> Option<TempCredential> {
+        self.temp_credentials
+            .lock()
+            .unwrap()
+            .get(&(endpoint.url_base, token.to_string()))
+            .cloned()
+    }
+
+    fn cache_temp_credential(&self, endpoint: &Endpoint, token: &str, credential: TempCredential) {
+        self.temp_credentials
+            .lock()
+            .unwrap()
+            .insert((endpoint.url_base, token.to_string()), credential);
     }
-    Ok(())
-}
 
-pub fn reset_user() {
-    unsafe {
-        TEMP_CREDENTIAL = None;
+    fn http_client(&self) -> reqwest::Client {
+        // cheap clone, the connection pool is shared
+        self.client.read().unwrap().clone()
     }
 }
 
 pub struct SyncClient {
+    ctx: Arc<SyncContext>,
     client: reqwest::Client,
+    endpoint: Endpoint,
     txid: i64,
     graph_uuid: String,
     credentials: Option<Credentials>,
     s3_prefix: Option<String>,
     auth_token: String,
 }
-unsafe impl Sync for SyncClient {}
-unsafe impl Send for SyncClient {}
-
 impl SyncClient {
-    pub fn new(token: &str) -> SyncClient {
-        let accept_invalid_certs = true;
-        // env::var("NODE_TLS_REJECT_UNAUTHORIZED").unwrap_or_default() == "0";
-        let client = {
-            let mut builder = reqwest::Client::builder()
-                .user_agent("Logseq-sync/0.3")
-                .connection_verbose(false)
-                // .dns_resolver(Arc::new(crate::doh::DoHResolver))
-                .timeout(Duration::from_secs(30))
-                .connect_timeout(Duration::from_secs(15))
-                .http2_keep_alive_interval(Duration::from_secs(10))
-                .http2_keep_alive_timeout(Duration::from_secs(60))
-                .http2_keep_alive_while_idle(true);
-            if let Some(proxy) = unsafe { HTTPS_PROXY.as_ref() } {
-                builder = builder.proxy(reqwest::Proxy::https(proxy).unwrap());
-            }
-            if accept_invalid_certs {
-                // log::info!("NODE_TLS_REJECT_UNAUTHORIZED=0, won't validate certs");
-                builder = builder.danger_accept_invalid_certs(true);
-            }
-            builder.build().unwrap()
-        };
-
+    pub fn new(ctx: &Arc<SyncContext>, endpoint: Endpoint, token: &str) -> SyncClient {
         SyncClient {
-            client,
+            ctx: ctx.clone(),
+            client: ctx.http_client(),
+            endpoint,
             txid: -1, // uninited
             credentials: None,
             graph_uuid: String::new(),
@@ -130,11 +168,12 @@ impl SyncClient {
     // API helpers
     // ==========
 
-    // update temp credentials if needed
-    pub async fn refresh_temp_credential(&mut self) -> Result<()> {
-        unsafe {
-            if self.credentials.is_none() && TEMP_CREDENTIAL.is_some() {
-                let temp_credential = TEMP_CREDENTIAL.clone().unwrap();
+    /// Update temp credentials if needed, return true if a new credential is fetched
+    pub async fn refresh_temp_credential(&mut self) -> Result<bool> {
+        if self.credentials.is_none() {
+            if let Some(temp_credential) =
+                self.ctx.temp_credential(&self.endpoint, &self.auth_token)
+            {
                 self.credentials = Some(temp_credential.credentials);
                 self.s3_prefix = Some(temp_credential.s3_prefix);
             }
@@ -147,9 +186,11 @@ impl SyncClient {
         {
             let temp_credential = self.get_temp_credential().await?;
 
-            unsafe {
-                TEMP_CREDENTIAL = Some(temp_credential.clone());
-            }
+            self.ctx.cache_temp_credential(
+                &self.endpoint,
+                &self.auth_token,
+                temp_credential.clone(),
+            );
 
             log::debug!(
                 "credential refreshed, next expiration at {}",
@@ -157,8 +198,9 @@ impl SyncClient {
             );
             self.credentials = Some(temp_credential.credentials);
             self.s3_prefix = Some(temp_credential.s3_prefix);
+            return Ok(true);
         }
-        Ok(())
+        Ok(false)
     }
 
     // ==========
@@ -172,7 +214,7 @@ impl SyncClient {
         let payload = json!({ "GraphName": name });
         let resp = self
             .client
-            .post(url_base().to_owned() + "create_graph")
+            .post(self.endpoint.url_base.to_owned() + "create_graph")
             .body(payload.to_string())
             .bearer_auth(&self.auth_token)
             .header("Content-Type", "application/octet-stream")
@@ -191,7 +233,7 @@ impl SyncClient {
         let payload = json!({ "GraphName": name });
         let resp = self
             .client
-            .post(url_base().to_owned() + "get_graph")
+            .post(self.endpoint.url_base.to_owned() + "get_graph")
             .body(payload.to_string())
             .bearer_auth(&self.auth_token)
             .header("Content-Type", "application/octet-stream")
@@ -210,7 +252,7 @@ impl SyncClient {
         let payload = json!({ "GraphUUID": uuid });
         let resp = self
             .client
-            .post(url_base().to_owned() + "get_graph")
+            .post(self.endpoint.url_base.to_owned() + "get_graph")
             .body(payload.to_string())
             .bearer_auth(&self.auth_token)
             .header("Content-Type", "application/octet-stream")
@@ -228,7 +270,7 @@ impl SyncClient {
     pub async fn list_graphs(&self) -> Result<Vec<types::SimpleGraph>> {
         let resp = self
             .client
-            .post(url_base().to_owned() + "list_graphs")
+            .post(self.endpoint.url_base.to_owned() + "list_graphs")
             .bearer_auth(&self.auth_token)
             .header("Content-Type", "application/octet-stream")
             .send()
@@ -246,7 +288,7 @@ impl SyncClient {
         let payload = json!({ "GraphUUID": self.graph_uuid });
         let resp = self
             .client
-            .post(url_base().to_owned() + "get_all_files")
+            .post(self.endpoint.url_base.to_owned() + "get_all_files")
             .body(payload.to_string())
             .bearer_auth(&self.auth_token)
             .header("Content-Type", "application/octet-stream")
@@ -275,7 +317,7 @@ impl SyncClient {
         });
         let resp = self
             .client
-            .post(url_base().to_owned() + "get_files")
+            .post(self.endpoint.url_base.to_owned() + "get_files")
             .body(payload.to_string())
             .bearer_auth(&self.auth_token)
             .header("Content-Type", "application/octet-stream")
@@ -298,7 +340,7 @@ impl SyncClient {
         });
         let resp = self
             .client
-            .post(url_base().to_owned() + "get_version_files")
+            .post(self.endpoint.url_base.to_owned() + "get_version_files")
             .body(payload.to_string())
             .bearer_auth(&self.auth_token)
             .header("Content-Type", "application/octet-stream")
@@ -310,11 +352,85 @@ impl SyncClient {
         Ok(files)
     }
 
+    /// Chunks of the graph not stored yet, of chunk ids
+    pub async fn get_missing_chunks<P: AsRef<str>, I: IntoIterator<Item = P>>(
+        &self,
+        chunks: I,
+    ) -> Result<Vec<String>> {
+        let payload = json!({
+            "GraphUUID": self.graph_uuid,
+            "Chunks": chunks.into_iter().map(|c| c.as_ref().to_owned()).collect::<Vec<String>>(),
+        });
+        let resp = self
+            .client
+            .post(self.endpoint.url_base.to_owned() + "get_missing_chunks")
+            .body(payload.to_string())
+            .bearer_auth(&self.auth_token)
+            .header("Content-Type", "application/octet-stream")
+            .send()
+            .await?;
+
+        let result: types::TypicalResponse = resp.json().await?;
+        match result.message {
+            None => Ok(serde_json::from_value(
+                result.data["MissingChunks"].clone(),
+            )?),
+            Some(message) => Err(SyncError::Custom(message)),
+        }
+    }
+
+    /// Store uploaded temp files as chunks of the graph,
+    /// chunk id => remote temp path returned by `upload_tempfile`
+    pub async fn put_chunks(&self, chunks: &HashMap<String, String>) -> Result<()> {
+        let payload = json!({
+            "GraphUUID": self.graph_uuid,
+            "Chunks": chunks,
+        });
+        let resp = self
+            .client
+            .post(self.endpoint.url_base.to_owned() + "put_chunks")
+            .body(payload.to_string())
+            .bearer_auth(&self.auth_token)
+            .header("Content-Type", "application/octet-stream")
+            .send()
+            .await?;
+
+        let result: types::TypicalResponse = resp.json().await?;
+        match result.message {
+            None => Ok(()),
+            Some(message) => Err(SyncError::Custom(message)),
+        }
+    }
+
+    /// Chunks' s3 get-object presigned-url, chunk id => url
+    pub async fn get_chunks<P: AsRef<str>, I: IntoIterator<Item = P>>(
+        &self,
+        chunks: I,
+    ) -> Result<HashMap<String, String>> {
+        let payload = json!({
+            "GraphUUID": self.graph_uuid,
+            "Chunks": chunks.into_iter().map(|c| c.as_ref().to_owned()).collect::<Vec<String>>(),
+        });
+        let resp = self
+            .client
+            .post(self.endpoint.url_base.to_owned() + "get_chunks")
+            .body(payload.to_string())
+            .bearer_auth(&self.auth_token)
+            .header("Content-Type", "application/octet-stream")
+            .send()
+            .await?;
+
+        let result: serde_json::Value = resp.json().await?;
+        let chunks: HashMap<String, String> =
+            serde_json::from_value(result["PresignedChunkUrls"].clone())?;
+        Ok(chunks)
+    }
+
     // expire after 1h
     pub async fn get_temp_credential(&self) -> Result<TempCredential> {
         let resp = self
             .client
-            .post(url_base().to_owned() + "get_temp_credential")
+            .post(self.endpoint.url_base.to_owned() + "get_temp_credential")
             .body("")
             .bearer_auth(&self.auth_token)
             .header("Content-Type", "application/octet-stream")
@@ -326,7 +442,7 @@ impl SyncClient {
         // FIXME: prefix is path style
         credential.s3_prefix = credential
             .s3_prefix
-            .strip_prefix(bucket())
+            .strip_prefix(self.endpoint.bucket)
             .unwrap()
             .trim_start_matches('/')
             .to_owned()
@@ -400,7 +516,7 @@ impl SyncClient {
             credentials.secret_key.clone(),
             Some(credentials.session_token.clone()),
         );
-        let bucket = Bucket::new(region(), bucket());
+        let bucket = Bucket::new(self.endpoint.region, self.endpoint.bucket);
 
         let key = self.s3_prefix.clone().unwrap() + &*random_string(12);
         // 1 hour expiration
@@ -448,6 +564,22 @@ impl SyncClient {
     // value: [s3-prefix]/xxxxxxxxxxx
     // (key, value, checksum) => (page/page1.md, s3-prefix/xxxxxxxxxxx.md, md5-checksum)
     pub async fn update_files<PK, PV, PH, I>(&self, files: I) -> Result<types::UpdateFiles>
+    where
+        PK: AsRef<str>,
+        PV: AsRef<str>,
+        PH: AsRef<str>,
+        I: IntoIterator<Item = (PK, PV, PH)>,
+    {
+        self.update_files_with_mtimes(files, &HashMap::new()).await
+    }
+
+    /// Same as `update_files`, also records local modified times(in milliseconds) of files,
+    /// key => mtime
+    pub async fn update_files_with_mtimes<PK, PV, PH, I>(
+        &self,
+        files: I,
+        mtimes: &HashMap<String, i64>,
+    ) -> Result<types::UpdateFiles>
     where
         PK: AsRef<str>,
         PV: AsRef<str>,
@@ -463,14 +595,17 @@ impl SyncClient {
                 )
             })
             .collect::<HashMap<_, _>>();
-        let payload = json!({
+        let mut payload = json!({
             "GraphUUID": self.graph_uuid,
             "TXId": self.txid,
             "Files": files
         });
+        if !mtimes.is_empty() {
+            payload["Mtimes"] = json!(mtimes);
+        }
         let resp = self
             .client
-            .post(url_base().to_owned() + "update_files")
+            .post(self.endpoint.url_base.to_owned() + "update_files")
             .body(payload.to_string())
             .bearer_auth(&self.auth_token)
             .header("Content-Type", "application/octet-stream")
@@ -500,7 +635,7 @@ impl SyncClient {
 
         let resp = self
             .client
-            .post(url_base().to_owned() + "delete_files")
+            .post(self.endpoint.url_base.to_owned() + "delete_files")
             .body(payload.to_string())
             .bearer_auth(&self.auth_token)
             .header("Content-Type", "application/octet-stream")
@@ -527,7 +662,7 @@ impl SyncClient {
 
         let resp = self
             .client
-            .post(url_base().to_owned() + "get_diff")
+            .post(self.endpoint.url_base.to_owned() + "get_diff")
             .body(payload.to_string())
             .bearer_auth(&self.auth_token)
             .header("Content-Type", "application/octet-stream")
@@ -560,7 +695,7 @@ impl SyncClient {
 
         let resp = self
             .client
-            .post(url_base().to_owned() + "rename_file")
+            .post(self.endpoint.url_base.to_owned() + "rename_file")
             .body(payload.to_string())
             .bearer_auth(&self.auth_token)
             .header("Content-Type", "application/octet-stream")
@@ -590,3 +725,56 @@ fn random_string(len: usize) -> String {
         .collect();
     chars.iter().collect::<String>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{json_body, MockServer};
+
+    #[tokio::test]
+    async fn test_chunk_requests() {
+        let server = MockServer::start(|req| match &*req.path {
+            "get_missing_chunks" => json_body(json!({"MissingChunks": ["b"]})),
+            "put_chunks" if req.json()["Chunks"].get("c").is_some() => {
+                json_body(json!({"message": "upload not found"}))
+            }
+            "put_chunks" => json_body(json!({})),
+            "get_chunks" => json_body(json!({
+                "PresignedChunkUrls": {"a": req.url("chunks/a")},
+            })),
+            _ => json_body(json!({"message": "not found"})),
+        })
+        .await;
+        let mut client = SyncClient::new(&Arc::new(SyncContext::new()), server.endpoint(), "token");
+        client.set_graph("graph", 1);
+
+        let missing = client.get_missing_chunks(["a", "b"]).await.unwrap();
+        assert_eq!(missing, vec!["b".to_string()]);
+        client
+            .put_chunks(&[("b".to_string(), "tmp/b".to_string())].into())
+            .await
+            .unwrap();
+        assert!(matches!(
+            client
+                .put_chunks(&[("c".to_string(), "tmp/c".to_string())].into())
+                .await,
+            Err(SyncError::Custom(_))
+        ));
+        let urls = client.get_chunks(["a"]).await.unwrap();
+        assert_eq!(urls, [("a".to_string(), server.url("chunks/a"))].into());
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].json(),
+            json!({"GraphUUID": "graph", "Chunks": ["a", "b"]})
+        );
+        assert_eq!(
+            requests[1].json(),
+            json!({"GraphUUID": "graph", "Chunks": {"b": "tmp/b"}})
+        );
+        assert_eq!(
+            requests[3].json(),
+            json!({"GraphUUID": "graph", "Chunks": ["a"]})
+        );
+    }
+}
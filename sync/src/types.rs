@@ -14,6 +14,9 @@ pub struct Graph {
     pub graph_name: String,
     #[serde(default, rename = "GraphUUID")]
     pub graph_uuid: String,
+    // optional features of the server, e.g. "chunked-storage"
+    #[serde(default, rename = "Capabilities")]
+    pub capabilities: Vec<String>,
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
@@ -68,6 +71,22 @@ pub struct FileObject {
     //pub storage_class: String,
     //pub owner: Option<String>,
     pub size: u64,
+    /// md5 checksum of the plain content, as sent by `update_files`.
+    /// Not listed by servers not storing it, such files are taken as changed remotely:
+    /// planned as downloads or conflicts, and not skipped as unchanged when uploading.
+    #[serde(default, alias = "checksum")]
+    pub checksum: Option<String>,
+    /// local modified time at upload in milliseconds, as sent by `update_files_with_mtimes`
+    #[serde(default, alias = "mtime")]
+    pub mtime: Option<i64>,
+}
+
+impl FileObject {
+    /// Modified time in milliseconds, the uploaded local mtime if recorded
+    pub fn mtime_millis(&self) -> i64 {
+        self.mtime
+            .unwrap_or_else(|| self.last_modified.timestamp_millis())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]